@@ -70,6 +70,36 @@ impl Bounds {
         }
     }
 
+    /// Bounds that contain nothing. The union of this with any other bounds is the other bounds
+    pub fn empty() -> Self {
+        Bounds::new(Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lower.x > self.upper.x || self.lower.y > self.upper.y || self.lower.z > self.upper.z
+    }
+
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        Bounds::new(self.lower.min(other.lower), self.upper.max(other.upper))
+    }
+
+    pub fn union_point(&self, p: Vec3) -> Bounds {
+        Bounds::new(self.lower.min(p), self.upper.max(p))
+    }
+
+    pub fn center(&self) -> Vec3 {
+        0.5 * (self.lower + self.upper)
+    }
+
+    pub fn diagonal(&self) -> Vec3 {
+        self.upper - self.lower
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.diagonal();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
     pub fn as_raw_ptr(&mut self) -> *mut RTCBounds {
         self as *mut Bounds as *mut RTCBounds
     }
@@ -136,13 +166,14 @@ pub(crate) trait MatrixTypeFormat {
     fn as_ptr(&self) -> *const f32;
 }
 
-// impl MatrixTypeFormat for glam::Mat4 {
-//     const FORMAT: MatrixFormat = MatrixFormat::float4x4ColumnMajor;
+impl MatrixTypeFormat for glam::Mat4 {
+    const FORMAT: MatrixFormat = MatrixFormat::float4x4ColumnMajor;
 
-//     fn as_ptr(&self) -> *const f32 {
-//         <Self as glam::Mat4>.as_ptr(self)
-//     }
-// }
+    fn as_ptr(&self) -> *const f32 {
+        let xfm: &[f32; 16] = self.as_ref();
+        xfm.as_ptr()
+    }
+}
 
 // impl MatrixTypeFormat for mint::ColumnMatrix3x4<f32> {
 //     const FORMAT: MatrixFormat = MatrixFormat::float3x4ColumnMajor;
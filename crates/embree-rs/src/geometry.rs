@@ -37,13 +37,21 @@ impl GeometryHandle {
         unsafe { rtcSetGeometryBuildQuality(self.ptr, quality as i32); }
     }
 
-    // pub(crate) fn set_instance_transform(&mut self, transform: &glam::Mat4) {
-    //     unsafe {
-    //         rtcSetGeometryTransform(self.ptr, 0,
-    //             glam::Mat4::FORMAT.into(),
-    //             transform.as_ptr() as *const c_void);
-    //     }
-    // }
+    /// Geometry with more than one time step is interpolated between them for motion blur.
+    ///  The time steps are evenly spaced over the time range [0,1] of a ray
+    pub(crate) fn set_time_step_count(&mut self, count: u32) {
+        debug_assert!(count >= 1);
+        unsafe { rtcSetGeometryTimeStepCount(self.ptr, count); }
+    }
+
+    /// Sets the transform of an instance at a time step
+    pub(crate) fn set_instance_transform<M: MatrixTypeFormat>(&mut self, time_step: u32, transform: &M) {
+        unsafe {
+            rtcSetGeometryTransform(self.ptr, time_step,
+                M::FORMAT as RTCFormat,
+                transform.as_ptr() as *const c_void);
+        }
+    }
 
     /// slot: slot is used as the time_step for a vertex buffer and the slot for a vertex attribute
     pub(crate) unsafe fn bind_shared_geometry_buffer<T>(&mut self, data: &Vec<T>, buf_type: BufferType, format: Format, slot: u32, byte_offset: usize) {
@@ -107,7 +115,7 @@ pub enum GeometryType {
     // RayFacingDisc = RTC_GEOMETRY_TYPE_DISC_POINT,
     Disc = RTC_GEOMETRY_TYPE_ORIENTED_DISC_POINT,
     User = RTC_GEOMETRY_TYPE_USER,
-    // Instance = RTC_GEOMETRY_TYPE_INSTANCE,
+    Instance = RTC_GEOMETRY_TYPE_INSTANCE,
 }
 
 #[repr(i32)]
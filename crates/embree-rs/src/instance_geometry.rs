@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use glam::*;
+
+use sys::*;
+
+use crate::device::*;
+use crate::geometry::*;
+use crate::scene::*;
+
+/// A copy of a scene placed with a transform. The instanced scene is shared, so many instances
+///  of it only need the memory for one
+pub struct InstanceGeometry {
+    pub(crate) handle: GeometryHandle,
+    scene: Arc<Scene>,
+    /// The transform at each time step, for motion blur
+    transforms: Vec<Mat4>,
+}
+
+impl InstanceGeometry {
+    pub fn new(device: &Device, scene: Arc<Scene>, transform: Mat4) -> Self {
+        let handle = GeometryHandle::new(device, GeometryType::Instance);
+        InstanceGeometry {
+            handle,
+            scene,
+            transforms: vec![transform],
+        }
+    }
+
+    /// Adds the transform at the next time step. The transforms are interpolated linearly between
+    ///  time steps, which are evenly spaced over the shutter interval
+    pub fn add_time_step(&mut self, transform: Mat4) {
+        self.transforms.push(transform);
+    }
+
+    pub fn time_step_count(&self) -> u32 {
+        self.transforms.len() as u32
+    }
+
+    pub fn transforms(&self) -> &[Mat4] {
+        &self.transforms
+    }
+
+    pub fn scene(&self) -> &Arc<Scene> {
+        &self.scene
+    }
+}
+
+impl Geometry for InstanceGeometry {
+    fn handle(&self) -> &GeometryHandle {
+        &self.handle
+    }
+
+    fn handle_mut(&mut self) -> &mut GeometryHandle {
+        &mut self.handle
+    }
+
+    fn bind_buffers(&mut self) {
+        self.handle.set_time_step_count(self.time_step_count());
+        unsafe { rtcSetGeometryInstancedScene(self.handle.as_raw_ptr(), self.scene.handle().as_ptr()); }
+        for (i, transform) in self.transforms.iter().enumerate() {
+            self.handle.set_instance_transform(i as u32, transform);
+        }
+    }
+}
@@ -6,6 +6,7 @@ mod device;
 mod scene;
 mod error;
 mod geometry;
+mod instance_geometry;
 mod point_geometry;
 mod polygon_geometry;
 mod ray;
@@ -16,6 +17,7 @@ pub use crate::device::*;
 pub use crate::scene::*;
 pub use crate::error::*;
 pub use crate::geometry::*;
+pub use crate::instance_geometry::*;
 pub use crate::point_geometry::*;
 pub use crate::polygon_geometry::*;
 pub use crate::ray::*;
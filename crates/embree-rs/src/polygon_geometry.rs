@@ -24,6 +24,11 @@ impl IndexedTriangle {
             v2,
         }
     }
+
+    /// The same triangle facing the other way
+    pub fn flipped(&self) -> Self {
+        IndexedTriangle::new(self.v0, self.v2, self.v1)
+    }
 }
 
 /// A quad is defined as a pair of triangles (v0, v1, v3) & (v2, v3, v1).
@@ -49,6 +54,11 @@ impl IndexedQuad {
             v3,
         }
     }
+
+    /// The same quad facing the other way
+    pub fn flipped(&self) -> Self {
+        IndexedQuad::new(self.v0, self.v3, self.v2, self.v1)
+    }
 }
 
 impl TypeFormat for IndexedTriangle {
@@ -69,6 +79,8 @@ pub struct $geometryname {
     pub(crate) handle: GeometryHandle,
     pub indices: Vec<$polygon>,
     pub vertices: Vec<Vec3>,
+    /// Vertex positions for each time step after the first (which is `vertices`), for motion blur
+    pub motion_vertices: Vec<Vec<Vec3>>,
     pub normals: Option<Vec<Vec3>>,
     pub tex_coords: Option<Vec<Vec2>>,
 }
@@ -80,6 +92,7 @@ impl $geometryname {
             handle: handle,
             indices: index_buffer,
             vertices: vertex_buffer,
+            motion_vertices: Vec::new(),
             normals: None,
             tex_coords: None,
         }
@@ -93,14 +106,32 @@ impl $geometryname {
         self.tex_coords = Some(buf);
     }
 
+    /// Adds the vertex positions at the next time step. The mesh moves linearly between time steps,
+    ///  which are evenly spaced over the shutter interval
+    pub fn add_time_step(&mut self, vertex_buffer: Vec<Vec3>) {
+        assert_eq!(vertex_buffer.len(), self.vertices.len(), "Each time step needs a position for every vertex");
+        self.motion_vertices.push(vertex_buffer);
+    }
+
+    pub fn time_step_count(&self) -> u32 {
+        1 + self.motion_vertices.len() as u32
+    }
+
+    /// Transforms the vertices and normals. Transforms that mirror the mesh also
+    ///  reverse the winding of its polygons so they keep facing the same way relative to the surface
     pub fn transform_mesh(&mut self, transform: Mat4) {
-        for v in self.vertices.iter_mut() {
+        for v in self.vertices.iter_mut().chain(self.motion_vertices.iter_mut().flatten()) {
             *v = transform.transform_point3(*v);
         }
         if let Some(ref mut normal_buf) = self.normals {
             let normal_transform = transform.inverse().transpose();
             for n in normal_buf.iter_mut() {
-                *n = normal_transform.transform_vector3(*n);
+                *n = normal_transform.transform_vector3(*n).normalize_or_zero();
+            }
+        }
+        if transform.determinant() < 0.0 {
+            for polygon in self.indices.iter_mut() {
+                *polygon = polygon.flipped();
             }
         }
     }
@@ -122,10 +153,16 @@ impl Geometry for $geometryname {
 
         self.indices.reserve(1);
         self.vertices.reserve(1);
+        let time_steps = self.time_step_count();
+        self.handle.set_time_step_count(time_steps);
         
         unsafe {
             self.handle.bind_shared_geometry_buffer(&mut self.indices, BufferType::Index, <$polygon>::FORMAT, 0, 0);
             self.handle.bind_shared_geometry_buffer(&mut self.vertices, BufferType::Vertex, Format::f32x3, 0, 0);
+            for (i, data) in self.motion_vertices.iter_mut().enumerate() {
+                data.reserve(1);
+                self.handle.bind_shared_geometry_buffer(data, BufferType::Vertex, Format::f32x3, (i + 1) as u32, 0);
+            }
 
             rtcSetGeometryVertexAttributeCount(self.handle.ptr, attrib_count);
 
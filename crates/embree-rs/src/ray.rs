@@ -46,6 +46,16 @@ impl Ray {
         }
     }
 
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    /// Sets the time within the shutter interval that the ray is traced at, for motion blur
+    pub fn set_time(&mut self, time: f32) {
+        debug_assert!((0.0..=1.0).contains(&time), "Ray time must be in [0,1]");
+        self.time = time;
+    }
+
     pub fn in_range(&self, t: f32) -> bool {
         t >= self.tnear && t <= self.tfar
     }
@@ -96,6 +106,12 @@ impl Hit {
     pub fn is_hit(&self) -> bool {
         !self.geom_id.is_invalid()
     }
+
+    /// Whether the hit is on geometry in an instanced scene. geom_id is then the ID of the geometry
+    ///  within that scene and inst_id is the ID of the instance
+    pub fn is_instanced(&self) -> bool {
+        !self.inst_id.is_invalid()
+    }
 }
 
 #[test]
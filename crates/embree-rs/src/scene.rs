@@ -133,6 +133,10 @@ fn empty_intersect_context() -> RTCIntersectContext {
 // }
 
 impl Scene {
+    pub(crate) fn handle(&self) -> &SceneHandle {
+        &self.handle
+    }
+
     pub fn bounds(&self) -> Bounds {
         let mut b = Bounds::zero();
         unsafe { rtcGetSceneBounds(self.handle.ptr, b.as_raw_ptr()); }
@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::obj::ObjError;
+
+/// Where in a file a problem is
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+    /// Path to the value in a JSON file, like `primitives[2].transform`
+    pub json_path: Option<String>,
+}
+
+/// Problems with a scene. Loading stops at IO and parse errors and missing files, while the others
+///  are found by `SceneDescription::problems` in scenes that did load
+#[derive(Debug)]
+pub enum SceneImportError {
+    /// A file couldn't be read or written
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A file isn't valid
+    Parse {
+        path: PathBuf,
+        location: Option<Location>,
+        message: String,
+        /// The error of the loader that found the problem
+        source: Option<Box<dyn Error + Send + Sync>>,
+    },
+    /// A file the scene refers to doesn't exist
+    MissingFile {
+        path: PathBuf,
+        referenced_by: String,
+    },
+    /// A primitive or BSDF refers to a BSDF the scene doesn't have
+    UnknownBsdf {
+        name: String,
+        referenced_by: String,
+    },
+    /// More than one BSDF has the same name, so references to it use the first
+    DuplicateBsdf {
+        name: String,
+    },
+    /// A primitive or the camera refers to a medium the scene doesn't have
+    UnknownMedium {
+        name: String,
+        referenced_by: String,
+    },
+    /// Something the renderer can't draw, which is left out or approximated
+    Unsupported {
+        feature: String,
+        reason: String,
+    },
+}
+
+impl SceneImportError {
+    /// Sorts the errors of the format loaders, which are IO errors or parse errors without a location
+    pub(crate) fn from_loader(path: &Path, error: Box<dyn Error + Send + Sync>) -> Self {
+        let error = match error.downcast::<io::Error>() {
+            Ok(source) => return SceneImportError::Io { path: path.to_owned(), source: *source },
+            Err(error) => error,
+        };
+        if let Some(ObjError::Load(tobj::LoadError::OpenFileFailed)) = error.downcast_ref::<ObjError>() {
+            // tobj doesn't keep the IO error, so find out why the file can't be opened
+            if let Err(source) = File::open(path) {
+                return SceneImportError::Io { path: path.to_owned(), source };
+            }
+        }
+        SceneImportError::Parse { path: path.to_owned(), location: None, message: error.to_string(), source: Some(error) }
+    }
+
+    pub(crate) fn json(path: &Path, text: &str, error: serde_json::Error) -> Self {
+        if error.is_io() || error.line() == 0 {
+            return SceneImportError::Parse { path: path.to_owned(), location: None, message: error.to_string(), source: None };
+        }
+        let (line, column) = (error.line(), error.column());
+        // serde_json puts the location at the end of its message
+        let message = error.to_string();
+        let suffix = format!(" at line {} column {}", line, column);
+        let message = message.strip_suffix(&suffix).unwrap_or(&message).to_owned();
+        SceneImportError::Parse {
+            path: path.to_owned(),
+            location: Some(Location { line, column, json_path: json_path_at(text, line, column) }),
+            message,
+            source: None,
+        }
+    }
+}
+
+impl fmt::Display for SceneImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneImportError::Io { path, source } => write!(f, "Unable to access {}: {}", path.display(), source),
+            SceneImportError::Parse { path, location: None, message, .. } => {
+                write!(f, "Invalid scene file {}: {}", path.display(), message)
+            },
+            SceneImportError::Parse { path, location: Some(location), message, .. } => {
+                write!(f, "Invalid scene file {} at line {} column {}", path.display(), location.line, location.column)?;
+                if let Some(json_path) = &location.json_path {
+                    write!(f, " ({})", json_path)?;
+                }
+                write!(f, ": {}", message)
+            },
+            SceneImportError::MissingFile { path, referenced_by } => {
+                write!(f, "{} refers to {}, which doesn't exist", referenced_by, path.display())
+            },
+            SceneImportError::UnknownBsdf { name, referenced_by } => {
+                write!(f, "{} refers to unknown BSDF {:?}", referenced_by, name)
+            },
+            SceneImportError::DuplicateBsdf { name } => {
+                write!(f, "BSDF {:?} is defined more than once, the first definition is used", name)
+            },
+            SceneImportError::UnknownMedium { name, referenced_by } => {
+                write!(f, "{} refers to unknown medium {:?}", referenced_by, name)
+            },
+            SceneImportError::Unsupported { feature, reason } => write!(f, "Unsupported {}: {}", feature, reason),
+        }
+    }
+}
+
+impl Error for SceneImportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SceneImportError::Io { source, .. } => Some(source),
+            SceneImportError::Parse { source: Some(source), .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+enum Frame {
+    Object { key: Option<String>, expecting_key: bool },
+    Array(usize),
+}
+
+/// The path to the value at a line and column of a JSON document, which are counted from one
+fn json_path_at(text: &str, line: usize, column: usize) -> Option<String> {
+    let mut stack: Vec<Frame> = Vec::new();
+    let (mut l, mut c) = (1, 0);
+    let mut chars = text.chars();
+    // Values serde buffers, like untagged enums, fail at their closing bracket, which means the whole container
+    let mut at_close = false;
+    while let Some(ch) = chars.next() {
+        if ch == '\n' {
+            l += 1;
+            c = 0;
+        } else {
+            c += 1;
+        }
+        if l > line || (l == line && c >= column) {
+            at_close = ch == '}' || ch == ']';
+            break;
+        }
+        match ch {
+            '{' => stack.push(Frame::Object { key: None, expecting_key: true }),
+            '[' => stack.push(Frame::Array(0)),
+            '}' | ']' => {
+                stack.pop();
+            },
+            ',' => match stack.last_mut() {
+                Some(Frame::Array(index)) => *index += 1,
+                Some(Frame::Object { expecting_key, .. }) => *expecting_key = true,
+                None => {},
+            },
+            ':' => {
+                if let Some(Frame::Object { expecting_key, .. }) = stack.last_mut() {
+                    *expecting_key = false;
+                }
+            },
+            '"' => {
+                let mut s = String::new();
+                while let Some(ch) = chars.next() {
+                    c += 1;
+                    match ch {
+                        '"' => break,
+                        '\\' => {
+                            c += 1;
+                            s.extend(chars.next());
+                        },
+                        ch => s.push(ch),
+                    }
+                }
+                if let Some(Frame::Object { key, expecting_key: true }) = stack.last_mut() {
+                    *key = Some(s);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut path = String::new();
+    // A stray closing bracket has nothing to close
+    let len = stack.len().saturating_sub(at_close as usize);
+    for frame in &stack[..len] {
+        match frame {
+            Frame::Object { key: Some(key), .. } => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+            },
+            Frame::Object { key: None, .. } => {},
+            Frame::Array(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+    if path.is_empty() { None } else { Some(path) }
+}
+
+#[test]
+fn test_json_path() {
+    let text = "{\n  \"primitives\": [\n    {},\n    { \"transform\": { \"position\": [0, \"a\", 0] } }\n  ]\n}";
+    assert_eq!(json_path_at(text, 4, 39).as_deref(), Some("primitives[1].transform.position[1]"));
+    assert_eq!(json_path_at(text, 3, 5).as_deref(), Some("primitives[0]"));
+    assert_eq!(json_path_at(text, 4, 46).as_deref(), Some("primitives[1].transform"));
+    assert_eq!(json_path_at(text, 1, 1), None);
+    // Unbalanced brackets
+    assert_eq!(json_path_at("]", 1, 1), None);
+    assert_eq!(json_path_at("]]}", 1, 3), None);
+    assert_eq!(json_path_at("{\"a\": [1]]", 1, 11), None);
+}
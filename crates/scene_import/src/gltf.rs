@@ -0,0 +1,761 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::read;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::matrix::*;
+use crate::tungsten_scene::*;
+
+/// Height of the image for cameras that only give an aspect ratio
+const CAMERA_HEIGHT: u32 = 720;
+
+const GLB_MAGIC: &[u8] = b"glTF";
+const GLB_CHUNK_JSON: u32 = 0x4E4F534A;
+const GLB_CHUNK_BIN: u32 = 0x004E4942;
+
+const MODE_TRIANGLES: u32 = 4;
+const MODE_TRIANGLE_STRIP: u32 = 5;
+const MODE_TRIANGLE_FAN: u32 = 6;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    scene: Option<usize>,
+    #[serde(default)]
+    scenes: Vec<GltfScene>,
+    #[serde(default)]
+    nodes: Vec<Node>,
+    #[serde(default)]
+    meshes: Vec<Mesh>,
+    #[serde(default)]
+    accessors: Vec<Accessor>,
+    #[serde(default)]
+    buffer_views: Vec<BufferView>,
+    #[serde(default)]
+    buffers: Vec<Buffer>,
+    #[serde(default)]
+    materials: Vec<GltfMaterial>,
+    #[serde(default)]
+    textures: Vec<GltfTexture>,
+    #[serde(default)]
+    images: Vec<Image>,
+    #[serde(default)]
+    cameras: Vec<GltfCamera>,
+    #[serde(default)]
+    extensions: DocumentExtensions,
+}
+
+#[derive(Deserialize)]
+struct GltfScene {
+    #[serde(default)]
+    nodes: Vec<usize>,
+}
+
+#[derive(Deserialize, Default)]
+struct DocumentExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    lights_punctual: Option<LightsPunctual>,
+}
+
+#[derive(Deserialize)]
+struct LightsPunctual {
+    lights: Vec<Light>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Node {
+    #[serde(default)]
+    children: Vec<usize>,
+    mesh: Option<usize>,
+    camera: Option<usize>,
+    matrix: Option<Matrix>,
+    translation: Option<[f32; 3]>,
+    /// A unit quaternion as x, y, z, w
+    rotation: Option<[f32; 4]>,
+    scale: Option<[f32; 3]>,
+    #[serde(default)]
+    extensions: NodeExtensions,
+}
+
+#[derive(Deserialize, Default)]
+struct NodeExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    light: Option<NodeLight>,
+}
+
+#[derive(Deserialize)]
+struct NodeLight {
+    light: usize,
+}
+
+#[derive(Deserialize)]
+struct Mesh {
+    primitives: Vec<MeshPrimitive>,
+}
+
+#[derive(Deserialize)]
+struct MeshPrimitive {
+    attributes: HashMap<String, usize>,
+    indices: Option<usize>,
+    material: Option<usize>,
+    #[serde(default = "default_mode")]
+    mode: u32,
+}
+
+fn default_mode() -> u32 {
+    MODE_TRIANGLES
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: Option<usize>,
+    #[serde(default)]
+    byte_offset: usize,
+    component_type: u32,
+    #[serde(default)]
+    normalized: bool,
+    count: usize,
+    #[serde(rename = "type")]
+    accessor_type: String,
+    sparse: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Buffer {
+    uri: Option<String>,
+    byte_length: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfMaterial {
+    name: Option<String>,
+    #[serde(default)]
+    pbr_metallic_roughness: PbrMetallicRoughness,
+    #[serde(default)]
+    emissive_factor: [f32; 3],
+    #[serde(default)]
+    extensions: MaterialExtensions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PbrMetallicRoughness {
+    #[serde(default = "float4_one")]
+    base_color_factor: [f32; 4],
+    base_color_texture: Option<TextureInfo>,
+    #[serde(default = "float_one")]
+    metallic_factor: f32,
+    #[serde(default = "float_one")]
+    roughness_factor: f32,
+}
+
+impl Default for PbrMetallicRoughness {
+    fn default() -> Self {
+        PbrMetallicRoughness {
+            base_color_factor: float4_one(),
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+        }
+    }
+}
+
+fn float4_one() -> [f32; 4] {
+    [1.0; 4]
+}
+
+fn float_one() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct TextureInfo {
+    index: usize,
+}
+
+#[derive(Deserialize, Default)]
+struct MaterialExtensions {
+    #[serde(rename = "KHR_materials_emissive_strength")]
+    emissive_strength: Option<EmissiveStrength>,
+    #[serde(rename = "KHR_materials_transmission")]
+    transmission: Option<Transmission>,
+    #[serde(rename = "KHR_materials_ior")]
+    ior: Option<MaterialIor>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EmissiveStrength {
+    #[serde(default = "float_one")]
+    emissive_strength: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Transmission {
+    #[serde(default)]
+    transmission_factor: f32,
+}
+
+#[derive(Deserialize)]
+struct MaterialIor {
+    #[serde(default = "default_ior")]
+    ior: f32,
+}
+
+#[derive(Deserialize)]
+struct GltfTexture {
+    source: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Image {
+    uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfCamera {
+    #[serde(rename = "type")]
+    camera_type: String,
+    perspective: Option<Perspective>,
+    orthographic: Option<Orthographic>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Perspective {
+    /// Vertical field of view in radians
+    yfov: f32,
+    aspect_ratio: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct Orthographic {
+    /// Half the width of the view
+    xmag: f32,
+    /// Half the height of the view
+    ymag: f32,
+}
+
+#[derive(Deserialize)]
+struct Light {
+    #[serde(rename = "type")]
+    light_type: String,
+    #[serde(default = "float3_one")]
+    color: [f32; 3],
+    #[serde(default = "float_one")]
+    intensity: f32,
+}
+
+fn float3_one() -> [f32; 3] {
+    [1.0; 3]
+}
+
+/// Loads a glTF 2.0 scene from a .gltf file or a binary .glb file. Meshes are transformed into world space,
+///  metallic-roughness materials become conductors or plastics and punctual lights become point lights
+///  (spot lights lose their cone) or distant lights. Light intensities are used as radiometric quantities
+pub(crate) fn load_gltf_scene(path: &Path) -> Result<SceneDescription, Box<dyn Error + Send + Sync>> {
+    let bytes = read(path)?;
+    let (json, bin) = if bytes.starts_with(GLB_MAGIC) {
+        parse_glb(&bytes)?
+    } else {
+        (&bytes[..], None)
+    };
+    let doc: Document = serde_json::from_slice(json)?;
+
+    let base_path = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut buffers = Vec::with_capacity(doc.buffers.len());
+    for (i, buffer) in doc.buffers.iter().enumerate() {
+        let data = match &buffer.uri {
+            Some(uri) => match uri.strip_prefix("data:") {
+                Some(data_uri) => decode_data_uri(data_uri)?,
+                None => read(base_path.join(uri))?,
+            },
+            // Only the first buffer can refer to the binary chunk of a .glb
+            None if i == 0 => bin.ok_or("Buffer 0 has no data")?.to_vec(),
+            None => return Err(format!("Buffer {} has no data", i).into()),
+        };
+        if data.len() < buffer.byte_length {
+            return Err(format!("Buffer {} is shorter than its length of {} bytes", i, buffer.byte_length).into());
+        }
+        buffers.push(data);
+    }
+
+    let mut importer = Importer {
+        doc: &doc,
+        buffers,
+        file: path.file_name().and_then(|f| f.to_str()).unwrap_or("").to_owned(),
+        primitives: Vec::new(),
+        camera: None,
+    };
+    let roots = match doc.scenes.get(doc.scene.unwrap_or(0)) {
+        Some(scene) => scene.nodes.clone(),
+        None => {
+            // Without a scene every node that isn't a child is drawn
+            let children: HashSet<usize> = doc.nodes.iter().flat_map(|n| n.children.iter().copied()).collect();
+            (0..doc.nodes.len()).filter(|i| !children.contains(i)).collect()
+        },
+    };
+    for root in roots {
+        importer.add_node(root, &IDENTITY, 0)?;
+    }
+
+    let mut bsdfs = vec![Material {
+        name: DEFAULT_BSDF.to_owned(),
+        bsdf: MaterialType::Lambert {},
+        albedo: Texture::Constant([0.5, 0.5, 0.5]),
+    }];
+    bsdfs.extend(doc.materials.iter().enumerate().map(|(i, m)| convert_material(&doc, i, m)));
+
+    let Importer { mut primitives, camera, .. } = importer;
+    let is_lit = primitives.iter().any(|p| p.emission.constant() != Some([0.0; 3]));
+    if !is_lit {
+        let mut sky = Primitive::new(PrimitiveType::InfiniteSphere { sample: true });
+        sky.emission = Texture::Constant([1.0, 1.0, 1.0]);
+        primitives.push(sky);
+    }
+    let camera = match camera {
+        Some(camera) => camera,
+        None => match mesh_bounds(&primitives) {
+            Some((min, max)) => Camera::framing(min, max),
+            None => Camera::framing([-1.0; 3], [1.0; 3]),
+        },
+    };
+
+    Ok(SceneDescription {
+        media: Vec::new(),
+        bsdfs,
+        primitives,
+        camera,
+        integrator: IntegratorSettings::default(),
+        renderer: RendererSettings::default(),
+        warnings: Vec::new(),
+        missing_files: Vec::new(),
+    })
+}
+
+/// The JSON and binary chunks of a .glb file
+type GlbChunks<'a> = (&'a [u8], Option<&'a [u8]>);
+
+fn parse_glb(bytes: &[u8]) -> Result<GlbChunks<'_>, Box<dyn Error + Send + Sync>> {
+    let u32_at = |i: usize| bytes.get(i..i + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    if u32_at(4) != Some(2) {
+        return Err("Only version 2 of the glTF binary format is supported".into());
+    }
+    let mut json = None;
+    let mut bin = None;
+    let mut offset = 12;
+    while let (Some(length), Some(chunk_type)) = (u32_at(offset), u32_at(offset + 4)) {
+        let start = offset + 8;
+        let chunk = bytes.get(start..start + length as usize).ok_or("glTF binary chunk is truncated")?;
+        match chunk_type {
+            GLB_CHUNK_JSON => json = Some(chunk),
+            GLB_CHUNK_BIN => bin = Some(chunk),
+            _ => {},
+        }
+        offset = start + length as usize;
+    }
+    Ok((json.ok_or("glTF binary file has no JSON chunk")?, bin))
+}
+
+/// Decodes the part of a data URI after "data:", which must be base64 encoded
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let data = match uri.split_once(";base64,") {
+        Some((_, data)) => data,
+        None => return Err("Only base64 data URIs are supported".into()),
+    };
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' | b'-' => Some(62),
+        b'/' | b'_' => Some(63),
+        _ => None,
+    };
+    let mut bytes = Vec::with_capacity(data.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in data.bytes().take_while(|&c| c != b'=') {
+        bits = (bits << 6) | value(c).ok_or("Invalid base64 data")? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+struct Importer<'a> {
+    doc: &'a Document,
+    buffers: Vec<Vec<u8>>,
+    /// Name of the file the meshes came from
+    file: String,
+    primitives: Vec<Primitive>,
+    /// The first camera found
+    camera: Option<Camera>,
+}
+
+impl<'a> Importer<'a> {
+    fn add_node(&mut self, index: usize, parent: &Matrix, depth: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let node = self.doc.nodes.get(index).ok_or_else(|| format!("Node {} doesn't exist", index))?;
+        if depth > self.doc.nodes.len() {
+            return Err("glTF node hierarchy has a cycle".into());
+        }
+        let world = mul(parent, &local_matrix(node));
+
+        if let Some(mesh) = node.mesh {
+            let mesh = self.doc.meshes.get(mesh).ok_or_else(|| format!("Mesh {} doesn't exist", mesh))?;
+            for primitive in &mesh.primitives {
+                self.add_mesh_primitive(primitive, &world)?;
+            }
+        }
+        if let Some(camera) = node.camera {
+            let camera = self.doc.cameras.get(camera).ok_or_else(|| format!("Camera {} doesn't exist", camera))?;
+            if self.camera.is_none() {
+                self.camera = Some(convert_camera(camera, &world));
+            }
+        }
+        if let Some(light) = &node.extensions.light {
+            let light = self.doc.extensions.lights_punctual.as_ref()
+                .and_then(|l| l.lights.get(light.light))
+                .ok_or_else(|| format!("Light {} doesn't exist", light.light))?;
+            if let Some(prim) = convert_light(light, &world) {
+                self.primitives.push(prim);
+            }
+        }
+
+        for &child in &node.children {
+            self.add_node(child, &world, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a mesh in world space. Points and lines are skipped
+    fn add_mesh_primitive(&mut self, primitive: &MeshPrimitive, world: &Matrix) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if ![MODE_TRIANGLES, MODE_TRIANGLE_STRIP, MODE_TRIANGLE_FAN].contains(&primitive.mode) {
+            return Ok(());
+        }
+        let attribute = |name: &str| primitive.attributes.get(name).copied();
+        let positions = self.read_floats(attribute("POSITION").ok_or("Mesh primitive has no positions")?, 3)?;
+        let vertex_count = positions.len() / 3;
+        let normals = attribute("NORMAL").map(|a| self.read_floats(a, 3)).transpose()?;
+        let uvs = attribute("TEXCOORD_0").map(|a| self.read_floats(a, 2)).transpose()?;
+        let tangents = attribute("TANGENT").map(|a| self.read_floats(a, 4)).transpose()?;
+
+        let indices = match primitive.indices {
+            Some(indices) => self.read_accessor(indices, component_u32)?.0,
+            None => (0..vertex_count as u32).collect(),
+        };
+        if let Some(&i) = indices.iter().find(|&&i| i as usize >= vertex_count) {
+            return Err(format!("Mesh index {} is out of range of its {} vertices", i, vertex_count).into());
+        }
+        let tris: Vec<[u32; 4]> = match primitive.mode {
+            MODE_TRIANGLE_STRIP => (2..indices.len()).map(|i| if i % 2 == 0 {
+                [indices[i - 2], indices[i - 1], indices[i], 0]
+            } else {
+                [indices[i - 1], indices[i - 2], indices[i], 0]
+            }).collect(),
+            MODE_TRIANGLE_FAN => (2..indices.len()).map(|i| [indices[0], indices[i - 1], indices[i], 0]).collect(),
+            _ => indices.chunks_exact(3).map(|t| [t[0], t[1], t[2], 0]).collect(),
+        };
+
+        let verts = (0..vertex_count).map(|i| Vertex {
+            pos: [positions[i*3], positions[i*3 + 1], positions[i*3 + 2]],
+            normal: normals.as_ref().map_or([0.0; 3], |n| [n[i*3], n[i*3 + 1], n[i*3 + 2]]),
+            // glTF's texture coordinates start at the top of the image
+            uv: uvs.as_ref().map_or([0.0; 2], |uv| [uv[i*2], 1.0 - uv[i*2 + 1]]),
+            tangent: tangents.as_ref().map_or([0.0; 4], |t| [t[i*4], t[i*4 + 1], t[i*4 + 2], t[i*4 + 3]]),
+        }).collect();
+        let mut mesh_data = TriangleMesh { verts, tris, ..Default::default() };
+        transform_mesh(&mut mesh_data, world);
+
+        let mut prim = Primitive::new(PrimitiveType::Mesh {
+            file: self.file.clone(),
+            smooth: normals.is_some(),
+            backface_culling: false,
+            recompute_normals: false,
+            mesh_data,
+        });
+        match primitive.material {
+            Some(m) => {
+                let material = self.doc.materials.get(m).ok_or_else(|| format!("Material {} doesn't exist", m))?;
+                prim.bsdf = BsdfRef::Name(material_name(self.doc, m));
+                let strength = material.extensions.emissive_strength.as_ref().map_or(1.0, |e| e.emissive_strength);
+                prim.emission = Texture::Constant(material.emissive_factor.map(|e| e * strength));
+            },
+            None => prim.bsdf = BsdfRef::Name(DEFAULT_BSDF.to_owned()),
+        }
+        self.primitives.push(prim);
+        Ok(())
+    }
+
+    fn read_floats(&self, index: usize, components: usize) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> {
+        let (values, accessor_components) = self.read_accessor(index, component_f32)?;
+        if accessor_components != components {
+            return Err(format!("Accessor {} has {} components instead of {}", index, accessor_components, components).into());
+        }
+        Ok(values)
+    }
+
+    /// Reads every component of every element of an accessor, along with the number of components per element
+    fn read_accessor<T>(&self, index: usize, convert: fn(&[u8], u32, bool) -> T) -> Result<(Vec<T>, usize), Box<dyn Error + Send + Sync>> {
+        let accessor = self.doc.accessors.get(index).ok_or_else(|| format!("Accessor {} doesn't exist", index))?;
+        if accessor.sparse.is_some() {
+            return Err("Sparse accessors aren't supported".into());
+        }
+        let components = match accessor.accessor_type.as_str() {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            t => return Err(format!("Unsupported accessor type {}", t).into()),
+        };
+        let size = match accessor.component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            t => return Err(format!("Unknown accessor component type {}", t).into()),
+        };
+        let view = match accessor.buffer_view {
+            Some(view) => self.doc.buffer_views.get(view).ok_or_else(|| format!("Buffer view {} doesn't exist", view))?,
+            // Accessors without a buffer view are all zero
+            None => return Ok(((0..accessor.count * components).map(|_| convert(&[0; 4][..size], accessor.component_type, false)).collect(), components)),
+        };
+        let buffer = self.buffers.get(view.buffer).ok_or_else(|| format!("Buffer {} doesn't exist", view.buffer))?;
+        let data = buffer.get(view.byte_offset..view.byte_offset + view.byte_length)
+            .ok_or_else(|| format!("Buffer view of accessor {} is out of range", index))?;
+
+        let element_size = size * components;
+        let stride = view.byte_stride.unwrap_or(element_size);
+        if accessor.count > 0 && accessor.byte_offset + stride * (accessor.count - 1) + element_size > data.len() {
+            return Err(format!("Accessor {} reads past the end of its buffer view", index).into());
+        }
+        let mut values = Vec::with_capacity(accessor.count * components);
+        for i in 0..accessor.count {
+            for c in 0..components {
+                let offset = accessor.byte_offset + i * stride + c * size;
+                values.push(convert(&data[offset..offset + size], accessor.component_type, accessor.normalized));
+            }
+        }
+        Ok((values, components))
+    }
+}
+
+fn component_f32(bytes: &[u8], component_type: u32, normalized: bool) -> f32 {
+    let (value, max) = match component_type {
+        5120 => (bytes[0] as i8 as f32, 127.0),
+        5121 => (bytes[0] as f32, 255.0),
+        5122 => (i16::from_le_bytes([bytes[0], bytes[1]]) as f32, 32767.0),
+        5123 => (u16::from_le_bytes([bytes[0], bytes[1]]) as f32, 65535.0),
+        5125 => (u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32, 1.0),
+        _ => return f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    };
+    if normalized { (value / max).max(-1.0) } else { value }
+}
+
+fn component_u32(bytes: &[u8], component_type: u32, _normalized: bool) -> u32 {
+    match component_type {
+        5121 => bytes[0] as u32,
+        5123 => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+        5126 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u32,
+        _ => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
+}
+
+/// Materials are named by their index, so that names are unique
+fn material_name(doc: &Document, index: usize) -> String {
+    match &doc.materials[index].name {
+        Some(name) => format!("{}: {}", index, name),
+        None => format!("{}", index),
+    }
+}
+
+/// Metals become conductors whose reflectance is the base colour and everything else becomes plastic.
+///  glTF's roughness is squared to give the width of the distribution, as ours is
+fn convert_material(doc: &Document, index: usize, material: &GltfMaterial) -> Material {
+    let pbr = &material.pbr_metallic_roughness;
+    let base_colour = [pbr.base_color_factor[0], pbr.base_color_factor[1], pbr.base_color_factor[2]];
+    let roughness = pbr.roughness_factor;
+    let ior = material.extensions.ior.as_ref().map_or(default_ior(), |i| i.ior);
+    let transmission = material.extensions.transmission.as_ref().map_or(0.0, |t| t.transmission_factor);
+
+    let (bsdf, colour) = if transmission >= 0.5 {
+        (MaterialType::RoughDielectric { ior, roughness, distribution: default_distribution() }, [1.0; 3])
+    } else if pbr.metallic_factor >= 0.5 {
+        // Reflecting the base colour at normal incidence
+        let eta = reflectance_eta(base_colour);
+        let metal = MaterialType::RoughConductor {
+            roughness,
+            material: String::new(),
+            eta: Some(eta),
+            k: Some([0.0; 3]),
+            distribution: default_distribution(),
+        };
+        (metal, [1.0; 3])
+    } else {
+        (MaterialType::RoughPlastic { ior, roughness, distribution: default_distribution() }, base_colour)
+    };
+
+    let image = pbr.base_color_texture.as_ref()
+        .and_then(|t| doc.textures.get(t.index))
+        .and_then(|t| t.source)
+        .and_then(|i| doc.images.get(i))
+        .and_then(|i| i.uri.as_ref())
+        .filter(|uri| !uri.starts_with("data:"));
+    let albedo = match image {
+        Some(uri) => Texture::Bitmap { file: uri.clone() },
+        None => Texture::Constant(colour),
+    };
+
+    Material {
+        name: material_name(doc, index),
+        bsdf,
+        albedo,
+    }
+}
+
+fn convert_camera(camera: &GltfCamera, world: &Matrix) -> Camera {
+    // glTF cameras look along -z, ours along +z, so turn it around the y axis
+    let m = mul(world, &[
+        -1.0, 0.0, 0.0, 0.0,
+        0.0, 1.0, 0.0, 0.0,
+        0.0, 0.0, -1.0, 0.0,
+        0.0, 0.0, 0.0, 1.0,
+    ]);
+    let mut result = Camera::look_at([0.0; 3], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0], 45.0);
+    result.transform = CameraTransform::Matrix(transpose(&m));
+    result.fov_axis = FovAxis::Vertical;
+    let aspect = match (camera.camera_type.as_str(), &camera.perspective, &camera.orthographic) {
+        ("orthographic", _, Some(ortho)) => {
+            result.camera_type = "orthographic".to_owned();
+            result.ortho_width = Some(2.0 * ortho.xmag);
+            Some(ortho.xmag / ortho.ymag)
+        },
+        (_, Some(perspective), _) => {
+            result.fov = perspective.yfov.to_degrees();
+            perspective.aspect_ratio
+        },
+        _ => None,
+    };
+    if let Some(aspect) = aspect.filter(|a| a.is_finite() && *a > 0.0) {
+        result.resolution = [(CAMERA_HEIGHT as f32 * aspect).round() as u32, CAMERA_HEIGHT];
+    }
+    result
+}
+
+fn convert_light(light: &Light, world: &Matrix) -> Option<Primitive> {
+    let emission = light.color.map(|c| c * light.intensity);
+    match light.light_type.as_str() {
+        "point" | "spot" => {
+            let mut prim = Primitive::new(PrimitiveType::Point {});
+            prim.transform.position = transform_point(world, [0.0; 3]);
+            prim.emission = Texture::Constant(emission);
+            Some(prim)
+        },
+        // The light travels along -z
+        "directional" => Some(Primitive::distant_light(transform_vector(world, [0.0, 0.0, 1.0]), emission)),
+        _ => None,
+    }
+}
+
+fn local_matrix(node: &Node) -> Matrix {
+    if let Some(matrix) = node.matrix {
+        return matrix;
+    }
+    let [tx, ty, tz] = node.translation.unwrap_or([0.0; 3]);
+    let [x, y, z, w] = node.rotation.unwrap_or([0.0, 0.0, 0.0, 1.0]);
+    let [sx, sy, sz] = node.scale.unwrap_or([1.0; 3]);
+    [
+        (1.0 - 2.0 * (y * y + z * z)) * sx, 2.0 * (x * y + z * w) * sx, 2.0 * (x * z - y * w) * sx, 0.0,
+        2.0 * (x * y - z * w) * sy, (1.0 - 2.0 * (x * x + z * z)) * sy, 2.0 * (y * z + x * w) * sy, 0.0,
+        2.0 * (x * z + y * w) * sz, 2.0 * (y * z - x * w) * sz, (1.0 - 2.0 * (x * x + y * y)) * sz, 0.0,
+        tx, ty, tz, 1.0,
+    ]
+}
+
+#[test]
+fn test_gltf_triangle() {
+    // One triangle in the xy plane, mirrored by its node and offset by its parent
+    let positions: Vec<u8> = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0].iter().flat_map(|f| f.to_le_bytes()).collect();
+    let normals: Vec<u8> = [0.0f32, 0.0, 1.0].iter().cycle().take(9).flat_map(|f| f.to_le_bytes()).collect();
+    let indices: Vec<u8> = [0u16, 1, 2, 0].iter().flat_map(|i| i.to_le_bytes()).collect();
+    let data: Vec<u8> = positions.into_iter().chain(normals).chain(indices).collect();
+    let encoded = {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut s = String::new();
+        for chunk in data.chunks(3) {
+            let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+            let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+            for i in 0..=chunk.len() {
+                s.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        s
+    };
+    let json = format!(r#"{{
+        "asset": {{ "version": "2.0" }},
+        "scene": 0,
+        "scenes": [{{ "nodes": [0] }}],
+        "nodes": [
+            {{ "translation": [0, 0, 5], "children": [1, 2] }},
+            {{ "mesh": 0, "scale": [-1, 1, 1] }},
+            {{ "extensions": {{ "KHR_lights_punctual": {{ "light": 0 }} }} }}
+        ],
+        "extensions": {{ "KHR_lights_punctual": {{ "lights": [{{ "type": "point", "color": [1, 0.5, 0.5], "intensity": 2 }}] }} }},
+        "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0, "NORMAL": 1 }}, "indices": 2, "material": 0 }}] }}],
+        "materials": [{{ "name": "gold", "pbrMetallicRoughness": {{ "baseColorFactor": [1, 0.8, 0.3, 1], "roughnessFactor": 0.2 }} }}],
+        "accessors": [
+            {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" }},
+            {{ "bufferView": 0, "byteOffset": 36, "componentType": 5126, "count": 3, "type": "VEC3" }},
+            {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+        ],
+        "bufferViews": [
+            {{ "buffer": 0, "byteOffset": 0, "byteLength": 72 }},
+            {{ "buffer": 0, "byteOffset": 72, "byteLength": 8 }}
+        ],
+        "buffers": [{{ "byteLength": 80, "uri": "data:application/octet-stream;base64,{}" }}]
+    }}"#, encoded);
+    assert_eq!(decode_data_uri(&format!(";base64,{}", encoded)).unwrap(), data);
+
+    let path = std::env::temp_dir().join("phosphor_test_triangle.gltf");
+    std::fs::write(&path, json).unwrap();
+    let scene = load_gltf_scene(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(scene.primitives.len(), 2);
+    match &scene.primitives[0].primitive {
+        PrimitiveType::Mesh { mesh_data, smooth: true, .. } => {
+            let pos: Vec<[f32; 3]> = mesh_data.verts.iter().map(|v| v.pos).collect();
+            assert_eq!(pos, vec![[0.0, 0.0, 5.0], [-1.0, 0.0, 5.0], [0.0, 1.0, 5.0]]);
+            assert!(mesh_data.verts.iter().all(|v| v.normal == [0.0, 0.0, 1.0]));
+            // Mirroring reverses the winding, which is flipped back so the face still points along +z
+            assert_eq!(mesh_data.tris, vec![[0, 2, 1, 0]]);
+        },
+        p => panic!("Expected a mesh, got {:?}", p),
+    }
+    assert_eq!(scene.primitives[0].bsdf.name(), Some("0: gold"));
+    assert!(matches!(scene.bsdfs[1].bsdf, MaterialType::RoughConductor { roughness, .. } if roughness == 0.2));
+
+    let light = &scene.primitives[1];
+    assert!(matches!(light.primitive, PrimitiveType::Point {}));
+    assert_eq!(light.transform.position, [0.0, 0.0, 5.0]);
+    assert_eq!(light.emission.constant(), Some([2.0, 1.0, 1.0]));
+}
@@ -1,48 +1,110 @@
+mod error;
+mod gltf;
+mod matrix;
+mod mitsuba;
+mod obj;
+mod pbrt;
+mod ply;
 mod scene_definition;
 mod tungsten_scene;
+mod writer;
+mod xml;
 
+pub use crate::error::{Location, SceneImportError};
+pub use crate::matrix::DISK_SEGMENTS;
+pub use crate::obj::ObjError;
 pub use crate::tungsten_scene::*;
 
 use std::error::Error;
 use std::fs::{read, read_to_string};
 use std::path::Path;
 
-use tobj::load_obj;
-
 fn file_ext(path: &Path) -> &str {
     path.extension().and_then(|ext| ext.to_str()).unwrap_or("")
 }
 
-pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<SceneDescription, Box<dyn Error + Send + Sync>> {
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<SceneDescription, SceneImportError> {
     load_scene_impl(path.as_ref())
 }
 
-fn load_scene_impl(path: &Path) -> Result<SceneDescription, Box<dyn Error + Send + Sync>> {
-    Ok(match file_ext(path) {
-        "json" => {
-            let mut scene: SceneDescription = serde_json::from_str(&read_to_string(path)?)?;
-            for prim in scene.primitives.iter_mut() {
-                match &mut prim.primitive {
-                    PrimitiveType::Mesh { file, mesh_data, .. } => {
-                        let base_path = path.parent().unwrap();
-                        let mesh_path = base_path.join(file);
-                        let mesh = load_mesh(mesh_path.as_ref())?;
-                        *mesh_data = mesh;
-                    }
-                    _ => {},
-                };
+fn load_scene_impl(path: &Path) -> Result<SceneDescription, SceneImportError> {
+    let loaded = match file_ext(path) {
+        "json" => Ok(load_tungsten_scene(path)?),
+        "obj" => obj::load_obj_scene(path),
+        "gltf" | "glb" => gltf::load_gltf_scene(path),
+        "xml" => mitsuba::load_mitsuba_scene(path),
+        "pbrt" => pbrt::load_pbrt_scene(path),
+        format => return Err(SceneImportError::Unsupported {
+            feature: format!("scene file format {:?}", format),
+            reason: format!("{} can't be loaded", path.display()),
+        }),
+    };
+    let mut scene = loaded.map_err(|e| SceneImportError::from_loader(path, e))?;
+    scene.resolve_bsdfs();
+    Ok(scene)
+}
+
+/// Writes a scene in Tungsten's format, along with its meshes, which changes the files the scene refers to
+pub fn save_scene<P: AsRef<Path>>(scene: &mut SceneDescription, path: P) -> Result<(), SceneImportError> {
+    let path = path.as_ref();
+    match file_ext(path) {
+        "json" => writer::save_tungsten_scene(scene, path),
+        format => Err(SceneImportError::Unsupported {
+            feature: format!("scene file format {:?}", format),
+            reason: format!("{} can't be written", path.display()),
+        }),
+    }
+}
+
+fn load_tungsten_scene(path: &Path) -> Result<SceneDescription, SceneImportError> {
+    let text = read_to_string(path).map_err(|source| SceneImportError::Io { path: path.to_owned(), source })?;
+    let mut scene: SceneDescription = serde_json::from_str(&text).map_err(|e| SceneImportError::json(path, &text, e))?;
+    let base_path = path.parent().unwrap();
+    for medium in scene.media.iter_mut() {
+        if let MediumType::Voxel { file, grid_data, .. } = &mut medium.medium {
+            let grid_path = base_path.join(file);
+            if !grid_path.exists() {
+                scene.missing_files.push((grid_path, format!("Medium {}", medium.name)));
+                continue;
             }
-            scene
-        },
-        "obj" => {
-            let _obj = load_obj(path, true)?;
-            todo!()
-        },
-        format => return Err(format!("Unknown scene file format {}", format).into()),
-    })
+            *grid_data = load_grid(grid_path.as_ref()).map_err(|e| SceneImportError::from_loader(&grid_path, e))?;
+        }
+    }
+    for (i, prim) in scene.primitives.iter_mut().enumerate() {
+        match &mut prim.primitive {
+            PrimitiveType::Mesh { file, mesh_data, .. } | PrimitiveType::Instances { file, mesh_data, .. } => {
+                let mesh_path = base_path.join(file);
+                if !mesh_path.exists() {
+                    scene.missing_files.push((mesh_path, format!("Primitive {}", i)));
+                    continue;
+                }
+                *mesh_data = load_mesh_impl(mesh_path.as_ref()).map_err(|e| SceneImportError::from_loader(&mesh_path, e))?;
+            }
+            _ => {},
+        };
+    }
+    Ok(scene)
+}
+
+/// Reads a mesh from a .wo3, OBJ or PLY file
+pub fn load_mesh<P: AsRef<Path>>(path: P) -> Result<TriangleMesh, SceneImportError> {
+    let path = path.as_ref();
+    load_mesh_impl(path).map_err(|e| SceneImportError::from_loader(path, e))
+}
+
+/// Writes a mesh as a .wo3 file, which loads faster than other formats
+pub fn save_mesh<P: AsRef<Path>>(mesh: &TriangleMesh, path: P) -> Result<(), SceneImportError> {
+    let path = path.as_ref();
+    match file_ext(path) {
+        "wo3" => writer::save_wo3(mesh, path),
+        format => Err(SceneImportError::Unsupported {
+            feature: format!("mesh file format {:?}", format),
+            reason: format!("{} can't be written", path.display()),
+        }),
+    }
 }
 
-fn load_mesh(path: &Path) -> Result<TriangleMesh, Box<dyn Error + Send + Sync>> {
+fn load_mesh_impl(path: &Path) -> Result<TriangleMesh, Box<dyn Error + Send + Sync>> {
     Ok(match file_ext(path) {
         "wo3" => {
             // let mut file = File::open(path)?;
@@ -53,30 +115,153 @@ fn load_mesh(path: &Path) -> Result<TriangleMesh, Box<dyn Error + Send + Sync>>
             // let len = u64::from_le_bytes(buf);
             // mesh.verts = vec![Default::default(); len as usize];
             // mesh
-            bincode::deserialize(&read(path)?)?
-        },
-        "obj" => {
-            dbg!(path);
-            let (models, _) = tobj::load_obj(path, true)?;
-            let mut data = TriangleMesh::default();
-            for m in models {
-                let base = data.verts.len() as u32;
-                for idx in m.mesh.indices.chunks(3) {
-                    let tri = [idx[0] + base, idx[1] + base, idx[2] + base, 0];
-                    data.tris.push(tri);
+            let bytes = read(path)?;
+            let mut mesh: TriangleMesh = bincode::deserialize(&bytes)?;
+            // Tangents written by `save_mesh` follow the mesh
+            let tangents = &bytes[bincode::serialized_size(&mesh)? as usize..];
+            if !tangents.is_empty() {
+                let tangents: Vec<[f32; 4]> = bincode::deserialize(tangents)?;
+                if tangents.len() != mesh.verts.len() {
+                    return Err(format!("{} tangents for {} vertices", tangents.len(), mesh.verts.len()).into());
                 }
-                let vertices = m.mesh.positions.len() / 3;
-                for i in 0..vertices {
-                    let vertex = Vertex {
-                        pos: [m.mesh.positions[i*3], m.mesh.positions[i*3 + 1], m.mesh.positions[i*3 + 2]],
-                        normal: [m.mesh.normals[i*3], m.mesh.normals[i*3 + 1], m.mesh.normals[i*3 + 2]],
-                        uv: [m.mesh.texcoords[i*2], m.mesh.texcoords[i*2 + 1]],
-                    };
-                    data.verts.push(vertex);
+                for (vert, tangent) in mesh.verts.iter_mut().zip(tangents) {
+                    vert.tangent = tangent;
                 }
             }
-            data
-        }
+            mesh
+        },
+        "obj" => obj::load_obj_mesh(path)?,
+        "ply" => ply::parse_ply(&read(path)?)?,
         format => return Err(format!("Unknown mesh file format {}", format).into()),
     })
 }
+
+fn load_grid(path: &Path) -> Result<DensityGrid, Box<dyn Error + Send + Sync>> {
+    match file_ext(path) {
+        "vol" => parse_vol(&read(path)?),
+        format => Err(format!("Unknown grid file format {}", format).into()),
+    }
+}
+
+/// Reads a grid in Mitsuba's binary volume format. Only the first channel is kept
+fn parse_vol(bytes: &[u8]) -> Result<DensityGrid, Box<dyn Error + Send + Sync>> {
+    if bytes.len() < 48 || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+        return Err("Not a version 3 volume file".into());
+    }
+    let int_at = |i: usize| i32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    let float_at = |i: usize| f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+    let encoding = int_at(4);
+    let (xres, yres, zres, channels) = (int_at(8), int_at(12), int_at(16), int_at(20));
+    if xres <= 0 || yres <= 0 || zres <= 0 || channels <= 0 {
+        return Err("Invalid volume resolution".into());
+    }
+    let bounds_min = [float_at(24), float_at(28), float_at(32)];
+    let bounds_max = [float_at(36), float_at(40), float_at(44)];
+
+    let count = (xres * yres * zres) as usize;
+    let channels = channels as usize;
+    let stride = match encoding {
+        1 => 4,
+        3 => 1,
+        _ => return Err(format!("Unsupported volume encoding {}", encoding).into()),
+    };
+    let body = &bytes[48..];
+    if body.len() < count * channels * stride {
+        return Err("Volume file is truncated".into());
+    }
+    let data = (0..count).map(|i| {
+        let offset = i * channels * stride;
+        match encoding {
+            1 => float_at(48 + offset),
+            _ => body[offset] as f32 / 255.0,
+        }
+    }).collect();
+
+    Ok(DensityGrid {
+        resolution: [xres as u32, yres as u32, zres as u32],
+        bounds_min,
+        bounds_max,
+        data,
+    })
+}
+
+#[test]
+fn test_scene_errors() {
+    let dir = std::env::temp_dir().join("phosphor_test_errors");
+    std::fs::create_dir_all(&dir).unwrap();
+    let text = include_str!("../../../scenes/cornell_box.json");
+
+    let renamed = dir.join("renamed.json");
+    std::fs::write(&renamed, text.replacen("\"LeftWall\"", "\"Left\"", 1)).unwrap();
+    let problems = load_scene(&renamed).unwrap().problems();
+    assert_eq!(problems.len(), 1);
+    assert!(matches!(&problems[0], SceneImportError::UnknownBsdf { name, .. } if name == "LeftWall"));
+
+    let broken = dir.join("broken.json");
+    std::fs::write(&broken, text.replacen("6.8", "\"far\"", 1)).unwrap();
+    match load_scene(&broken) {
+        Err(SceneImportError::Parse { location: Some(location), .. }) => {
+            assert_eq!(location.json_path.as_deref(), Some("camera.transform"));
+        },
+        other => panic!("Expected a parse error, got {:?}", other.map(|_| ())),
+    }
+
+    // Every missing mesh is reported, not just the first
+    let meshes = dir.join("meshes.json");
+    std::fs::write(&meshes, text.replacen("\"type\": \"cube\"", "\"type\": \"mesh\", \"file\": \"a.wo3\"", 1)
+        .replacen("\"type\": \"cube\"", "\"type\": \"mesh\", \"file\": \"b.wo3\"", 1)).unwrap();
+    let problems = load_scene(&meshes).unwrap().problems();
+    assert_eq!(problems.len(), 2);
+    assert!(matches!(&problems[0], SceneImportError::MissingFile { path, .. } if path.ends_with("a.wo3")));
+    assert!(matches!(&problems[1], SceneImportError::MissingFile { path, .. } if path.ends_with("b.wo3")));
+
+    let unbalanced = dir.join("unbalanced.json");
+    std::fs::write(&unbalanced, "]").unwrap();
+    assert!(matches!(load_scene(&unbalanced), Err(SceneImportError::Parse { .. })));
+
+    let missing = load_scene(dir.join("missing.json"));
+    assert!(matches!(missing, Err(SceneImportError::Io { .. })));
+    let missing = load_scene(dir.join("missing.obj"));
+    assert!(matches!(missing, Err(SceneImportError::Io { .. })));
+
+    // The loader's error is kept, so callers can tell what went wrong
+    let empty = dir.join("empty.obj");
+    std::fs::write(&empty, "v 0 0 0\n").unwrap();
+    let error = load_scene(&empty).err().unwrap();
+    assert!(matches!(error.source().and_then(|e| e.downcast_ref::<ObjError>()), Some(ObjError::NoFaces)));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_bsdf_resolution() {
+    let dir = std::env::temp_dir().join("phosphor_test_resolution");
+    std::fs::create_dir_all(&dir).unwrap();
+    let text = include_str!("../../../scenes/cornell_box.json")
+        .replacen("\"bsdf\": \"LeftWall\"", r#""bsdf": {
+            "type": "mixed", "ratio": 0.3, "bsdf0": { "type": "lambert", "albedo": 0.2 }, "bsdf1": "Floor"
+        }"#, 1)
+        .replacen("\"name\": \"RightWall\"", "\"name\": \"Floor\"", 1);
+    let path = dir.join("inline.json");
+    std::fs::write(&path, text).unwrap();
+    let scene = load_scene(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(scene.bsdfs.len(), 10);
+    let inline = scene.primitives.iter().find(|p| p.bsdf.index() == Some(8)).unwrap();
+    assert!(matches!(inline.primitive, PrimitiveType::Quad));
+    match &scene.bsdfs[8].bsdf {
+        MaterialType::Mixed { bsdf0, bsdf1, .. } => {
+            assert_eq!(bsdf0.index(), Some(9));
+            assert!(matches!(scene.bsdfs[9].bsdf, MaterialType::Lambert {}));
+            // The first of the two BSDFs called Floor
+            assert_eq!(bsdf1.index(), Some(1));
+        },
+        _ => panic!("Inline BSDF isn't mixed"),
+    }
+
+    let problems = scene.problems();
+    assert_eq!(problems.len(), 2);
+    assert!(matches!(&problems[0], SceneImportError::DuplicateBsdf { name } if name == "Floor"));
+    assert!(matches!(&problems[1], SceneImportError::UnknownBsdf { name, .. } if name == "RightWall"));
+}
@@ -0,0 +1,206 @@
+use crate::tungsten_scene::*;
+
+/// A column major affine transform, as used by the scene formats that give transforms as matrices
+pub(crate) type Matrix = [f32; 16];
+
+pub(crate) const IDENTITY: Matrix = [
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 1.0, 0.0,
+    0.0, 0.0, 0.0, 1.0,
+];
+
+pub(crate) fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [0.0; 16];
+    for col in 0..4 {
+        for row in 0..4 {
+            m[col * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[col * 4 + k]).sum();
+        }
+    }
+    m
+}
+
+/// Converts between row major and column major
+pub(crate) fn transpose(m: &Matrix) -> Matrix {
+    let mut t = [0.0; 16];
+    for row in 0..4 {
+        for col in 0..4 {
+            t[row * 4 + col] = m[col * 4 + row];
+        }
+    }
+    t
+}
+
+pub(crate) fn translation(v: [f32; 3]) -> Matrix {
+    let mut m = IDENTITY;
+    m[12..15].copy_from_slice(&v);
+    m
+}
+
+pub(crate) fn scaling(s: [f32; 3]) -> Matrix {
+    let mut m = IDENTITY;
+    m[0] = s[0];
+    m[5] = s[1];
+    m[10] = s[2];
+    m
+}
+
+/// A rotation by an angle in degrees, anticlockwise around the axis
+pub(crate) fn rotation(axis: [f32; 3], degrees: f32) -> Matrix {
+    let [x, y, z] = normalize(axis);
+    let (s, c) = degrees.to_radians().sin_cos();
+    let t = 1.0 - c;
+    [
+        t * x * x + c, t * x * y + s * z, t * x * z - s * y, 0.0,
+        t * x * y - s * z, t * y * y + c, t * y * z + s * x, 0.0,
+        t * x * z + s * y, t * y * z - s * x, t * z * z + c, 0.0,
+        0.0, 0.0, 0.0, 1.0,
+    ]
+}
+
+/// A camera to world transform for a camera looking along +z with +y up. The x axis points left
+pub(crate) fn look_at(origin: [f32; 3], target: [f32; 3], up: [f32; 3]) -> Matrix {
+    let dir = normalize(sub(target, origin));
+    let left = normalize(cross(up, dir));
+    let up = cross(dir, left);
+    [
+        left[0], left[1], left[2], 0.0,
+        up[0], up[1], up[2], 0.0,
+        dir[0], dir[1], dir[2], 0.0,
+        origin[0], origin[1], origin[2], 1.0,
+    ]
+}
+
+pub(crate) fn transform_point(m: &Matrix, p: [f32; 3]) -> [f32; 3] {
+    let v = transform_vector(m, p);
+    [v[0] + m[12], v[1] + m[13], v[2] + m[14]]
+}
+
+pub(crate) fn transform_vector(m: &Matrix, v: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|row| m[row] * v[0] + m[4 + row] * v[1] + m[8 + row] * v[2])
+}
+
+pub(crate) fn determinant(m: &Matrix) -> f32 {
+    let c = cofactor(m);
+    m[0] * c[0] + m[1] * c[1] + m[2] * c[2]
+}
+
+/// The cofactor matrix of the upper 3x3 part
+pub(crate) fn cofactor(m: &Matrix) -> Matrix {
+    let a = |col: usize, row: usize| m[col * 4 + row];
+    let mut c = IDENTITY;
+    for col in 0..3 {
+        for row in 0..3 {
+            let (c0, c1) = ((col + 1) % 3, (col + 2) % 3);
+            let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+            c[col * 4 + row] = a(c0, r0) * a(c1, r1) - a(c1, r0) * a(c0, r1);
+        }
+    }
+    c
+}
+
+/// The inverse of an affine transform
+pub(crate) fn inverse(m: &Matrix) -> Matrix {
+    let det = determinant(m);
+    let mut inv = transpose(&cofactor(m)).map(|c| c / det);
+    inv[15] = 1.0;
+    let t = transform_vector(&inv, [m[12], m[13], m[14]]);
+    inv[12..15].copy_from_slice(&[-t[0], -t[1], -t[2]]);
+    inv
+}
+
+pub(crate) fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if len > 0.0 { v.map(|c| c / len) } else { v }
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// Moves a mesh into world space. Normals use the inverse transpose, which is the cofactor matrix up to scale.
+///  Mirroring reverses the winding, so it is flipped back to keep the faces pointing the same way
+pub(crate) fn transform_mesh(mesh: &mut TriangleMesh, m: &Matrix) {
+    let cofactor = cofactor(m);
+    let handedness = determinant(m).signum();
+    if handedness < 0.0 {
+        for tri in mesh.tris.iter_mut() {
+            tri.swap(1, 2);
+        }
+    }
+    for vert in mesh.verts.iter_mut() {
+        vert.pos = transform_point(m, vert.pos);
+        vert.normal = normalize(transform_vector(&cofactor, vert.normal).map(|c| c * handedness));
+        let [x, y, z] = normalize(transform_vector(m, [vert.tangent[0], vert.tangent[1], vert.tangent[2]]));
+        vert.tangent = [x, y, z, vert.tangent[3] * handedness];
+    }
+}
+
+/// The bounding box of the meshes, or None if there aren't any
+pub(crate) fn mesh_bounds(primitives: &[Primitive]) -> Option<([f32; 3], [f32; 3])> {
+    primitives.iter()
+        .filter_map(|prim| match &prim.primitive {
+            PrimitiveType::Mesh { mesh_data, .. } => Some(mesh_data.verts.iter()),
+            _ => None,
+        })
+        .flatten()
+        .fold(None, |bounds, v| {
+            let (min, max) = bounds.unwrap_or((v.pos, v.pos));
+            Some(([0, 1, 2].map(|i| min[i].min(v.pos[i])), [0, 1, 2].map(|i| max[i].max(v.pos[i]))))
+        })
+}
+
+/// Name of the BSDF the importers give primitives without one, defined like the format's default
+pub(crate) const DEFAULT_BSDF: &str = "default";
+
+/// How deeply includes can be nested, which stops files that include each other
+pub(crate) const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Number of segments in the meshes disks are turned into
+pub const DISK_SEGMENTS: u32 = 64;
+
+/// The index of refraction that gives a reflectance at normal incidence
+pub(crate) fn reflectance_eta(reflectance: [f32; 3]) -> [f32; 3] {
+    reflectance.map(|r| {
+        let r = r.clamp(0.0, 0.99).sqrt();
+        (1.0 + r) / (1.0 - r)
+    })
+}
+
+/// A disk with a radius of one in the xy plane and facing +z, like pbrt's and Mitsuba's
+pub(crate) fn disk() -> TriangleMesh {
+    let mut mesh = TriangleMesh::default();
+    mesh.verts.push(Vertex { normal: [0.0, 0.0, 1.0], uv: [0.5, 0.5], ..Default::default() });
+    for i in 0..DISK_SEGMENTS {
+        let (s, c) = (2.0 * std::f32::consts::PI * i as f32 / DISK_SEGMENTS as f32).sin_cos();
+        mesh.verts.push(Vertex {
+            pos: [c, s, 0.0],
+            normal: [0.0, 0.0, 1.0],
+            uv: [0.5 * (c + 1.0), 0.5 * (s + 1.0)],
+            ..Default::default()
+        });
+        mesh.tris.push([0, i + 1, (i + 1) % DISK_SEGMENTS + 1, 0]);
+    }
+    mesh
+}
+
+#[test]
+fn test_look_at() {
+    let m = look_at([1.0, 2.0, 3.0], [1.0, 2.0, 0.0], [0.0, 1.0, 0.0]);
+    assert_eq!(transform_point(&m, [0.0, 0.0, 1.0]), [1.0, 2.0, 2.0]);
+    assert_eq!(transform_vector(&m, [0.0, 1.0, 0.0]), [0.0, 1.0, 0.0]);
+    // Looking along -z, left is -x
+    assert_eq!(transform_vector(&m, [1.0, 0.0, 0.0]), [-1.0, 0.0, 0.0]);
+    // A quarter turn about z takes x to y
+    let r = rotation([0.0, 0.0, 1.0], 90.0);
+    let v = transform_vector(&r, [1.0, 0.0, 0.0]);
+    assert!(v[0].abs() < 1e-6 && (v[1] - 1.0).abs() < 1e-6 && v[2].abs() < 1e-6);
+}
@@ -0,0 +1,963 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::matrix::*;
+use crate::tungsten_scene::*;
+use crate::xml::*;
+
+/// Mitsuba's names for the refractive indices of common materials
+const NAMED_IORS: &[(&str, f32)] = &[
+    ("vacuum", 1.0),
+    ("helium", 1.00004),
+    ("hydrogen", 1.00013),
+    ("air", 1.000277),
+    ("carbon dioxide", 1.00045),
+    ("water", 1.333),
+    ("acetone", 1.36),
+    ("ethanol", 1.361),
+    ("carbon tetrachloride", 1.461),
+    ("glycerol", 1.4729),
+    ("benzene", 1.501),
+    ("silicone oil", 1.52045),
+    ("bromine", 1.661),
+    ("water ice", 1.31),
+    ("fused quartz", 1.458),
+    ("pyrex", 1.47),
+    ("acrylic glass", 1.49),
+    ("polypropylene", 1.49),
+    ("bk7", 1.5046),
+    ("sodium chloride", 1.544),
+    ("amber", 1.55),
+    ("pet", 1.575),
+    ("diamond", 2.419),
+];
+
+/// Objects declared with an id, which later elements can refer to
+enum Named {
+    /// The name of the BSDF, which differs from the id for BSDFs that wrap another one
+    Bsdf(String),
+    Medium,
+    Texture(Texture),
+}
+
+/// Loads a Mitsuba XML scene. Parameters given by `<default>` are substituted and `<include>`d files
+///  are read in place. Shapes, BSDFs and emitters Tungsten has no equivalent for are kept as unsupported
+///  items, so the renderer can report them
+pub(crate) fn load_mitsuba_scene(path: &Path) -> Result<SceneDescription, Box<dyn Error + Send + Sync>> {
+    let root = parse_xml(&read_to_string(path)?)?;
+    if root.name != "scene" {
+        return Err(format!("Mitsuba scene has a root element of {} instead of scene", root.name).into());
+    }
+    let base_path = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut params = HashMap::new();
+    let mut elements = Vec::new();
+    expand(&root, base_path, Path::new(""), &mut params, &mut elements, 0)?;
+
+    let mut importer = Importer {
+        base_path,
+        file: path.file_name().and_then(|f| f.to_str()).unwrap_or("").to_owned(),
+        bsdfs: Vec::new(),
+        media: Vec::new(),
+        primitives: Vec::new(),
+        camera: None,
+        integrator: IntegratorSettings::default(),
+        renderer: RendererSettings::default(),
+        ids: HashMap::new(),
+        uses_default_bsdf: false,
+    };
+    for element in &elements {
+        importer.add(element)?;
+    }
+
+    let Importer { mut bsdfs, media, primitives, camera, integrator, renderer, uses_default_bsdf, .. } = importer;
+    if uses_default_bsdf {
+        bsdfs.push(Material {
+            name: DEFAULT_BSDF.to_owned(),
+            bsdf: MaterialType::Lambert {},
+            albedo: Texture::Constant([0.5, 0.5, 0.5]),
+        });
+    }
+    let camera = match camera {
+        Some(camera) => camera,
+        None => match mesh_bounds(&primitives) {
+            Some((min, max)) => Camera::framing(min, max),
+            None => Camera::framing([-1.0; 3], [1.0; 3]),
+        },
+    };
+
+    Ok(SceneDescription {
+        media,
+        bsdfs,
+        primitives,
+        camera,
+        integrator,
+        renderer,
+        warnings: Vec::new(),
+        missing_files: Vec::new(),
+    })
+}
+
+/// Flattens the children of a scene into `out`, reading includes and substituting parameters.
+///  Relative file names in included files are made relative to the main scene's directory
+fn expand(scene: &XmlElement, base_path: &Path, dir: &Path, params: &mut HashMap<String, String>,
+          out: &mut Vec<XmlElement>, depth: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for child in &scene.children {
+        match child.name.as_str() {
+            "default" => {
+                let name = child.attr("name").ok_or("<default> has no name")?;
+                let value = child.attr("value").ok_or_else(|| format!("<default> {} has no value", name))?;
+                params.entry(name.to_owned()).or_insert_with(|| value.to_owned());
+            },
+            "include" => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err("Includes are nested too deeply".into());
+                }
+                let filename = substitute(child.attr("filename").ok_or("<include> has no filename")?, params)?;
+                let file = dir.join(filename);
+                let root = parse_xml(&read_to_string(base_path.join(&file))?)
+                    .map_err(|e| format!("Unable to read {}: {}", file.display(), e))?;
+                let include_dir = file.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+                expand(&root, base_path, &include_dir, params, out, depth + 1)?;
+            },
+            _ => out.push(resolve(child, dir, params)?),
+        }
+    }
+    Ok(())
+}
+
+/// Copies an element with its parameters substituted and its file names relative to the main scene
+fn resolve(element: &XmlElement, dir: &Path, params: &HashMap<String, String>) -> Result<XmlElement, Box<dyn Error + Send + Sync>> {
+    let is_filename = element.name == "string" && element.attr("name").map(normalize_name).as_deref() == Some("filename");
+    let mut attributes = Vec::with_capacity(element.attributes.len());
+    for (name, value) in &element.attributes {
+        let mut value = substitute(value, params)?;
+        if is_filename && name == "value" && Path::new(&value).is_relative() && dir != Path::new("") {
+            value = dir.join(value).to_string_lossy().into_owned();
+        }
+        attributes.push((name.clone(), value));
+    }
+    Ok(XmlElement {
+        name: element.name.clone(),
+        attributes,
+        children: element.children.iter().map(|c| resolve(c, dir, params)).collect::<Result<_, _>>()?,
+    })
+}
+
+/// Replaces `$name` with the value of the parameter
+fn substitute(value: &str, params: &HashMap<String, String>) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if !value.contains('$') {
+        return Ok(value.to_owned());
+    }
+    // Longer names first, so that $spp isn't replaced inside $spp_max
+    let mut names: Vec<&String> = params.keys().collect();
+    names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+    let mut value = value.to_owned();
+    for name in names {
+        value = value.replace(&format!("${}", name), &params[name]);
+    }
+    match value.find('$') {
+        Some(i) => Err(format!("Parameter {} has no value", &value[i..]).into()),
+        None => Ok(value),
+    }
+}
+
+/// Property names are compared ignoring case and underscores, so that Mitsuba 0.6's toWorld
+///  matches the to_world of later versions
+fn normalize_name(name: &str) -> String {
+    name.chars().filter(|&c| c != '_').flat_map(char::to_lowercase).collect()
+}
+
+fn property<'e>(element: &'e XmlElement, tags: &[&str], name: &str) -> Option<&'e XmlElement> {
+    element.children.iter().find(|child| {
+        tags.contains(&child.name.as_str()) && child.attr("name").map(normalize_name).as_deref() == Some(name)
+    })
+}
+
+fn numbers(value: &str) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> {
+    value.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().map_err(|_| format!("Invalid number {:?}", s).into()))
+        .collect()
+}
+
+fn value(element: &XmlElement) -> Result<&str, Box<dyn Error + Send + Sync>> {
+    element.attr("value").ok_or_else(|| format!("<{}> has no value", element.name).into())
+}
+
+fn float(element: &XmlElement, name: &str) -> Result<Option<f32>, Box<dyn Error + Send + Sync>> {
+    match property(element, &["float", "integer"], name) {
+        Some(p) => Ok(Some(value(p)?.trim().parse().map_err(|_| format!("Invalid value for {}", name))?)),
+        None => Ok(None),
+    }
+}
+
+fn string<'e>(element: &'e XmlElement, name: &str) -> Option<&'e str> {
+    property(element, &["string"], name).and_then(|p| p.attr("value"))
+}
+
+fn boolean(element: &XmlElement, name: &str) -> Result<Option<bool>, Box<dyn Error + Send + Sync>> {
+    match property(element, &["boolean"], name) {
+        Some(p) => Ok(Some(value(p)?.trim().parse().map_err(|_| format!("Invalid value for {}", name))?)),
+        None => Ok(None),
+    }
+}
+
+/// An RGB colour, a spectrum or a single number. Spectra given as wavelength:value pairs are averaged
+fn colour(element: &XmlElement, name: &str) -> Result<Option<[f32; 3]>, Box<dyn Error + Send + Sync>> {
+    let p = match property(element, &["rgb", "spectrum", "float", "integer"], name) {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let value = value(p)?;
+    let values = if value.contains(':') {
+        let samples = value.split(',')
+            .map(|pair| pair.split(':').nth(1).ok_or_else(|| format!("Invalid spectrum {:?}", value).into()))
+            .map(|v| v.and_then(numbers))
+            .collect::<Result<Vec<_>, Box<dyn Error + Send + Sync>>>()?;
+        let samples: Vec<f32> = samples.into_iter().flatten().collect();
+        vec![samples.iter().sum::<f32>() / samples.len().max(1) as f32]
+    } else {
+        numbers(value)?
+    };
+    match values[..] {
+        [v] => Ok(Some([v, v, v])),
+        [r, g, b] => Ok(Some([r, g, b])),
+        _ => Err(format!("{} should have one or three values", name).into()),
+    }
+}
+
+/// A point or vector given by x, y and z attributes or by a list of values
+fn xyz(element: &XmlElement, default: f32) -> Result<[f32; 3], Box<dyn Error + Send + Sync>> {
+    if let Some(value) = element.attr("value") {
+        return match numbers(value)?[..] {
+            [v] => Ok([v, v, v]),
+            [x, y, z] => Ok([x, y, z]),
+            _ => Err(format!("<{}> should have one or three values", element.name).into()),
+        };
+    }
+    let mut v = [default; 3];
+    for (c, axis) in v.iter_mut().zip(["x", "y", "z"]) {
+        if let Some(a) = element.attr(axis) {
+            *c = a.trim().parse().map_err(|_| format!("Invalid {} coordinate in <{}>", axis, element.name))?;
+        }
+    }
+    Ok(v)
+}
+
+fn point(element: &XmlElement, name: &str) -> Result<Option<[f32; 3]>, Box<dyn Error + Send + Sync>> {
+    property(element, &["point", "vector"], name).map(|p| xyz(p, 0.0)).transpose()
+}
+
+/// A transform built from a list of operations, each applied after the ones before it
+fn transform(element: &XmlElement, name: &str) -> Result<Matrix, Box<dyn Error + Send + Sync>> {
+    let t = match property(element, &["transform"], name) {
+        Some(t) => t,
+        None => return Ok(IDENTITY),
+    };
+    let vector = |op: &XmlElement, name: &str| -> Result<[f32; 3], Box<dyn Error + Send + Sync>> {
+        match numbers(op.attr(name).ok_or_else(|| format!("<{}> has no {}", op.name, name))?)?[..] {
+            [x, y, z] => Ok([x, y, z]),
+            _ => Err(format!("{} of <{}> should have three values", name, op.name).into()),
+        }
+    };
+    let mut m = IDENTITY;
+    for op in &t.children {
+        let op_matrix = match normalize_name(&op.name).as_str() {
+            "translate" => translation(xyz(op, 0.0)?),
+            "scale" => scaling(xyz(op, 1.0)?),
+            "rotate" => {
+                let angle = op.attr("angle").ok_or("<rotate> has no angle")?;
+                rotation(xyz(op, 0.0)?, angle.trim().parse().map_err(|_| "Invalid angle in <rotate>")?)
+            },
+            // Matrices are written row by row
+            "matrix" => match numbers(value(op)?)?[..] {
+                [a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p] => transpose(&[a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p]),
+                [a, b, c, d, e, f, g, h, i] => transpose(&[a, b, c, 0.0, d, e, f, 0.0, g, h, i, 0.0, 0.0, 0.0, 0.0, 1.0]),
+                _ => return Err("<matrix> should have 9 or 16 values".into()),
+            },
+            "lookat" => {
+                let up = match op.attr("up") {
+                    Some(_) => vector(op, "up")?,
+                    None => [0.0, 1.0, 0.0],
+                };
+                look_at(vector(op, "origin")?, vector(op, "target")?, up)
+            },
+            other => return Err(format!("Unknown transform operation {}", other).into()),
+        };
+        m = mul(&op_matrix, &m);
+    }
+    Ok(m)
+}
+
+fn refractive_index(element: &XmlElement, name: &str, default: f32) -> Result<f32, Box<dyn Error + Send + Sync>> {
+    if let Some(ior) = float(element, name)? {
+        return Ok(ior);
+    }
+    match string(element, name) {
+        Some(material) => NAMED_IORS.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(material.trim()))
+            .map(|&(_, ior)| ior)
+            .ok_or_else(|| format!("Unknown refractive index {}", material).into()),
+        None => Ok(default),
+    }
+}
+
+/// Tungsten's roughness, which is squared to get Mitsuba's alpha. Anisotropic BSDFs use the average
+fn roughness(element: &XmlElement) -> Result<f32, Box<dyn Error + Send + Sync>> {
+    let alpha = match (float(element, "alpha")?, float(element, "alphau")?, float(element, "alphav")?) {
+        (Some(alpha), ..) => alpha,
+        (None, Some(u), v) => 0.5 * (u + v.unwrap_or(u)),
+        (None, None, _) => 0.1,
+    };
+    Ok(alpha.max(0.0).sqrt())
+}
+
+fn distribution(element: &XmlElement) -> String {
+    match string(element, "distribution") {
+        Some("ggx") => "ggx".to_owned(),
+        Some("phong") => "phong".to_owned(),
+        _ => "beckmann".to_owned(),
+    }
+}
+
+struct Importer<'a> {
+    base_path: &'a Path,
+    /// Name of the scene file, given to shapes that aren't loaded from a mesh file
+    file: String,
+    bsdfs: Vec<Material>,
+    media: Vec<Medium>,
+    primitives: Vec<Primitive>,
+    camera: Option<Camera>,
+    integrator: IntegratorSettings,
+    renderer: RendererSettings,
+    ids: HashMap<String, Named>,
+    uses_default_bsdf: bool,
+}
+
+impl<'a> Importer<'a> {
+    fn add(&mut self, element: &XmlElement) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match element.name.as_str() {
+            "integrator" => {
+                // Integrators that produce other outputs wrap the one that renders the image
+                let mut integrator = Some(element);
+                while let Some(i) = integrator {
+                    if let Some(max_depth) = float(i, "maxdepth")? {
+                        // Mitsuba counts the segments of a path, with -1 meaning no limit
+                        if max_depth >= 1.0 {
+                            self.integrator.max_bounces = max_depth as u32 - 1;
+                        }
+                        break;
+                    }
+                    integrator = i.children.iter().find(|c| c.name == "integrator");
+                }
+            },
+            "sensor" => self.camera = Some(self.convert_sensor(element)?),
+            "bsdf" => {
+                let name = self.convert_bsdf(element, None)?;
+                if let Some(id) = element.attr("id") {
+                    self.ids.insert(id.to_owned(), Named::Bsdf(name));
+                }
+            },
+            "texture" => {
+                let texture = self.convert_texture(element)?;
+                if let Some(id) = element.attr("id") {
+                    self.ids.insert(id.to_owned(), Named::Texture(texture));
+                }
+            },
+            "medium" => {
+                self.convert_medium(element)?;
+            },
+            "shape" => self.add_shape(element)?,
+            "emitter" => self.add_emitter(element)?,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// A name for an object without an id that no other object has
+    fn anonymous_name(&self, prefix: &str) -> String {
+        (self.bsdfs.len() + self.media.len()..)
+            .map(|i| format!("{}{}", prefix, i))
+            .find(|name| !self.ids.contains_key(name) && self.bsdfs.iter().all(|b| &b.name != name)
+                && self.media.iter().all(|m| &m.name != name))
+            .unwrap()
+    }
+
+    fn find_ref<'e>(&self, element: &'e XmlElement, name: Option<&str>) -> Option<&'e str> {
+        element.children.iter()
+            .filter(|c| c.name == "ref" && c.attr("name").map(normalize_name).as_deref() == name)
+            .filter_map(|c| c.attr("id"))
+            .next()
+    }
+
+    /// A colour or a texture, either nested in the element or referred to by id
+    fn texture(&mut self, element: &XmlElement, name: &str) -> Result<Option<Texture>, Box<dyn Error + Send + Sync>> {
+        if let Some(c) = colour(element, name)? {
+            return Ok(Some(Texture::Constant(c)));
+        }
+        if let Some(t) = property(element, &["texture"], name) {
+            return Ok(Some(self.convert_texture(t)?));
+        }
+        let is_named = |c: &&XmlElement| c.name == "ref" && c.attr("name").map(normalize_name).as_deref() == Some(name);
+        match element.children.iter().find(is_named).and_then(|c| c.attr("id")) {
+            Some(id) => match self.ids.get(id) {
+                Some(Named::Texture(t)) => Ok(Some(t.clone())),
+                _ => Err(format!("{} isn't a texture", id).into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn convert_texture(&mut self, element: &XmlElement) -> Result<Texture, Box<dyn Error + Send + Sync>> {
+        let texture_type = element.attr("type").unwrap_or("");
+        Ok(match texture_type {
+            "bitmap" => Texture::Bitmap {
+                file: string(element, "filename").ok_or("Bitmap texture has no filename")?.to_owned(),
+            },
+            "checkerboard" => {
+                // The pattern has two squares in each direction before it's scaled
+                let uv = transform(element, "touv")?;
+                let scale = [
+                    float(element, "uscale")?.unwrap_or(1.0) * uv[0],
+                    float(element, "vscale")?.unwrap_or(1.0) * uv[5],
+                ];
+                Texture::Checker {
+                    on_color: colour(element, "color0")?.unwrap_or([0.4; 3]),
+                    off_color: colour(element, "color1")?.unwrap_or([0.2; 3]),
+                    res_u: (2.0 * scale[0].abs()).round().max(1.0) as u32,
+                    res_v: (2.0 * scale[1].abs()).round().max(1.0) as u32,
+                }
+            },
+            t => Texture::Unsupported { type_name: t.to_owned() },
+        })
+    }
+
+    /// The names of the BSDFs nested in or referred to by another one, in order
+    fn inner_bsdfs(&mut self, element: &XmlElement) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let mut names = Vec::new();
+        for child in &element.children {
+            match child.name.as_str() {
+                "bsdf" => names.push(self.convert_bsdf(child, None)?),
+                "ref" => match child.attr("id").and_then(|id| self.ids.get(id)) {
+                    Some(Named::Bsdf(name)) => names.push(name.clone()),
+                    Some(_) => {},
+                    None => return Err(format!("Unknown BSDF {}", child.attr("id").unwrap_or("")).into()),
+                },
+                _ => {},
+            }
+        }
+        Ok(names)
+    }
+
+    /// Adds a BSDF and returns its name, which is its id if it has one
+    fn convert_bsdf(&mut self, element: &XmlElement, name: Option<String>) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let name = name
+            .or_else(|| element.attr("id").map(str::to_owned))
+            .unwrap_or_else(|| self.anonymous_name("bsdf"));
+        let bsdf_type = element.attr("type").unwrap_or("");
+        let (bsdf, albedo) = match bsdf_type {
+            // Only the BSDF inside these is used
+            "twosided" | "bumpmap" | "normalmap" => {
+                return match element.children.iter().find(|c| c.name == "bsdf") {
+                    Some(inner) => self.convert_bsdf(inner, Some(name)),
+                    None => self.inner_bsdfs(element)?.into_iter().next()
+                        .ok_or_else(|| format!("BSDF {} has nothing inside it", name).into()),
+                };
+            },
+            "diffuse" | "roughdiffuse" => {
+                (MaterialType::Lambert {}, self.texture(element, "reflectance")?.unwrap_or(Texture::Constant([0.5; 3])))
+            },
+            "conductor" | "roughconductor" => {
+                let material = string(element, "material").unwrap_or("Cu");
+                let (mut eta, mut k) = (colour(element, "eta")?, colour(element, "k")?);
+                // A perfect mirror
+                if material == "none" && eta.is_none() {
+                    eta = Some(reflectance_eta([1.0; 3]));
+                    k = Some([0.0; 3]);
+                }
+                let bsdf = if bsdf_type == "conductor" {
+                    MaterialType::Conductor { material: material.to_owned(), eta, k }
+                } else {
+                    MaterialType::RoughConductor {
+                        roughness: roughness(element)?,
+                        material: material.to_owned(),
+                        eta,
+                        k,
+                        distribution: distribution(element),
+                    }
+                };
+                (bsdf, self.texture(element, "specularreflectance")?.unwrap_or(Texture::Constant([1.0; 3])))
+            },
+            "dielectric" | "thindielectric" | "roughdielectric" => {
+                let ior = refractive_index(element, "intior", 1.5046)? / refractive_index(element, "extior", 1.000277)?;
+                let bsdf = if bsdf_type == "roughdielectric" {
+                    MaterialType::RoughDielectric { ior, roughness: roughness(element)?, distribution: distribution(element) }
+                } else {
+                    MaterialType::Dielectric { ior }
+                };
+                (bsdf, self.texture(element, "speculartransmittance")?.unwrap_or(Texture::Constant([1.0; 3])))
+            },
+            "plastic" | "roughplastic" => {
+                let ior = refractive_index(element, "intior", 1.49)? / refractive_index(element, "extior", 1.000277)?;
+                let bsdf = if bsdf_type == "roughplastic" {
+                    MaterialType::RoughPlastic { ior, roughness: roughness(element)?, distribution: distribution(element) }
+                } else {
+                    MaterialType::Plastic { ior }
+                };
+                (bsdf, self.texture(element, "diffusereflectance")?.unwrap_or(Texture::Constant([0.5; 3])))
+            },
+            "principled" => {
+                let base_colour = self.texture(element, "basecolor")?.unwrap_or(Texture::Constant([0.5; 3]));
+                let roughness = float(element, "roughness")?.unwrap_or(0.5);
+                let metallic = float(element, "metallic")?.unwrap_or(0.0);
+                let ior = float(element, "eta")?.unwrap_or(1.5);
+                if float(element, "spectrans")?.unwrap_or(0.0) >= 0.5 {
+                    (MaterialType::RoughDielectric { ior, roughness, distribution: default_distribution() }, Texture::Constant([1.0; 3]))
+                } else if metallic >= 0.5 {
+                    let eta = reflectance_eta(base_colour.constant().unwrap_or([0.9; 3]));
+                    let metal = MaterialType::RoughConductor {
+                        roughness,
+                        material: String::new(),
+                        eta: Some(eta),
+                        k: Some([0.0; 3]),
+                        distribution: default_distribution(),
+                    };
+                    (metal, Texture::Constant([1.0; 3]))
+                } else {
+                    (MaterialType::RoughPlastic { ior, roughness, distribution: default_distribution() }, base_colour)
+                }
+            },
+            "blendbsdf" => {
+                let weight = float(element, "weight")?.unwrap_or(0.5);
+                match &self.inner_bsdfs(element)?[..] {
+                    // The weight is that of the second BSDF
+                    [bsdf0, bsdf1] => {
+                        let mixed = MaterialType::Mixed {
+                            bsdf0: BsdfRef::Name(bsdf0.clone()),
+                            bsdf1: BsdfRef::Name(bsdf1.clone()),
+                            ratio: 1.0 - weight,
+                        };
+                        (mixed, Texture::Constant([1.0; 3]))
+                    },
+                    _ => return Err(format!("Blend BSDF {} should have two BSDFs", name).into()),
+                }
+            },
+            "mask" => {
+                let alpha = self.texture(element, "opacity")?.unwrap_or(Texture::Constant([0.5; 3]));
+                let base = self.inner_bsdfs(element)?.into_iter().next()
+                    .ok_or_else(|| format!("Mask BSDF {} has nothing inside it", name))?;
+                (MaterialType::Transparency { base: BsdfRef::Name(base), alpha }, Texture::Constant([1.0; 3]))
+            },
+            "null" => (MaterialType::Null, Texture::Constant([1.0; 3])),
+            t => {
+                let unsupported = MaterialType::Unsupported {
+                    type_name: t.to_owned(),
+                    reason: "Mitsuba BSDF with no Tungsten equivalent".to_owned(),
+                };
+                (unsupported, Texture::Constant([0.5; 3]))
+            },
+        };
+        self.bsdfs.push(Material { name: name.clone(), bsdf, albedo });
+        Ok(name)
+    }
+
+    /// Adds a medium and returns its name
+    fn convert_medium(&mut self, element: &XmlElement) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let name = match element.attr("id") {
+            Some(id) => id.to_owned(),
+            None => self.anonymous_name("medium"),
+        };
+        let medium_type = element.attr("type").unwrap_or("");
+        let medium = match medium_type {
+            "homogeneous" => {
+                let (sigma_a, sigma_s) = match (colour(element, "sigmaa")?, colour(element, "sigmas")?) {
+                    (Some(sigma_a), Some(sigma_s)) => (sigma_a, sigma_s),
+                    _ => {
+                        let sigma_t = colour(element, "sigmat")?.unwrap_or([1.0; 3]);
+                        let albedo = colour(element, "albedo")?.unwrap_or([0.75; 3]);
+                        let sigma_s = [0, 1, 2].map(|i| sigma_t[i] * albedo[i]);
+                        ([0, 1, 2].map(|i| sigma_t[i] - sigma_s[i]), sigma_s)
+                    },
+                };
+                MediumType::Homogeneous { sigma_a, sigma_s, density: float(element, "scale")?.unwrap_or(1.0) }
+            },
+            t => MediumType::Unsupported {
+                type_name: t.to_owned(),
+                reason: "Mitsuba medium with no Tungsten equivalent".to_owned(),
+            },
+        };
+        let phase_function = match element.children.iter().find(|c| c.name == "phase") {
+            Some(phase) if phase.attr("type") == Some("hg") => PhaseFunction::HenyeyGreenstein {
+                g: float(phase, "g")?.unwrap_or(0.8),
+            },
+            _ => PhaseFunction::Isotropic,
+        };
+        self.media.push(Medium { name: name.clone(), medium, phase_function });
+        if let Some(id) = element.attr("id") {
+            self.ids.insert(id.to_owned(), Named::Medium);
+        }
+        Ok(name)
+    }
+
+    /// The medium nested in or referred to by an element, under the given property name
+    fn medium(&mut self, element: &XmlElement, name: Option<&str>) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let is_named = |c: &&XmlElement| c.name == "medium" && c.attr("name").map(normalize_name).as_deref() == name;
+        if let Some(inner) = element.children.iter().find(is_named) {
+            return Ok(Some(self.convert_medium(inner)?));
+        }
+        Ok(self.find_ref(element, name)
+            .filter(|id| matches!(self.ids.get(*id), Some(Named::Medium)))
+            .map(str::to_owned))
+    }
+
+    fn convert_sensor(&mut self, sensor: &XmlElement) -> Result<Camera, Box<dyn Error + Send + Sync>> {
+        let mut camera = Camera::look_at([0.0; 3], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0], 45.0);
+
+        // Any scale is only needed for orthographic cameras, which get it from ortho_width
+        let mut to_world = transform(sensor, "toworld")?;
+        let width_scale = transform_vector(&to_world, [1.0, 0.0, 0.0]).iter().map(|x| x * x).sum::<f32>().sqrt();
+        for axis in 0..3 {
+            let [x, y, z] = normalize([to_world[axis * 4], to_world[axis * 4 + 1], to_world[axis * 4 + 2]]);
+            to_world[axis * 4..axis * 4 + 3].copy_from_slice(&[x, y, z]);
+        }
+        camera.transform = CameraTransform::Matrix(transpose(&to_world));
+
+        match sensor.attr("type").unwrap_or("") {
+            "perspective" => {},
+            "thinlens" => {
+                camera.camera_type = "thinlens".to_owned();
+                if let Some(radius) = float(sensor, "apertureradius")? {
+                    camera.aperture_size = radius;
+                }
+                if let Some(distance) = float(sensor, "focusdistance")? {
+                    camera.focus_distance = distance;
+                }
+            },
+            "orthographic" => {
+                // The view spans -1 to 1 horizontally before it's scaled
+                camera.camera_type = "orthographic".to_owned();
+                camera.ortho_width = Some(2.0 * width_scale);
+            },
+            // Left for the renderer to report
+            t => camera.camera_type = t.to_owned(),
+        }
+
+        let fov_axis = string(sensor, "fovaxis").unwrap_or("x");
+        camera.fov_axis = match fov_axis {
+            "y" => FovAxis::Vertical,
+            "smaller" => FovAxis::Smaller,
+            "larger" => FovAxis::Larger,
+            _ => FovAxis::Horizontal,
+        };
+        camera.fov = match float(sensor, "fov")? {
+            Some(fov) => fov,
+            None => {
+                // The focal length of a lens on 35mm film, which is 36mm wide
+                let focal_length = string(sensor, "focallength").unwrap_or("50mm");
+                let focal_length: f32 = focal_length.trim().trim_end_matches("mm").parse()
+                    .map_err(|_| format!("Invalid focal length {}", focal_length))?;
+                camera.fov_axis = FovAxis::Horizontal;
+                (2.0 * (18.0 / focal_length).atan()).to_degrees()
+            },
+        };
+
+        let mut resolution = [768, 576];
+        if let Some(film) = sensor.children.iter().find(|c| c.name == "film") {
+            resolution = [
+                float(film, "width")?.map_or(resolution[0], |w| w as u32),
+                float(film, "height")?.map_or(resolution[1], |h| h as u32),
+            ];
+            if let Some(filter) = film.children.iter().find(|c| c.name == "rfilter") {
+                camera.reconstruction_filter = match filter.attr("type") {
+                    Some("box") => "box",
+                    Some("gaussian") => "gaussian",
+                    _ => "tent",
+                }.to_owned();
+            }
+        }
+        // Mitsuba measures a diagonal field of view across the corners of the image
+        if fov_axis == "diagonal" {
+            let [w, h] = resolution.map(|r| r as f32);
+            let half_width = (camera.fov.to_radians() / 2.0).tan() * w / (w * w + h * h).sqrt();
+            camera.fov = (2.0 * half_width.atan()).to_degrees();
+        }
+        camera.resolution = resolution;
+
+        if let Some(sampler) = sensor.children.iter().find(|c| c.name == "sampler") {
+            if let Some(spp) = float(sampler, "samplecount")? {
+                self.renderer.spp = spp as u32;
+            }
+        }
+        camera.medium = self.medium(sensor, None)?;
+        Ok(camera)
+    }
+
+    fn add_shape(&mut self, shape: &XmlElement) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let shape_type = shape.attr("type").unwrap_or("");
+        let to_world = transform(shape, "toworld")?;
+        let flip_normals = boolean(shape, "flipnormals")?.unwrap_or(false);
+
+        let mesh = |file: String, mut mesh_data: TriangleMesh, smooth: bool| {
+            if flip_normals {
+                for tri in mesh_data.tris.iter_mut() {
+                    tri.swap(1, 2);
+                }
+                for vert in mesh_data.verts.iter_mut() {
+                    vert.normal = vert.normal.map(|n| -n);
+                }
+            }
+            transform_mesh(&mut mesh_data, &to_world);
+            PrimitiveType::Mesh { file, smooth, backface_culling: false, recompute_normals: false, mesh_data }
+        };
+        let prim = Primitive::new(match shape_type {
+            "obj" | "ply" => {
+                let file = string(shape, "filename").ok_or("Mesh shape has no filename")?;
+                let mesh_data = crate::load_mesh_impl(&self.base_path.join(file))
+                    .map_err(|e| format!("Unable to load {}: {}", file, e))?;
+                let smooth = !boolean(shape, "facenormals")?.unwrap_or(false);
+                mesh(file.to_owned(), mesh_data, smooth)
+            },
+            "rectangle" => mesh(self.file.clone(), rectangle(), false),
+            "cube" => mesh(self.file.clone(), cube(), false),
+            "disk" => mesh(self.file.clone(), disk(), false),
+            "sphere" => {
+                let centre = point(shape, "center")?.unwrap_or([0.0; 3]);
+                let radius = float(shape, "radius")?.unwrap_or(1.0);
+                let mut prim = Primitive::new(PrimitiveType::Sphere {});
+                prim.transform.position = transform_point(&to_world, centre);
+                prim.transform.scale = [radius * determinant(&to_world).abs().cbrt(); 3];
+                self.finish_shape(shape, prim)?;
+                return Ok(());
+            },
+            t => PrimitiveType::Unsupported {
+                type_name: t.to_owned(),
+                reason: "Mitsuba shape with no Tungsten equivalent".to_owned(),
+            },
+        });
+        self.finish_shape(shape, prim)
+    }
+
+    /// Gives a shape its BSDF, emission and media and adds it to the scene
+    fn finish_shape(&mut self, shape: &XmlElement, mut prim: Primitive) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let bsdf = match shape.children.iter().find(|c| c.name == "bsdf") {
+            Some(inner) => Some(self.convert_bsdf(inner, None)?),
+            None => shape.children.iter()
+                .filter(|c| c.name == "ref")
+                .filter_map(|c| match c.attr("id").and_then(|id| self.ids.get(id)) {
+                    Some(Named::Bsdf(name)) => Some(name.clone()),
+                    _ => None,
+                })
+                .next(),
+        };
+        prim.bsdf = BsdfRef::Name(bsdf.unwrap_or_else(|| {
+            self.uses_default_bsdf = true;
+            DEFAULT_BSDF.to_owned()
+        }));
+        if let Some(emitter) = shape.children.iter().find(|c| c.name == "emitter" && c.attr("type") == Some("area")) {
+            prim.emission = self.texture(emitter, "radiance")?.unwrap_or(Texture::Constant([1.0; 3]));
+        }
+        prim.int_medium = self.medium(shape, Some("interior"))?;
+        prim.ext_medium = self.medium(shape, Some("exterior"))?;
+        self.primitives.push(prim);
+        Ok(())
+    }
+
+    fn add_emitter(&mut self, emitter: &XmlElement) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let to_world = transform(emitter, "toworld")?;
+        let emitter_type = emitter.attr("type").unwrap_or("");
+        let prim = match emitter_type {
+            "point" | "spot" => {
+                let mut prim = Primitive::new(PrimitiveType::Point {});
+                let position = point(emitter, "position")?.unwrap_or([0.0; 3]);
+                prim.transform.position = transform_point(&to_world, position);
+                prim.emission = self.texture(emitter, "intensity")?.unwrap_or(Texture::Constant([1.0; 3]));
+                prim
+            },
+            "constant" => {
+                let mut prim = Primitive::new(PrimitiveType::InfiniteSphere { sample: true });
+                prim.emission = self.texture(emitter, "radiance")?.unwrap_or(Texture::Constant([1.0; 3]));
+                prim
+            },
+            "envmap" => {
+                let mut prim = Primitive::new(PrimitiveType::InfiniteSphere { sample: true });
+                let file = string(emitter, "filename").ok_or("Environment map has no filename")?;
+                prim.emission = Texture::Bitmap { file: file.to_owned() };
+                prim
+            },
+            "directional" => {
+                // The light travels along the direction, or along +z before it's transformed
+                let direction = match point(emitter, "direction")? {
+                    Some(d) => d,
+                    None => transform_vector(&to_world, [0.0, 0.0, 1.0]),
+                };
+                let irradiance = colour(emitter, "irradiance")?.unwrap_or([1.0; 3]);
+                Primitive::distant_light(direction.map(|d| -d), irradiance)
+            },
+            "sky" | "sun" | "sunsky" => {
+                let mut sky = Primitive::new(PrimitiveType::Skydome {
+                    temperature: 5777.0,
+                    gamma_scale: 1.0,
+                    turbidity: float(emitter, "turbidity")?.unwrap_or(3.0),
+                    intensity: 2.0,
+                });
+                sky.emission = Texture::Constant([1.0; 3]);
+                sky
+            },
+            t => Primitive::new(PrimitiveType::Unsupported {
+                type_name: t.to_owned(),
+                reason: "Mitsuba emitter with no Tungsten equivalent".to_owned(),
+            }),
+        };
+        self.primitives.push(prim);
+        Ok(())
+    }
+}
+
+/// Mitsuba's rectangle, from -1 to 1 in x and y and facing +z
+fn rectangle() -> TriangleMesh {
+    let verts = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]].iter().map(|&[x, y]| Vertex {
+        pos: [x, y, 0.0],
+        normal: [0.0, 0.0, 1.0],
+        uv: [0.5 * (x + 1.0), 0.5 * (y + 1.0)],
+        ..Default::default()
+    }).collect();
+    TriangleMesh { verts, tris: vec![[0, 1, 2, 0], [0, 2, 3, 0]], ..Default::default() }
+}
+
+/// Mitsuba's cube, from -1 to 1 on each axis, with separate vertices for each face
+fn cube() -> TriangleMesh {
+    let mut mesh = TriangleMesh::default();
+    for axis in 0..3 {
+        for sign in [-1.0, 1.0] {
+            // u and v axes whose cross product points out of the face
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let (u, v) = if sign > 0.0 { (u, v) } else { (v, u) };
+            let base = mesh.verts.len() as u32;
+            for [a, b] in [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]] {
+                let mut pos = [0.0; 3];
+                pos[axis] = sign;
+                pos[u] = a;
+                pos[v] = b;
+                let mut normal = [0.0; 3];
+                normal[axis] = sign;
+                mesh.verts.push(Vertex { pos, normal, uv: [0.5 * (a + 1.0), 0.5 * (b + 1.0)], ..Default::default() });
+            }
+            mesh.tris.push([base, base + 1, base + 2, 0]);
+            mesh.tris.push([base, base + 2, base + 3, 0]);
+        }
+    }
+    mesh
+}
+
+#[test]
+fn test_mitsuba_scene() {
+    let dir = std::env::temp_dir().join("phosphor_test_mitsuba");
+    std::fs::create_dir_all(dir.join("parts")).unwrap();
+    let scene = r#"<?xml version="1.0"?>
+<scene version="3.0.0">
+    <default name="spp" value="32"/>
+    <default name="res" value="256"/>
+    <integrator type="path">
+        <integer name="max_depth" value="8"/>
+    </integrator>
+    <sensor type="perspective">
+        <float name="fov" value="40"/>
+        <transform name="to_world">
+            <lookat origin="0, 1, 4" target="0, 1, 0" up="0, 1, 0"/>
+        </transform>
+        <sampler type="independent">
+            <integer name="sample_count" value="$spp"/>
+        </sampler>
+        <film type="hdrfilm">
+            <integer name="width" value="$res"/>
+            <integer name="height" value="$res"/>
+        </film>
+    </sensor>
+    <bsdf type="twosided" id="white">
+        <bsdf type="diffuse">
+            <rgb name="reflectance" value="0.8, 0.8, 0.8"/>
+        </bsdf>
+    </bsdf>
+    <include filename="parts/light.xml"/>
+    <shape type="rectangle">
+        <transform name="to_world">
+            <scale value="2"/>
+            <rotate x="1" angle="-90"/>
+        </transform>
+        <ref id="white"/>
+    </shape>
+    <shape type="sphere">
+        <point name="center" x="0" y="1" z="0"/>
+        <float name="radius" value="0.5"/>
+        <bsdf type="dielectric">
+            <string name="int_ior" value="water"/>
+        </bsdf>
+    </shape>
+    <shape type="hair"/>
+</scene>"#;
+    let light = r#"<scene version="3.0.0">
+    <shape type="disk">
+        <transform name="toWorld">
+            <rotate x="1" angle="90"/>
+            <translate y="2"/>
+        </transform>
+        <emitter type="area">
+            <rgb name="radiance" value="10"/>
+        </emitter>
+    </shape>
+</scene>"#;
+    std::fs::write(dir.join("scene.xml"), scene).unwrap();
+    std::fs::write(dir.join("parts/light.xml"), light).unwrap();
+    let result = load_mitsuba_scene(&dir.join("scene.xml"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    let scene = result.unwrap();
+
+    assert_eq!(scene.integrator.max_bounces, 7);
+    assert_eq!(scene.renderer.spp, 32);
+    assert_eq!(scene.camera.resolution, [256, 256]);
+    assert_eq!(scene.camera.fov, 40.0);
+    match scene.camera.transform {
+        CameraTransform::Matrix(m) => {
+            let m = transpose(&m);
+            assert_eq!(transform_point(&m, [0.0; 3]), [0.0, 1.0, 4.0]);
+            assert_eq!(transform_vector(&m, [0.0, 0.0, 1.0]), [0.0, 0.0, -1.0]);
+        },
+        t => panic!("Expected a matrix, got {:?}", t),
+    }
+
+    let white = scene.bsdfs.iter().find(|b| b.name == "white").unwrap();
+    assert!(matches!(white.bsdf, MaterialType::Lambert {}));
+    assert_eq!(white.albedo.constant(), Some([0.8; 3]));
+
+    assert_eq!(scene.primitives.len(), 4);
+    // The light from the include is a disk facing down at y = 2
+    match &scene.primitives[0].primitive {
+        PrimitiveType::Mesh { mesh_data, .. } => {
+            assert!(mesh_data.verts.iter().all(|v| (v.pos[1] - 2.0).abs() < 1e-6));
+            assert!(mesh_data.verts.iter().all(|v| (v.normal[1] + 1.0).abs() < 1e-6));
+        },
+        p => panic!("Expected a mesh, got {:?}", p),
+    }
+    assert_eq!(scene.primitives[0].emission.constant(), Some([10.0; 3]));
+    assert_eq!(scene.primitives[0].bsdf.name(), Some(DEFAULT_BSDF));
+
+    // The floor is scaled by 2 and faces up
+    assert_eq!(scene.primitives[1].bsdf.name(), Some("white"));
+    match &scene.primitives[1].primitive {
+        PrimitiveType::Mesh { mesh_data, .. } => {
+            assert!(mesh_data.verts.iter().all(|v| v.pos[1].abs() < 1e-6 && (v.pos[0].abs() - 2.0).abs() < 1e-6));
+            assert!(mesh_data.verts.iter().all(|v| (v.normal[1] - 1.0).abs() < 1e-6));
+        },
+        p => panic!("Expected a mesh, got {:?}", p),
+    }
+
+    let sphere = &scene.primitives[2];
+    assert_eq!(sphere.transform.position, [0.0, 1.0, 0.0]);
+    assert_eq!(sphere.transform.scale, [0.5; 3]);
+    let glass = scene.bsdfs.iter().find(|b| sphere.bsdf.name() == Some(&b.name)).unwrap();
+    assert!(matches!(glass.bsdf, MaterialType::Dielectric { ior } if (ior - 1.333 / 1.000277).abs() < 1e-6));
+
+    assert!(matches!(&scene.primitives[3].primitive, PrimitiveType::Unsupported { type_name, .. } if type_name == "hair"));
+}
@@ -0,0 +1,329 @@
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use crate::matrix::{reflectance_eta, DEFAULT_BSDF};
+use crate::tungsten_scene::*;
+
+/// Reasons an OBJ file can't be loaded
+#[derive(Debug)]
+pub enum ObjError {
+    /// The file couldn't be read or parsed
+    Load(tobj::LoadError),
+    /// A face refers to a vertex the object doesn't have
+    IndexOutOfRange {
+        object: String,
+        index: u32,
+        vertices: usize,
+    },
+    /// There are no faces in the file
+    NoFaces,
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Load(e) => write!(f, "Unable to load OBJ file: {}", e),
+            ObjError::IndexOutOfRange { object, index, vertices } => {
+                write!(f, "Object {} has a face with vertex {} but only {} vertices", object, index, vertices)
+            },
+            ObjError::NoFaces => write!(f, "OBJ file has no faces"),
+        }
+    }
+}
+
+impl Error for ObjError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ObjError::Load(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<tobj::LoadError> for ObjError {
+    fn from(e: tobj::LoadError) -> Self {
+        ObjError::Load(e)
+    }
+}
+
+/// Loads an OBJ file as a scene. Each object becomes a mesh, its MTL materials are approximated
+///  with Tungsten BSDFs and a camera is placed so that everything is in view
+pub(crate) fn load_obj_scene(path: &Path) -> Result<SceneDescription, Box<dyn Error + Send + Sync>> {
+    let (models, obj_materials) = tobj::load_obj(path, true).map_err(ObjError::Load)?;
+    let file = path.file_name().and_then(|f| f.to_str()).unwrap_or("").to_owned();
+
+    let mut bsdfs = vec![Material {
+        name: DEFAULT_BSDF.to_owned(),
+        bsdf: MaterialType::Lambert {},
+        albedo: Texture::Constant([0.5, 0.5, 0.5]),
+    }];
+    bsdfs.extend(obj_materials.iter().map(convert_material));
+
+    let mut primitives = Vec::new();
+    let mut bounds_min = [f32::INFINITY; 3];
+    let mut bounds_max = [f32::NEG_INFINITY; 3];
+    let mut has_emitters = false;
+    for model in &models {
+        if model.mesh.indices.is_empty() {
+            continue;
+        }
+        for p in model.mesh.positions.chunks(3) {
+            for i in 0..3 {
+                bounds_min[i] = bounds_min[i].min(p[i]);
+                bounds_max[i] = bounds_max[i].max(p[i]);
+            }
+        }
+        let has_normals = !model.mesh.normals.is_empty();
+        let mut prim = Primitive::new(PrimitiveType::Mesh {
+            file: file.clone(),
+            smooth: has_normals,
+            backface_culling: false,
+            recompute_normals: false,
+            mesh_data: triangle_mesh(&model.name, &model.mesh)?,
+        });
+        match model.mesh.material_id.and_then(|id| obj_materials.get(id)) {
+            Some(m) => {
+                prim.bsdf = BsdfRef::Name(m.name.clone());
+                if let Some(ke) = emission(m) {
+                    prim.emission = Texture::Constant(ke);
+                    has_emitters = true;
+                }
+            },
+            None => prim.bsdf = BsdfRef::Name(DEFAULT_BSDF.to_owned()),
+        }
+        primitives.push(prim);
+    }
+    if primitives.is_empty() {
+        return Err(ObjError::NoFaces.into());
+    }
+
+    // Without any lights of its own, the model is lit by a white sky
+    if !has_emitters {
+        let mut sky = Primitive::new(PrimitiveType::InfiniteSphere { sample: true });
+        sky.emission = Texture::Constant([1.0, 1.0, 1.0]);
+        primitives.push(sky);
+    }
+
+    Ok(SceneDescription {
+        media: Vec::new(),
+        bsdfs,
+        primitives,
+        camera: Camera::framing(bounds_min, bounds_max),
+        integrator: IntegratorSettings::default(),
+        renderer: RendererSettings::default(),
+        warnings: Vec::new(),
+        missing_files: Vec::new(),
+    })
+}
+
+/// Reads every object in an OBJ file into one mesh. Objects without normals get area weighted
+///  normals and missing texture coordinates are zero. The fourth index of each triangle is
+///  the position of its object's material in the mesh's materials
+pub(crate) fn load_obj_mesh(path: &Path) -> Result<TriangleMesh, ObjError> {
+    let (models, obj_materials) = tobj::load_obj(path, true)?;
+    let mut data = TriangleMesh::default();
+    for model in &models {
+        let mut mesh = triangle_mesh(&model.name, &model.mesh)?;
+        if model.mesh.normals.is_empty() {
+            mesh.compute_normals();
+        }
+
+        let name = model.mesh.material_id
+            .and_then(|id| obj_materials.get(id))
+            .map_or("", |m| m.name.as_str());
+        let material = match data.materials.iter().position(|m| m == name) {
+            Some(i) => i,
+            None => {
+                data.materials.push(name.to_owned());
+                data.materials.len() - 1
+            },
+        };
+
+        let base = data.verts.len() as u32;
+        data.tris.extend(mesh.tris.iter().map(|t| [t[0] + base, t[1] + base, t[2] + base, material as u32]));
+        data.verts.extend(mesh.verts);
+    }
+    if data.tris.is_empty() {
+        return Err(ObjError::NoFaces);
+    }
+    Ok(data)
+}
+
+/// Converts a mesh loaded with a single index per vertex. Missing normals and texture coordinates are zero
+fn triangle_mesh(object: &str, mesh: &tobj::Mesh) -> Result<TriangleMesh, ObjError> {
+    let vertices = mesh.positions.len() / 3;
+    let verts = (0..vertices).map(|i| {
+        let normal = if mesh.normals.len() >= 3 * vertices {
+            [mesh.normals[i*3], mesh.normals[i*3 + 1], mesh.normals[i*3 + 2]]
+        } else {
+            [0.0; 3]
+        };
+        let uv = if mesh.texcoords.len() >= 2 * vertices {
+            [mesh.texcoords[i*2], mesh.texcoords[i*2 + 1]]
+        } else {
+            [0.0; 2]
+        };
+        Vertex {
+            pos: [mesh.positions[i*3], mesh.positions[i*3 + 1], mesh.positions[i*3 + 2]],
+            normal,
+            uv,
+            ..Default::default()
+        }
+    }).collect();
+    if let Some(&index) = mesh.indices.iter().find(|&&i| i as usize >= vertices) {
+        return Err(ObjError::IndexOutOfRange {
+            object: object.to_owned(),
+            index,
+            vertices,
+        });
+    }
+    let tris = mesh.indices.chunks_exact(3).map(|idx| [idx[0], idx[1], idx[2], 0]).collect();
+
+    Ok(TriangleMesh {
+        verts,
+        tris,
+        ..Default::default()
+    })
+}
+
+/// Maps the Phong style parameters of an MTL material onto the closest BSDF.
+///  Transparent materials become glass, specular only materials become metals whose
+///  reflectance is Ks and materials with both diffuse and specular colours become plastic
+fn convert_material(m: &tobj::Material) -> Material {
+    // Blinn-Phong exponents roughly match a Beckmann distribution with alpha = sqrt(2 / (Ns + 2)),
+    //  which is the square of the roughness
+    let roughness = (2.0 / (m.shininess.max(0.0) + 2.0)).powf(0.25);
+    let ior = if m.optical_density > 1.0 { m.optical_density } else { default_ior() };
+    let transparent = m.dissolve < 1.0 || matches!(m.illumination_model, Some(4) | Some(6) | Some(7) | Some(9));
+    let has_diffuse = m.diffuse.iter().any(|&c| c > 0.0);
+    let has_specular = m.specular.iter().any(|&c| c > 0.0);
+
+    let (bsdf, colour) = if transparent {
+        (MaterialType::RoughDielectric { ior, roughness, distribution: default_distribution() }, [1.0; 3])
+    } else if has_specular && !has_diffuse {
+        // Reflecting Ks at normal incidence
+        let eta = reflectance_eta(m.specular);
+        let metal = MaterialType::RoughConductor {
+            roughness,
+            material: String::new(),
+            eta: Some(eta),
+            k: Some([0.0; 3]),
+            distribution: default_distribution(),
+        };
+        (metal, [1.0; 3])
+    } else if has_specular {
+        (MaterialType::RoughPlastic { ior, roughness, distribution: default_distribution() }, m.diffuse)
+    } else {
+        (MaterialType::Lambert {}, m.diffuse)
+    };
+
+    let albedo = if m.diffuse_texture.is_empty() {
+        Texture::Constant(colour)
+    } else {
+        Texture::Bitmap { file: m.diffuse_texture.clone() }
+    };
+
+    Material {
+        name: m.name.clone(),
+        bsdf,
+        albedo,
+    }
+}
+
+/// The Ke colour, which tobj doesn't read itself
+fn emission(m: &tobj::Material) -> Option<[f32; 3]> {
+    let values: Vec<f32> = m.unknown_param.get("Ke")?
+        .split_whitespace()
+        .map(|v| v.parse())
+        .collect::<Result<_, _>>().ok()?;
+    let ke = match values[..] {
+        [c] => [c, c, c],
+        [r, g, b] => [r, g, b],
+        _ => return None,
+    };
+    if ke.iter().any(|&c| c > 0.0) { Some(ke) } else { None }
+}
+
+#[test]
+fn test_mtl_conversion() {
+    let mtl = "
+newmtl metal
+Ks 0.04 0.5 0.9
+Ns 1000
+
+newmtl lamp
+Kd 0.8 0.1 0.1
+Ke 2 2 1
+
+newmtl water
+Kd 0.8 0.8 0.8
+Ni 1.33
+d 0.2
+";
+    let (materials, _) = tobj::load_mtl_buf(&mut mtl.as_bytes()).unwrap();
+    assert_eq!(materials.len(), 3);
+
+    let metal = convert_material(&materials[0]);
+    match metal.bsdf {
+        MaterialType::RoughConductor { eta: Some(eta), k: Some(k), roughness, .. } => {
+            // Reflectance at normal incidence of a dielectric with index n
+            for i in 0..3 {
+                let r0 = ((eta[i] - 1.0) / (eta[i] + 1.0)).powi(2);
+                assert!((r0 - materials[0].specular[i]).abs() < 1e-4);
+                assert_eq!(k[i], 0.0);
+            }
+            assert!(roughness > 0.0 && roughness < 0.3);
+        },
+        b => panic!("Expected a conductor, got {:?}", b),
+    }
+
+    let lamp = convert_material(&materials[1]);
+    assert!(matches!(lamp.bsdf, MaterialType::Lambert {}));
+    assert_eq!(lamp.albedo.constant(), Some([0.8, 0.1, 0.1]));
+    assert_eq!(emission(&materials[1]), Some([2.0, 2.0, 1.0]));
+    assert_eq!(emission(&materials[0]), None);
+
+    assert!(matches!(convert_material(&materials[2]).bsdf, MaterialType::RoughDielectric { ior, .. } if ior == 1.33));
+}
+
+#[test]
+fn test_obj_without_normals() {
+    let dir = std::env::temp_dir();
+    let obj = "
+mtllib phosphor_test_objects.mtl
+o Floor
+v 0 0 0
+v 1 0 0
+v 1 0 -1
+v 0 0 -1
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+usemtl wood
+f 1/1 2/2 3/3 4/4
+o Post
+v 0 0 0
+v 0 1 0
+v 0.1 0 0
+usemtl metal
+f 5 6 7
+";
+    let mtl = "newmtl wood\nKd 0.5 0.3 0.1\nnewmtl metal\nKs 0.9 0.9 0.9\n";
+    std::fs::write(dir.join("phosphor_test_objects.obj"), obj).unwrap();
+    std::fs::write(dir.join("phosphor_test_objects.mtl"), mtl).unwrap();
+    let mesh = load_obj_mesh(&dir.join("phosphor_test_objects.obj"));
+    std::fs::remove_file(dir.join("phosphor_test_objects.obj")).unwrap();
+    std::fs::remove_file(dir.join("phosphor_test_objects.mtl")).unwrap();
+    let mesh = mesh.unwrap();
+
+    assert_eq!(mesh.verts.len(), 7);
+    assert_eq!(mesh.tris.len(), 3);
+    assert_eq!(mesh.materials, vec!["wood", "metal"]);
+    assert_eq!(mesh.tris.iter().map(|t| t[3]).collect::<Vec<_>>(), vec![0, 0, 1]);
+    // The floor faces up and the post faces along -z
+    assert!(mesh.verts[..4].iter().all(|v| v.normal == [0.0, 1.0, 0.0]));
+    assert!(mesh.verts[4..].iter().all(|v| v.normal == [0.0, 0.0, -1.0] && v.uv == [0.0, 0.0]));
+    assert_eq!(mesh.verts[2].uv, [1.0, 1.0]);
+}
@@ -0,0 +1,854 @@
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::matrix::*;
+use crate::tungsten_scene::*;
+
+/// pbrt's world is left handed, so it's mirrored in x to give the same image
+const MIRROR: Matrix = [
+    -1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 1.0, 0.0,
+    0.0, 0.0, 0.0, 1.0,
+];
+
+const DIRECTIVES: &[&str] = &[
+    "Accelerator", "ActiveTransform", "AreaLightSource", "Attribute", "AttributeBegin", "AttributeEnd",
+    "Camera", "ColorSpace", "ConcatTransform", "CoordinateSystem", "CoordSysTransform", "Film", "Identity",
+    "Import", "Include", "Integrator", "LightSource", "LookAt", "MakeNamedMaterial", "MakeNamedMedium",
+    "Material", "MediumInterface", "NamedMaterial", "ObjectBegin", "ObjectEnd", "ObjectInstance", "Option",
+    "PixelFilter", "ReverseOrientation", "Rotate", "Sampler", "Scale", "Shape", "Texture", "Transform",
+    "TransformBegin", "TransformEnd", "TransformTimes", "Translate", "WorldBegin", "WorldEnd",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Str(String),
+    /// A directive, a number or a boolean
+    Word(String),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {},
+            '#' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            },
+            '[' => tokens.push((Token::Open, line)),
+            ']' => tokens.push((Token::Close, line)),
+            '"' => {
+                let start = line;
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => s.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(c) => c,
+                            None => return Err(format!("Unterminated string on line {}", start)),
+                        }),
+                        Some('\n') => return Err(format!("Unterminated string on line {}", start)),
+                        Some(c) => s.push(c),
+                        None => return Err(format!("Unterminated string on line {}", start)),
+                    }
+                }
+                tokens.push((Token::Str(s), start));
+            },
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "[]\"#".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((Token::Word(word), line));
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f32),
+    /// Integer parameters are kept exact, as indices can be too large for an f32
+    Integer(i64),
+    Str(String),
+    Bool(bool),
+}
+
+/// A typed parameter, like `"rgb Kd" [0.5 0.5 0.5]`
+#[derive(Debug)]
+struct Param {
+    param_type: String,
+    name: String,
+    values: Vec<Value>,
+}
+
+struct Params(Vec<Param>);
+
+impl Params {
+    fn get(&self, name: &str) -> Option<&Param> {
+        self.0.iter().find(|p| p.name == name)
+    }
+
+    fn numbers(&self, name: &str) -> Option<Vec<f32>> {
+        self.get(name).map(|p| p.values.iter().filter_map(|v| match v {
+            Value::Number(n) => Some(*n),
+            Value::Integer(i) => Some(*i as f32),
+            _ => None,
+        }).collect())
+    }
+
+    fn integers(&self, name: &str) -> Option<Vec<i64>> {
+        self.get(name).map(|p| p.values.iter().filter_map(|v| match v {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }).collect())
+    }
+
+    fn float(&self, name: &str) -> Option<f32> {
+        self.numbers(name).and_then(|n| n.first().copied())
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.get(name).and_then(|p| p.values.first()) {
+            Some(Value::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn strings(&self, name: &str) -> Vec<&str> {
+        self.get(name).map_or(Vec::new(), |p| p.values.iter().filter_map(|v| match v {
+            Value::Str(s) => Some(s.as_str()),
+            _ => None,
+        }).collect())
+    }
+
+    fn boolean(&self, name: &str) -> Option<bool> {
+        match self.get(name).and_then(|p| p.values.first()) {
+            Some(Value::Bool(b)) => Some(*b),
+            Some(Value::Str(s)) => Some(s == "true"),
+            _ => None,
+        }
+    }
+
+    /// A colour given as RGB, a single number or a spectrum. Spectra are averaged, and blackbodies are white
+    fn colour(&self, name: &str) -> Option<[f32; 3]> {
+        let param = self.get(name)?;
+        let n = self.numbers(name)?;
+        match (param.param_type.as_str(), &n[..]) {
+            ("rgb" | "color", [r, g, b, ..]) => Some([*r, *g, *b]),
+            ("float", [v, ..]) => Some([*v; 3]),
+            ("spectrum", [_, ..]) => {
+                // Wavelength and value pairs
+                let values: Vec<f32> = n.iter().skip(1).step_by(2).copied().collect();
+                let average = values.iter().sum::<f32>() / values.len().max(1) as f32;
+                Some([average; 3])
+            },
+            // pbrt-v3 gives the temperature and a scale, v4 only the temperature
+            ("blackbody", [_, scale]) => Some([*scale; 3]),
+            ("blackbody", [_]) => Some([1.0; 3]),
+            _ => None,
+        }
+    }
+}
+
+/// The state that AttributeBegin saves and AttributeEnd restores
+#[derive(Clone)]
+struct GraphicsState {
+    /// Object to world, in pbrt's left handed world
+    ctm: Matrix,
+    material: String,
+    area_light: Option<Texture>,
+    reverse_orientation: bool,
+}
+
+/// Loads a scene in the pbrt-v3 or pbrt-v4 format. Directives that have no equivalent are skipped
+///  and reported in the scene's warnings with their line numbers
+pub(crate) fn load_pbrt_scene(path: &Path) -> Result<SceneDescription, Box<dyn Error + Send + Sync>> {
+    let mut importer = Importer {
+        base_path: path.parent().unwrap_or_else(|| Path::new("")),
+        state: GraphicsState {
+            ctm: IDENTITY,
+            material: DEFAULT_BSDF.to_owned(),
+            area_light: None,
+            reverse_orientation: false,
+        },
+        stack: Vec::new(),
+        coordinate_systems: HashMap::new(),
+        textures: HashMap::new(),
+        bsdfs: Vec::new(),
+        primitives: Vec::new(),
+        camera: None,
+        resolution: [1280, 720],
+        filter: "tent",
+        integrator: IntegratorSettings::default(),
+        renderer: RendererSettings::default(),
+        warnings: Vec::new(),
+        location: String::new(),
+        in_object: false,
+        uses_default_bsdf: false,
+    };
+    let file = path.file_name().and_then(|f| f.to_str()).unwrap_or("").to_owned();
+    importer.parse_file(&file, &read_to_string(path)?, 0)?;
+
+    let Importer { mut bsdfs, primitives, camera, resolution, filter, integrator, renderer, warnings, uses_default_bsdf, .. } = importer;
+    if uses_default_bsdf {
+        bsdfs.push(Material {
+            name: DEFAULT_BSDF.to_owned(),
+            bsdf: MaterialType::Lambert {},
+            albedo: Texture::Constant([0.5, 0.5, 0.5]),
+        });
+    }
+    let mut camera = match camera {
+        Some(camera) => camera,
+        None => match mesh_bounds(&primitives) {
+            Some((min, max)) => Camera::framing(min, max),
+            None => Camera::framing([-1.0; 3], [1.0; 3]),
+        },
+    };
+    camera.resolution = resolution;
+    camera.reconstruction_filter = filter.to_owned();
+    if camera.camera_type == "orthographic" {
+        // The screen window spans -1 to 1 along the shorter side
+        let aspect = resolution[0] as f32 / resolution[1] as f32;
+        camera.ortho_width = Some(2.0 * aspect.max(1.0));
+    }
+
+    Ok(SceneDescription {
+        media: Vec::new(),
+        bsdfs,
+        primitives,
+        camera,
+        integrator,
+        renderer,
+        warnings,
+        missing_files: Vec::new(),
+    })
+}
+
+struct Importer<'a> {
+    base_path: &'a Path,
+    state: GraphicsState,
+    stack: Vec<GraphicsState>,
+    coordinate_systems: HashMap<String, Matrix>,
+    textures: HashMap<String, Texture>,
+    bsdfs: Vec<Material>,
+    primitives: Vec<Primitive>,
+    camera: Option<Camera>,
+    resolution: [u32; 2],
+    filter: &'static str,
+    integrator: IntegratorSettings,
+    renderer: RendererSettings,
+    warnings: Vec<String>,
+    /// Where the directive being read is, for warnings
+    location: String,
+    /// Shapes between ObjectBegin and ObjectEnd are only drawn by instances, which aren't supported
+    in_object: bool,
+    uses_default_bsdf: bool,
+}
+
+impl<'a> Importer<'a> {
+    fn parse_file(&mut self, file: &str, text: &str, depth: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let tokens = tokenize(text).map_err(|e| format!("{} in {}", e, file))?;
+        let is_directive = |t: &Token| matches!(t, Token::Word(w) if DIRECTIVES.contains(&w.as_str()));
+        let mut i = 0;
+        while i < tokens.len() {
+            let (name, line) = match &tokens[i] {
+                (Token::Word(w), line) if is_directive(&tokens[i].0) => (w.as_str(), *line),
+                (_, line) => return Err(format!("Expected a directive on line {} of {}", line, file).into()),
+            };
+            let end = (i + 1..tokens.len()).find(|&j| is_directive(&tokens[j].0)).unwrap_or(tokens.len());
+            let args: Vec<Token> = tokens[i + 1..end].iter().map(|(t, _)| t.clone()).collect();
+            self.location = format!("line {} of {}", line, file);
+            self.directive(name, &args, depth)
+                .map_err(|e| format!("{} on line {} of {}", e, line, file))?;
+            i = end;
+        }
+        Ok(())
+    }
+
+    /// Records a warning along with the directive it came from
+    fn warn(&mut self, warning: String) {
+        let warning = format!("{} on {}", warning, self.location);
+        self.warnings.push(warning);
+    }
+
+    fn directive(&mut self, name: &str, args: &[Token], depth: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match name {
+            "AttributeBegin" | "TransformBegin" => self.stack.push(self.state.clone()),
+            "AttributeEnd" | "TransformEnd" => {
+                let state = self.stack.pop().ok_or_else(|| format!("{} without a matching begin", name))?;
+                if name == "TransformEnd" {
+                    self.state.ctm = state.ctm;
+                } else {
+                    self.state = state;
+                }
+            },
+            "WorldBegin" => {
+                self.state.ctm = IDENTITY;
+                self.coordinate_systems.insert("world".to_owned(), IDENTITY);
+            },
+            "WorldEnd" => {},
+            "Identity" => self.state.ctm = IDENTITY,
+            "Translate" => {
+                let [x, y, z] = fixed_numbers(args)?;
+                self.concat(&translation([x, y, z]));
+            },
+            "Scale" => {
+                let [x, y, z] = fixed_numbers(args)?;
+                self.concat(&scaling([x, y, z]));
+            },
+            "Rotate" => {
+                let [angle, x, y, z] = fixed_numbers(args)?;
+                self.concat(&rotation([x, y, z], angle));
+            },
+            "LookAt" => {
+                let [ex, ey, ez, lx, ly, lz, ux, uy, uz] = fixed_numbers(args)?;
+                self.concat(&inverse(&look_at([ex, ey, ez], [lx, ly, lz], [ux, uy, uz])));
+            },
+            // Matrices are written with the translation last, which is column major
+            "Transform" => self.state.ctm = fixed_numbers(args)?,
+            "ConcatTransform" => self.concat(&fixed_numbers(args)?),
+            "CoordinateSystem" => {
+                let name = string_arg(args, 0)?.to_owned();
+                self.coordinate_systems.insert(name, self.state.ctm);
+            },
+            "CoordSysTransform" => {
+                let name = string_arg(args, 0)?;
+                match self.coordinate_systems.get(name) {
+                    Some(m) => self.state.ctm = *m,
+                    None => self.warn(format!("Unknown coordinate system {}", name)),
+                }
+            },
+            "ReverseOrientation" => self.state.reverse_orientation = !self.state.reverse_orientation,
+            "Camera" => {
+                let camera = self.convert_camera(string_arg(args, 0)?, &params(args, 1)?);
+                self.camera = Some(camera);
+                self.coordinate_systems.insert("camera".to_owned(), inverse(&self.state.ctm));
+            },
+            "Film" => {
+                let p = params(args, 1)?;
+                self.resolution = [
+                    p.float("xresolution").map_or(self.resolution[0], |x| x as u32),
+                    p.float("yresolution").map_or(self.resolution[1], |y| y as u32),
+                ];
+                if let Some(file) = p.string("filename") {
+                    self.renderer.output_file = file.to_owned();
+                }
+            },
+            "Sampler" => {
+                if let Some(spp) = params(args, 1)?.float("pixelsamples") {
+                    self.renderer.spp = spp as u32;
+                }
+            },
+            "Integrator" => {
+                if let Some(max_depth) = params(args, 1)?.float("maxdepth") {
+                    self.integrator.max_bounces = max_depth.max(0.0) as u32;
+                }
+            },
+            "PixelFilter" => {
+                self.filter = match string_arg(args, 0)? {
+                    "box" => "box",
+                    "gaussian" => "gaussian",
+                    _ => "tent",
+                };
+            },
+            "Accelerator" | "ColorSpace" | "Option" => {},
+            "Material" => {
+                let name = self.anonymous_name();
+                let material = self.convert_material(name.clone(), string_arg(args, 0)?, &params(args, 1)?);
+                self.bsdfs.push(material);
+                self.state.material = name;
+            },
+            "MakeNamedMaterial" => {
+                let name = string_arg(args, 0)?.to_owned();
+                let p = params(args, 1)?;
+                let material_type = p.string("type").ok_or("Named material has no type")?.to_owned();
+                let material = self.convert_material(name, &material_type, &p);
+                self.bsdfs.push(material);
+            },
+            "NamedMaterial" => {
+                let name = string_arg(args, 0)?;
+                if !self.bsdfs.iter().any(|b| b.name == name) {
+                    self.warn(format!("Unknown material {}", name));
+                }
+                self.state.material = name.to_owned();
+            },
+            "Texture" => {
+                let name = string_arg(args, 0)?.to_owned();
+                let texture = self.convert_texture(string_arg(args, 2)?, &params(args, 3)?);
+                self.textures.insert(name, texture);
+            },
+            "Shape" => {
+                let shape_type = string_arg(args, 0)?;
+                if self.in_object {
+                    return Ok(());
+                }
+                let prim = self.convert_shape(shape_type, &params(args, 1)?)?;
+                self.primitives.push(prim);
+            },
+            "AreaLightSource" => {
+                let p = params(args, 1)?;
+                let scale = p.float("scale").unwrap_or(1.0);
+                let radiance = p.colour("L").unwrap_or([1.0; 3]);
+                self.state.area_light = Some(Texture::Constant(radiance.map(|l| l * scale)));
+            },
+            "LightSource" => {
+                let prim = self.convert_light(string_arg(args, 0)?, &params(args, 1)?);
+                self.primitives.push(prim);
+            },
+            "Include" | "Import" => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err("Includes are nested too deeply".into());
+                }
+                let file = string_arg(args, 0)?.to_owned();
+                let text = read_to_string(self.base_path.join(&file))
+                    .map_err(|e| format!("Unable to read {}: {}", file, e))?;
+                self.parse_file(&file, &text, depth + 1)?;
+            },
+            "ObjectBegin" => {
+                self.stack.push(self.state.clone());
+                self.in_object = true;
+                self.warn(format!("Object instancing isn't supported, skipping object {}", string_arg(args, 0)?));
+            },
+            "ObjectEnd" => {
+                self.state = self.stack.pop().ok_or("ObjectEnd without a matching ObjectBegin")?;
+                self.in_object = false;
+            },
+            directive => self.warn(format!("Unsupported directive {}", directive)),
+        }
+        Ok(())
+    }
+
+    fn concat(&mut self, m: &Matrix) {
+        self.state.ctm = mul(&self.state.ctm, m);
+    }
+
+    /// Object to world in Tungsten's right handed world
+    fn world(&self) -> Matrix {
+        mul(&MIRROR, &self.state.ctm)
+    }
+
+    fn anonymous_name(&self) -> String {
+        (self.bsdfs.len()..)
+            .map(|i| format!("material{}", i))
+            .find(|name| self.bsdfs.iter().all(|b| &b.name != name))
+            .unwrap()
+    }
+
+    fn convert_camera(&mut self, camera_type: &str, p: &Params) -> Camera {
+        let mut camera = Camera::look_at([0.0; 3], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0], p.float("fov").unwrap_or(90.0));
+        // The current transform is world to camera
+        let to_world = mul(&MIRROR, &inverse(&self.state.ctm));
+        camera.transform = CameraTransform::Matrix(transpose(&to_world));
+        camera.fov_axis = FovAxis::Smaller;
+        match camera_type {
+            "perspective" => {
+                let radius = p.float("lensradius").unwrap_or(0.0);
+                if radius > 0.0 {
+                    camera.camera_type = "thinlens".to_owned();
+                    camera.aperture_size = radius;
+                    camera.focus_distance = p.float("focaldistance").unwrap_or(1e6);
+                }
+            },
+            "orthographic" => camera.camera_type = "orthographic".to_owned(),
+            "spherical" => camera.camera_type = "equirectangular".to_owned(),
+            t => self.warn(format!("Unsupported camera {}, using a perspective camera", t)),
+        }
+        camera
+    }
+
+    /// A texture parameter, which can be a colour or the name of a texture
+    fn texture(&mut self, p: &Params, name: &str) -> Option<Texture> {
+        if let Some(c) = p.colour(name) {
+            return Some(Texture::Constant(c));
+        }
+        let texture_name = p.get(name).filter(|param| param.param_type == "texture").and(p.string(name))?;
+        match self.textures.get(texture_name) {
+            Some(t) => Some(t.clone()),
+            None => {
+                self.warn(format!("Unknown texture {}", texture_name));
+                None
+            },
+        }
+    }
+
+    fn convert_texture(&mut self, class: &str, p: &Params) -> Texture {
+        match class {
+            "imagemap" => match p.string("filename") {
+                Some(file) => Texture::Bitmap { file: file.to_owned() },
+                None => Texture::Unsupported { type_name: class.to_owned() },
+            },
+            "checkerboard" => Texture::Checker {
+                on_color: self.texture(p, "tex1").and_then(|t| t.constant()).unwrap_or([1.0; 3]),
+                off_color: self.texture(p, "tex2").and_then(|t| t.constant()).unwrap_or([0.0; 3]),
+                res_u: p.float("uscale").unwrap_or(1.0).abs().round().max(1.0) as u32,
+                res_v: p.float("vscale").unwrap_or(1.0).abs().round().max(1.0) as u32,
+            },
+            "constant" => Texture::Constant(p.colour("value").unwrap_or([1.0; 3])),
+            t => Texture::Unsupported { type_name: t.to_owned() },
+        }
+    }
+
+    fn convert_material(&mut self, name: String, material_type: &str, p: &Params) -> Material {
+        // pbrt-v4 remaps roughness to alpha with a square root, and Tungsten squares its roughness
+        let roughness = |default: f32| {
+            let r = p.float("roughness").or_else(|| p.float("uroughness")).unwrap_or(default);
+            if p.boolean("remaproughness").unwrap_or(true) { r.max(0.0).sqrt().sqrt() } else { r.max(0.0).sqrt() }
+        };
+        let eta = p.float("eta").or_else(|| p.float("index")).unwrap_or(1.5);
+        let (bsdf, albedo) = match material_type {
+            "matte" | "diffuse" => {
+                (MaterialType::Lambert {}, self.texture(p, "Kd").or_else(|| self.texture(p, "reflectance")))
+            },
+            "plastic" | "coateddiffuse" => {
+                let roughness = roughness(if material_type == "plastic" { 0.1 } else { 0.0 });
+                let bsdf = if roughness > 0.0 {
+                    MaterialType::RoughPlastic { ior: eta, roughness, distribution: default_distribution() }
+                } else {
+                    MaterialType::Plastic { ior: eta }
+                };
+                (bsdf, self.texture(p, "Kd").or_else(|| self.texture(p, "reflectance")))
+            },
+            "metal" | "conductor" => {
+                // Named spectra like metal-Au-eta are Tungsten's metals
+                let metal = p.string("eta")
+                    .and_then(|s| s.strip_prefix("metal-"))
+                    .and_then(|s| s.strip_suffix("-eta"))
+                    .unwrap_or("Cu")
+                    .to_owned();
+                let (eta, k) = match (p.colour("eta"), p.colour("k"), p.colour("reflectance")) {
+                    (_, _, Some(reflectance)) => (Some(reflectance_eta(reflectance)), Some([0.0; 3])),
+                    (eta, k, None) => (eta, k),
+                };
+                let roughness = roughness(0.0);
+                let bsdf = if roughness > 0.0 {
+                    MaterialType::RoughConductor { roughness, material: metal, eta, k, distribution: default_distribution() }
+                } else {
+                    MaterialType::Conductor { material: metal, eta, k }
+                };
+                (bsdf, None)
+            },
+            "glass" | "dielectric" | "thindielectric" => {
+                let roughness = roughness(0.0);
+                let bsdf = if roughness > 0.0 && material_type != "thindielectric" {
+                    MaterialType::RoughDielectric { ior: eta, roughness, distribution: default_distribution() }
+                } else {
+                    MaterialType::Dielectric { ior: eta }
+                };
+                (bsdf, None)
+            },
+            "mirror" => (MaterialType::Mirror {}, self.texture(p, "Kr")),
+            "mix" => {
+                let mut materials: Vec<String> = p.strings("materials").into_iter().map(str::to_owned).collect();
+                // pbrt-v3 names them separately
+                materials.extend(["namedmaterial1", "namedmaterial2"].iter().filter_map(|n| p.string(n).map(str::to_owned)));
+                match &materials[..] {
+                    // The amount is the weight of the second material
+                    [bsdf0, bsdf1] => {
+                        let ratio = 1.0 - p.float("amount").unwrap_or(0.5);
+                        let (bsdf0, bsdf1) = (BsdfRef::Name(bsdf0.clone()), BsdfRef::Name(bsdf1.clone()));
+                        (MaterialType::Mixed { bsdf0, bsdf1, ratio }, None)
+                    },
+                    _ => (unsupported(material_type, "A mix material needs two materials"), None),
+                }
+            },
+            "" | "none" | "interface" => (MaterialType::Null, None),
+            t => (unsupported(t, "pbrt material with no Tungsten equivalent"), None),
+        };
+        let default_albedo = match bsdf {
+            MaterialType::Lambert {} | MaterialType::Plastic { .. } | MaterialType::RoughPlastic { .. } => [0.5; 3],
+            _ => [1.0; 3],
+        };
+        Material {
+            name,
+            bsdf,
+            albedo: albedo.unwrap_or(Texture::Constant(default_albedo)),
+        }
+    }
+
+    fn convert_shape(&mut self, shape_type: &str, p: &Params) -> Result<Primitive, Box<dyn Error + Send + Sync>> {
+        let world = self.world();
+        let reverse_orientation = self.state.reverse_orientation;
+        let mesh = |file: String, mut mesh_data: TriangleMesh, smooth: bool| {
+            if reverse_orientation {
+                for tri in mesh_data.tris.iter_mut() {
+                    tri.swap(1, 2);
+                }
+                for vert in mesh_data.verts.iter_mut() {
+                    vert.normal = vert.normal.map(|n| -n);
+                }
+            }
+            transform_mesh(&mut mesh_data, &world);
+            PrimitiveType::Mesh { file, smooth, backface_culling: false, recompute_normals: false, mesh_data }
+        };
+        let mut prim = Primitive::new(match shape_type {
+            "trianglemesh" => {
+                let (mesh_data, has_normals) = triangle_mesh(p)?;
+                mesh(String::new(), mesh_data, has_normals)
+            },
+            "plymesh" => {
+                let file = p.string("filename").ok_or("PLY mesh has no filename")?;
+                let mesh_data = crate::load_mesh_impl(&self.base_path.join(file))
+                    .map_err(|e| format!("Unable to load {}: {}", file, e))?;
+                mesh(file.to_owned(), mesh_data, true)
+            },
+            "disk" => {
+                let radius = p.float("radius").unwrap_or(1.0);
+                let height = p.float("height").unwrap_or(0.0);
+                let mut disk = disk();
+                transform_mesh(&mut disk, &mul(&translation([0.0, 0.0, height]), &scaling([radius, radius, 1.0])));
+                mesh(String::new(), disk, false)
+            },
+            "sphere" => PrimitiveType::Sphere {},
+            t => PrimitiveType::Unsupported {
+                type_name: t.to_owned(),
+                reason: "pbrt shape with no Tungsten equivalent".to_owned(),
+            },
+        });
+        if let PrimitiveType::Sphere {} = prim.primitive {
+            // Spheres stay round, so only the average scale is kept
+            prim.transform.position = transform_point(&world, [0.0; 3]);
+            prim.transform.scale = [p.float("radius").unwrap_or(1.0) * determinant(&world).abs().cbrt(); 3];
+        }
+        if self.state.material == DEFAULT_BSDF {
+            self.uses_default_bsdf = true;
+        }
+        prim.bsdf = BsdfRef::Name(self.state.material.clone());
+        if let Some(emission) = &self.state.area_light {
+            prim.emission = emission.clone();
+        }
+        Ok(prim)
+    }
+
+    fn convert_light(&mut self, light_type: &str, p: &Params) -> Primitive {
+        let world = self.world();
+        let scale = p.colour("scale").unwrap_or([1.0; 3]);
+        let scaled = |c: [f32; 3]| [0, 1, 2].map(|i| c[i] * scale[i]);
+        let mut prim = match light_type {
+            "point" | "spot" => {
+                let mut prim = Primitive::new(PrimitiveType::Point {});
+                let from = p.numbers("from").filter(|f| f.len() == 3).map_or([0.0; 3], |f| [f[0], f[1], f[2]]);
+                prim.transform.position = transform_point(&world, from);
+                prim.emission = Texture::Constant(scaled(p.colour("I").unwrap_or([1.0; 3])));
+                prim
+            },
+            "distant" => {
+                let from = p.numbers("from").filter(|f| f.len() == 3).map_or([0.0; 3], |f| [f[0], f[1], f[2]]);
+                let to = p.numbers("to").filter(|t| t.len() == 3).map_or([0.0, 0.0, 1.0], |t| [t[0], t[1], t[2]]);
+                let direction = transform_vector(&world, [from[0] - to[0], from[1] - to[1], from[2] - to[2]]);
+                Primitive::distant_light(direction, scaled(p.colour("L").unwrap_or([1.0; 3])))
+            },
+            "infinite" => {
+                let mut prim = Primitive::new(PrimitiveType::InfiniteSphere { sample: true });
+                prim.emission = match p.string("mapname").or_else(|| p.string("filename")) {
+                    Some(file) => Texture::Bitmap { file: file.to_owned() },
+                    None => Texture::Constant(scaled(p.colour("L").unwrap_or([1.0; 3]))),
+                };
+                prim
+            },
+            t => Primitive::new(PrimitiveType::Unsupported {
+                type_name: t.to_owned(),
+                reason: "pbrt light with no Tungsten equivalent".to_owned(),
+            }),
+        };
+        prim.power = p.float("power");
+        prim
+    }
+}
+
+fn unsupported(type_name: &str, reason: &str) -> MaterialType {
+    MaterialType::Unsupported { type_name: type_name.to_owned(), reason: reason.to_owned() }
+}
+
+/// Reads a mesh from its parameters, and whether it had normals
+fn triangle_mesh(p: &Params) -> Result<(TriangleMesh, bool), Box<dyn Error + Send + Sync>> {
+    let positions = p.numbers("P").ok_or("Triangle mesh has no positions")?;
+    let vertex_count = positions.len() / 3;
+    let indices: Vec<u32> = match p.integers("indices").or_else(|| p.integers("vertexindices")) {
+        Some(indices) => indices.iter()
+            .map(|&i| u32::try_from(i).map_err(|_| format!("Triangle mesh index {} is out of range", i)))
+            .collect::<Result<_, _>>()?,
+        None if vertex_count == 3 => vec![0, 1, 2],
+        None => return Err("Triangle mesh has no indices".into()),
+    };
+    if let Some(&i) = indices.iter().find(|&&i| i as usize >= vertex_count) {
+        return Err(format!("Triangle mesh index {} is out of range of its {} vertices", i, vertex_count).into());
+    }
+    let normals = p.numbers("N").filter(|n| n.len() == positions.len());
+    let uvs = p.numbers("uv").or_else(|| p.numbers("st")).filter(|uv| uv.len() == vertex_count * 2);
+
+    let verts = (0..vertex_count).map(|i| Vertex {
+        pos: [positions[i*3], positions[i*3 + 1], positions[i*3 + 2]],
+        normal: normals.as_ref().map_or([0.0; 3], |n| [n[i*3], n[i*3 + 1], n[i*3 + 2]]),
+        uv: uvs.as_ref().map_or([0.0; 2], |uv| [uv[i*2], uv[i*2 + 1]]),
+        ..Default::default()
+    }).collect();
+    let tris = indices.chunks_exact(3).map(|t| [t[0], t[1], t[2], 0]).collect();
+    let mut mesh = TriangleMesh { verts, tris, ..Default::default() };
+    if normals.is_none() {
+        mesh.compute_normals();
+    }
+    Ok((mesh, normals.is_some()))
+}
+
+fn string_arg(args: &[Token], index: usize) -> Result<&str, Box<dyn Error + Send + Sync>> {
+    match args.get(index) {
+        Some(Token::Str(s)) => Ok(s),
+        _ => Err(format!("Expected a string as argument {}", index + 1).into()),
+    }
+}
+
+/// A fixed number of numbers, which may be in brackets
+fn fixed_numbers<const N: usize>(args: &[Token]) -> Result<[f32; N], Box<dyn Error + Send + Sync>> {
+    let numbers = args.iter()
+        .filter(|t| !matches!(t, Token::Open | Token::Close))
+        .map(|t| match t {
+            Token::Word(w) => w.parse().map_err(|_| format!("Invalid number {}", w)),
+            t => Err(format!("Expected a number, found {:?}", t)),
+        })
+        .collect::<Result<Vec<f32>, _>>()?;
+    numbers.try_into().map_err(|n: Vec<f32>| format!("Expected {} numbers, found {}", N, n.len()).into())
+}
+
+/// Reads the parameters after the first few arguments
+fn params(args: &[Token], skip: usize) -> Result<Params, Box<dyn Error + Send + Sync>> {
+    let mut params = Vec::new();
+    let mut tokens = args.iter().skip(skip);
+    while let Some(token) = tokens.next() {
+        let declaration = match token {
+            Token::Str(s) => s,
+            t => return Err(format!("Expected a parameter, found {:?}", t).into()),
+        };
+        let (param_type, name) = match declaration.split_whitespace().collect::<Vec<_>>()[..] {
+            [param_type, name] => (param_type.to_owned(), name.to_owned()),
+            _ => return Err(format!("Invalid parameter declaration {:?}", declaration).into()),
+        };
+        let mut values = Vec::new();
+        let mut push = |t: &Token| -> Result<(), Box<dyn Error + Send + Sync>> {
+            values.push(match t {
+                Token::Str(s) => Value::Str(s.clone()),
+                Token::Word(w) if w == "true" => Value::Bool(true),
+                Token::Word(w) if w == "false" => Value::Bool(false),
+                Token::Word(w) if param_type == "integer" => {
+                    Value::Integer(w.parse().map_err(|_| format!("Invalid integer {} for {}", w, name))?)
+                },
+                Token::Word(w) => Value::Number(w.parse().map_err(|_| format!("Invalid value {} for {}", w, name))?),
+                t => return Err(format!("Unexpected {:?} in {}", t, name).into()),
+            });
+            Ok(())
+        };
+        match tokens.next() {
+            Some(Token::Open) => loop {
+                match tokens.next() {
+                    Some(Token::Close) => break,
+                    Some(t) => push(t)?,
+                    None => return Err(format!("Values of {} have no closing bracket", name).into()),
+                }
+            },
+            Some(t) => push(t)?,
+            None => return Err(format!("Parameter {} has no value", name).into()),
+        }
+        params.push(Param { param_type, name, values });
+    }
+    Ok(Params(params))
+}
+
+#[test]
+fn test_pbrt_scene() {
+    let dir = std::env::temp_dir().join("phosphor_test_pbrt");
+    std::fs::create_dir_all(&dir).unwrap();
+    let scene = r#"# A Cornell-ish box
+LookAt 0 1 4  0 1 0  0 1 0
+Camera "perspective" "float fov" [ 40 ]
+Film "rgb" "integer xresolution" 320 "integer yresolution" [240]
+Sampler "halton" "integer pixelsamples" 32
+Integrator "volpath" "integer maxdepth" [ 6 ]
+WorldBegin
+MakeNamedMaterial "white" "string type" "diffuse" "rgb reflectance" [ 0.8 0.8 0.8 ]
+AttributeBegin
+    AreaLightSource "diffuse" "blackbody L" [ 6500 ] "float scale" 10
+    Translate 0 2 0
+    Shape "trianglemesh" "point3 P" [ -1 0 -1  1 0 -1  1 0 1  -1 0 1 ]
+        "integer indices" [ 0 1 2  0 2 3 ]
+AttributeEnd
+NamedMaterial "white"
+Include "sphere.pbrt"
+MakeNamedMedium "fog" "string type" "homogeneous"
+Shape "curve" "point3 P" [ 0 0 0 1 1 1 2 2 2 3 3 3 ]
+"#;
+    let sphere = "AttributeBegin\n  Material \"dielectric\" \"float eta\" 1.33\n  Translate 1 0 0\n  Shape \"sphere\" \"float radius\" 0.5\nAttributeEnd\n";
+    std::fs::write(dir.join("scene.pbrt"), scene).unwrap();
+    std::fs::write(dir.join("sphere.pbrt"), sphere).unwrap();
+    let result = load_pbrt_scene(&dir.join("scene.pbrt"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    let scene = result.unwrap();
+
+    assert_eq!(scene.camera.resolution, [320, 240]);
+    assert_eq!(scene.camera.fov, 40.0);
+    assert_eq!(scene.renderer.spp, 32);
+    assert_eq!(scene.integrator.max_bounces, 6);
+    match scene.camera.transform {
+        CameraTransform::Matrix(m) => {
+            let m = transpose(&m);
+            let p = transform_point(&m, [0.0; 3]);
+            assert!((p[0] - 0.0).abs() < 1e-5 && (p[1] - 1.0).abs() < 1e-5 && (p[2] - 4.0).abs() < 1e-5);
+            let forward = transform_vector(&m, [0.0, 0.0, 1.0]);
+            assert!((forward[2] + 1.0).abs() < 1e-5);
+        },
+        t => panic!("Expected a matrix, got {:?}", t),
+    }
+
+    assert_eq!(scene.primitives.len(), 3);
+    let light = &scene.primitives[0];
+    assert_eq!(light.emission.constant(), Some([10.0; 3]));
+    assert_eq!(light.bsdf.name(), Some(DEFAULT_BSDF));
+    match &light.primitive {
+        PrimitiveType::Mesh { mesh_data, .. } => assert!(mesh_data.verts.iter().all(|v| v.pos[1] == 2.0)),
+        p => panic!("Expected a mesh, got {:?}", p),
+    }
+
+    // The sphere is mirrored along with the rest of the world
+    let sphere = &scene.primitives[1];
+    assert!(matches!(sphere.primitive, PrimitiveType::Sphere {}));
+    assert_eq!(sphere.transform.position, [-1.0, 0.0, 0.0]);
+    assert_eq!(sphere.transform.scale, [0.5; 3]);
+    assert_eq!(sphere.emission.constant(), Some([0.0; 3]));
+    let glass = scene.bsdfs.iter().find(|b| sphere.bsdf.name() == Some(&b.name)).unwrap();
+    assert!(matches!(glass.bsdf, MaterialType::Dielectric { ior } if ior == 1.33));
+    let white = scene.bsdfs.iter().find(|b| b.name == "white").unwrap();
+    assert_eq!(white.albedo.constant(), Some([0.8; 3]));
+
+    assert!(matches!(&scene.primitives[2].primitive, PrimitiveType::Unsupported { type_name, .. } if type_name == "curve"));
+    assert_eq!(scene.warnings, vec!["Unsupported directive MakeNamedMedium on line 17 of scene.pbrt"]);
+}
+
+#[test]
+fn test_integer_params() {
+    // 2^24 + 1 isn't representable as an f32
+    let parse = |text: &str| {
+        let tokens: Vec<Token> = tokenize(text).unwrap().into_iter().map(|(t, _)| t).collect();
+        params(&tokens, 0)
+    };
+    let p = parse(r#""integer indices" [ 16777217 0 1 ] "float radius" 2"#).unwrap();
+    assert_eq!(p.integers("indices"), Some(vec![16777217, 0, 1]));
+    assert_eq!(p.float("radius"), Some(2.0));
+    assert!(parse(r#""integer indices" [ 1.5 ]"#).is_err());
+}
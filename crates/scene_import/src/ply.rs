@@ -0,0 +1,325 @@
+use std::error::Error;
+
+use crate::tungsten_scene::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(match name {
+            "char" | "int8" => ScalarType::I8,
+            "uchar" | "uint8" => ScalarType::U8,
+            "short" | "int16" => ScalarType::I16,
+            "ushort" | "uint16" => ScalarType::U16,
+            "int" | "int32" => ScalarType::I32,
+            "uint" | "uint32" => ScalarType::U32,
+            "float" | "float32" => ScalarType::F32,
+            "double" | "float64" => ScalarType::F64,
+            t => return Err(format!("Unknown PLY property type {}", t).into()),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::I8 | ScalarType::U8 => 1,
+            ScalarType::I16 | ScalarType::U16 => 2,
+            ScalarType::I32 | ScalarType::U32 | ScalarType::F32 => 4,
+            ScalarType::F64 => 8,
+        }
+    }
+
+    /// The largest value of integer types, which colours are scaled by
+    fn max(self) -> f64 {
+        match self {
+            ScalarType::I8 => i8::MAX as f64,
+            ScalarType::U8 => u8::MAX as f64,
+            ScalarType::I16 => i16::MAX as f64,
+            ScalarType::U16 => u16::MAX as f64,
+            ScalarType::I32 => i32::MAX as f64,
+            ScalarType::U32 => u32::MAX as f64,
+            ScalarType::F32 | ScalarType::F64 => 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PropertyType {
+    Scalar(ScalarType),
+    /// The type of the length and the type of the items
+    List(ScalarType, ScalarType),
+}
+
+#[derive(Debug)]
+struct Property {
+    name: String,
+    property_type: PropertyType,
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Reads values from the body of the file
+struct Reader<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, scalar_type: ScalarType) -> Result<f64, Box<dyn Error + Send + Sync>> {
+        if self.format == Format::Ascii {
+            let start = self.offset + self.bytes[self.offset..].iter().take_while(|c| c.is_ascii_whitespace()).count();
+            let end = start + self.bytes[start..].iter().take_while(|c| !c.is_ascii_whitespace()).count();
+            self.offset = end;
+            let token = std::str::from_utf8(&self.bytes[start..end])?;
+            if token.is_empty() {
+                return Err("PLY file ends early".into());
+            }
+            return Ok(token.parse()?);
+        }
+
+        let size = scalar_type.size();
+        let mut b = [0; 8];
+        b[..size].copy_from_slice(self.bytes.get(self.offset..self.offset + size).ok_or("PLY file ends early")?);
+        self.offset += size;
+        if self.format == Format::BinaryBigEndian {
+            b[..size].reverse();
+        }
+        Ok(match scalar_type {
+            ScalarType::I8 => b[0] as i8 as f64,
+            ScalarType::U8 => b[0] as f64,
+            ScalarType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            ScalarType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            ScalarType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            ScalarType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            ScalarType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            ScalarType::F64 => f64::from_le_bytes(b),
+        })
+    }
+}
+
+/// Reads a mesh from a PLY file in any of its formats. Vertices can have positions, normals,
+///  texture coordinates and colours. Polygons are split into fans of triangles
+pub(crate) fn parse_ply(bytes: &[u8]) -> Result<TriangleMesh, Box<dyn Error + Send + Sync>> {
+    let (format, elements, body) = parse_header(bytes)?;
+    let mut reader = Reader { format, bytes: body, offset: 0 };
+
+    let mut mesh = TriangleMesh::default();
+    let mut has_normals = false;
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let find = |names: &[&str]| element.properties.iter().position(|p| names.contains(&p.name.as_str()));
+                let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+                let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+                let uv = [find(&["u", "s", "texture_u", "texture_s"]), find(&["v", "t", "texture_v", "texture_t"])];
+                let colour = [find(&["red", "r"]), find(&["green", "g"]), find(&["blue", "b"])];
+                if position.iter().any(|p| p.is_none()) {
+                    return Err("PLY vertices have no positions".into());
+                }
+                has_normals = normal.iter().all(|n| n.is_some());
+                let has_colours = colour.iter().all(|c| c.is_some());
+                // Integer colours go up to the largest value of their type
+                let colour_max = colour.map(|c| match c.map(|i| &element.properties[i].property_type) {
+                    Some(PropertyType::Scalar(t)) => t.max() as f32,
+                    _ => 1.0,
+                });
+
+                let mut values = vec![0.0; element.properties.len()];
+                for _ in 0..element.count {
+                    for (value, property) in values.iter_mut().zip(&element.properties) {
+                        *value = match property.property_type {
+                            PropertyType::Scalar(t) => reader.read(t)?,
+                            PropertyType::List(..) => {
+                                skip_property(&mut reader, property)?;
+                                0.0
+                            },
+                        };
+                    }
+                    let get = |index: Option<usize>| index.map_or(0.0, |i| values[i] as f32);
+                    mesh.verts.push(Vertex {
+                        pos: position.map(get),
+                        normal: normal.map(get),
+                        uv: uv.map(get),
+                        ..Default::default()
+                    });
+                    if has_colours {
+                        mesh.colours.push([0, 1, 2].map(|i| get(colour[i]) / colour_max[i]));
+                    }
+                }
+            },
+            "face" => {
+                let indices = element.properties.iter()
+                    .position(|p| p.name == "vertex_indices" || p.name == "vertex_index");
+                for _ in 0..element.count {
+                    for (i, property) in element.properties.iter().enumerate() {
+                        match property.property_type {
+                            PropertyType::List(count_type, item_type) if Some(i) == indices => {
+                                let count = reader.read(count_type)? as usize;
+                                let mut face = Vec::with_capacity(count);
+                                for _ in 0..count {
+                                    face.push(reader.read(item_type)? as u32);
+                                }
+                                for k in 2..face.len() {
+                                    mesh.tris.push([face[0], face[k - 1], face[k], 0]);
+                                }
+                            },
+                            _ => skip_property(&mut reader, property)?,
+                        }
+                    }
+                }
+            },
+            _ => {
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        skip_property(&mut reader, property)?;
+                    }
+                }
+            },
+        }
+    }
+
+    let vertex_count = mesh.verts.len();
+    if let Some(&i) = mesh.tris.iter().flat_map(|t| &t[..3]).find(|&&i| i as usize >= vertex_count) {
+        return Err(format!("PLY face index {} is out of range of its {} vertices", i, vertex_count).into());
+    }
+    if !has_normals {
+        mesh.compute_normals();
+    }
+    Ok(mesh)
+}
+
+fn skip_property(reader: &mut Reader, property: &Property) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match property.property_type {
+        PropertyType::Scalar(t) => {
+            reader.read(t)?;
+        },
+        PropertyType::List(count_type, item_type) => {
+            let count = reader.read(count_type)? as usize;
+            for _ in 0..count {
+                reader.read(item_type)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// The format, the elements and the rest of the file after the header
+type Header<'a> = (Format, Vec<Element>, &'a [u8]);
+
+fn parse_header(bytes: &[u8]) -> Result<Header<'_>, Box<dyn Error + Send + Sync>> {
+    const END: &[u8] = b"end_header";
+    let end = bytes.windows(END.len()).position(|w| w == END).ok_or("PLY file has no end_header")?;
+    // The body starts after the line ending of end_header
+    let mut body = end + END.len();
+    if bytes.get(body) == Some(&b'\r') {
+        body += 1;
+    }
+    if bytes.get(body) == Some(&b'\n') {
+        body += 1;
+    }
+
+    let header = std::str::from_utf8(&bytes[..end])?;
+    let mut lines = header.lines();
+    if lines.next().map(str::trim) != Some("ply") {
+        return Err("Not a PLY file".into());
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["format", f, _version] => format = Some(match f {
+                "ascii" => Format::Ascii,
+                "binary_little_endian" => Format::BinaryLittleEndian,
+                "binary_big_endian" => Format::BinaryBigEndian,
+                f => return Err(format!("Unknown PLY format {}", f).into()),
+            }),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_owned(),
+                count: count.parse()?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_type, item_type, name] => {
+                let element = elements.last_mut().ok_or("PLY property outside of an element")?;
+                element.properties.push(Property {
+                    name: name.to_owned(),
+                    property_type: PropertyType::List(ScalarType::parse(count_type)?, ScalarType::parse(item_type)?),
+                });
+            },
+            ["property", scalar_type, name] => {
+                let element = elements.last_mut().ok_or("PLY property outside of an element")?;
+                element.properties.push(Property {
+                    name: name.to_owned(),
+                    property_type: PropertyType::Scalar(ScalarType::parse(scalar_type)?),
+                });
+            },
+            _ => {},
+        }
+    }
+    let format = format.ok_or("PLY file has no format")?;
+    Ok((format, elements, &bytes[body..]))
+}
+
+#[test]
+fn test_ply_formats() {
+    // A unit square split into two triangles, in the xy plane
+    let header = |format: &str| format!("ply\nformat {} 1.0\ncomment made by hand\n\
+        element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+        property uchar red\nproperty uchar green\nproperty uchar blue\n\
+        element face 1\nproperty list uchar int vertex_indices\nproperty uchar flags\nend_header\n", format);
+    let positions = [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+
+    let mut ascii = header("ascii");
+    for p in &positions {
+        ascii += &format!("{} {} {} 255 0 51\n", p[0], p[1], p[2]);
+    }
+    ascii += "4 0 1 2 3 7\n";
+
+    let binary = |big_endian: bool| {
+        let mut bytes = header(if big_endian { "binary_big_endian" } else { "binary_little_endian" }).into_bytes();
+        for p in &positions {
+            for c in p {
+                bytes.extend(if big_endian { c.to_be_bytes() } else { c.to_le_bytes() });
+            }
+            bytes.extend([255, 0, 51]);
+        }
+        bytes.push(4);
+        for i in 0..4i32 {
+            bytes.extend(if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+        }
+        bytes.push(7);
+        bytes
+    };
+
+    for bytes in [ascii.into_bytes(), binary(false), binary(true)] {
+        let mesh = parse_ply(&bytes).unwrap();
+        let pos: Vec<[f32; 3]> = mesh.verts.iter().map(|v| v.pos).collect();
+        assert_eq!(pos, positions);
+        assert_eq!(mesh.tris, vec![[0, 1, 2, 0], [0, 2, 3, 0]]);
+        assert!(mesh.verts.iter().all(|v| v.normal == [0.0, 0.0, 1.0]));
+        assert!(mesh.colours.iter().all(|&c| c == [1.0, 0.0, 0.2]));
+        assert_eq!(mesh.colours.len(), 4);
+    }
+}
@@ -1,49 +1,524 @@
-use serde::{Deserialize, Deserializer};
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::path::PathBuf;
 
-#[derive(Deserialize)]
+use crate::error::SceneImportError;
+
+#[derive(Serialize, Deserialize)]
 pub struct SceneDescription {
+    #[serde(default)]
     pub media: Vec<Medium>,
+    #[serde(default)]
     pub bsdfs: Vec<Material>,
     pub primitives: Vec<Primitive>,
     pub camera: Camera,
     pub integrator: IntegratorSettings,
     pub renderer: RendererSettings,
+    /// Problems found while importing that didn't stop the scene loading, for the renderer to report
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+    /// Files the scene refers to that don't exist, with what refers to them. Those are left empty
+    #[serde(skip)]
+    pub missing_files: Vec<(PathBuf, String)>,
 }
 
 impl SceneDescription {
     pub fn resolution(&self) -> (u32, u32) {
         (self.camera.resolution[0], self.camera.resolution[1])
     }
+
+    /// Problems that don't stop the scene loading but change how it looks: references to BSDFs and
+    ///  media that don't exist, and features the renderer doesn't support
+    pub fn problems(&self) -> Vec<SceneImportError> {
+        let mut problems: Vec<SceneImportError> = self.missing_files.iter()
+            .map(|(path, referenced_by)| SceneImportError::MissingFile { path: path.clone(), referenced_by: referenced_by.clone() })
+            .collect();
+        let unknown_bsdf = |bsdf: &BsdfRef, referenced_by: &str| match bsdf {
+            BsdfRef::Name(name) if !self.bsdfs.iter().any(|b| &b.name == name) => {
+                Some(SceneImportError::UnknownBsdf { name: name.clone(), referenced_by: referenced_by.to_owned() })
+            },
+            BsdfRef::Resolved { index, name } if *index >= self.bsdfs.len() => {
+                Some(SceneImportError::UnknownBsdf { name: name.clone(), referenced_by: referenced_by.to_owned() })
+            },
+            _ => None,
+        };
+        let unknown_medium = |name: &Option<String>, referenced_by: &str| match name {
+            Some(name) if !self.media.iter().any(|m| &m.name == name) => {
+                Some(SceneImportError::UnknownMedium { name: name.clone(), referenced_by: referenced_by.to_owned() })
+            },
+            _ => None,
+        };
+        let unsupported_texture = |texture: &Texture, referenced_by: &str| match texture {
+            Texture::Unsupported { type_name } => Some(SceneImportError::Unsupported {
+                feature: format!("texture type {:?}", type_name),
+                reason: format!("{} uses it", referenced_by),
+            }),
+            _ => None,
+        };
+
+        for (i, bsdf) in self.bsdfs.iter().enumerate() {
+            let referenced_by = format!("BSDF {}", bsdf.name);
+            if self.bsdfs[..i].iter().any(|b| b.name == bsdf.name) && !bsdf.name.is_empty() {
+                problems.push(SceneImportError::DuplicateBsdf { name: bsdf.name.clone() });
+            }
+            match &bsdf.bsdf {
+                MaterialType::Mixed { bsdf0, bsdf1, .. } => {
+                    problems.extend(unknown_bsdf(bsdf0, &referenced_by));
+                    problems.extend(unknown_bsdf(bsdf1, &referenced_by));
+                },
+                MaterialType::Transparency { base, alpha } => {
+                    problems.extend(unknown_bsdf(base, &referenced_by));
+                    problems.extend(unsupported_texture(alpha, &referenced_by));
+                },
+                MaterialType::Unsupported { type_name, reason } => problems.push(SceneImportError::Unsupported {
+                    feature: format!("BSDF type {:?} in {}", type_name, referenced_by),
+                    reason: reason.clone(),
+                }),
+                _ => {},
+            }
+            problems.extend(unsupported_texture(&bsdf.albedo, &referenced_by));
+        }
+        for medium in &self.media {
+            if let MediumType::Unsupported { type_name, reason } = &medium.medium {
+                problems.push(SceneImportError::Unsupported {
+                    feature: format!("medium type {:?} in medium {}", type_name, medium.name),
+                    reason: reason.clone(),
+                });
+            }
+        }
+        for (i, prim) in self.primitives.iter().enumerate() {
+            let referenced_by = format!("Primitive {}", i);
+            if prim.primitive.needs_bsdf() {
+                problems.extend(unknown_bsdf(&prim.bsdf, &referenced_by));
+            }
+            problems.extend(unknown_medium(&prim.int_medium, &referenced_by));
+            problems.extend(unknown_medium(&prim.ext_medium, &referenced_by));
+            problems.extend(unsupported_texture(&prim.emission, &referenced_by));
+            if let PrimitiveType::Unsupported { type_name, reason } = &prim.primitive {
+                problems.push(SceneImportError::Unsupported {
+                    feature: format!("primitive type {:?} in {}", type_name, referenced_by),
+                    reason: reason.clone(),
+                });
+            }
+        }
+        problems.extend(unknown_medium(&self.camera.medium, "The camera"));
+        problems
+    }
+
+    /// Moves BSDFs defined inline into `bsdfs` and resolves the names BSDFs are referred to by to
+    ///  indices into it. A name defined more than once refers to its first definition, and names that
+    ///  aren't defined are left for `problems` to report
+    pub fn resolve_bsdfs(&mut self) {
+        for (i, prim) in self.primitives.iter_mut().enumerate() {
+            if let Some(material) = take_inline_bsdf(&mut prim.bsdf, self.bsdfs.len(), format!("Primitive {}", i)) {
+                self.bsdfs.push(material);
+            }
+        }
+        // BSDFs taken from inside other BSDFs are added to the end, so this also reaches them
+        let mut i = 0;
+        while i < self.bsdfs.len() {
+            let mut next = self.bsdfs.len();
+            let mut taken = Vec::new();
+            let name = self.bsdfs[i].name.clone();
+            for (j, reference) in self.bsdfs[i].bsdf.references_mut().into_iter().enumerate() {
+                if let Some(material) = take_inline_bsdf(reference, next, format!("{} {}", name, j)) {
+                    taken.push(material);
+                    next += 1;
+                }
+            }
+            self.bsdfs.extend(taken);
+            i += 1;
+        }
+
+        let mut indices = HashMap::new();
+        for (i, bsdf) in self.bsdfs.iter().enumerate() {
+            indices.entry(bsdf.name.clone()).or_insert(i);
+        }
+        let resolve = |reference: &mut BsdfRef| {
+            if let BsdfRef::Name(name) = reference {
+                if let Some(&index) = indices.get(name) {
+                    *reference = BsdfRef::Resolved { index, name: std::mem::take(name) };
+                }
+            }
+        };
+        self.primitives.iter_mut().for_each(|prim| resolve(&mut prim.bsdf));
+        for bsdf in &mut self.bsdfs {
+            bsdf.bsdf.references_mut().into_iter().for_each(&resolve);
+        }
+    }
 }
 
-#[derive(Deserialize)]
+/// Replaces an inline BSDF with a reference to the index it will be moved to, and returns it.
+///  BSDFs without a name are given the default one
+fn take_inline_bsdf(reference: &mut BsdfRef, index: usize, default_name: String) -> Option<Material> {
+    match std::mem::take(reference) {
+        BsdfRef::Inline(mut material) => {
+            if material.name.is_empty() {
+                material.name = default_name;
+            }
+            *reference = BsdfRef::Resolved { index, name: material.name.clone() };
+            Some(*material)
+        },
+        other => {
+            *reference = other;
+            None
+        },
+    }
+}
+
+/// How a primitive or another BSDF refers to a BSDF. Loading a scene resolves names and inline
+///  definitions to indices into `SceneDescription::bsdfs`, and resolved BSDFs are written by name
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum BsdfRef {
+    Name(String),
+    /// A BSDF defined where it is used
+    Inline(Box<Material>),
+    #[serde(skip)]
+    Resolved {
+        index: usize,
+        name: String,
+    },
+}
+
+impl BsdfRef {
+    /// The name of the BSDF, unless it is defined inline
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            BsdfRef::Name(name) | BsdfRef::Resolved { name, .. } => Some(name),
+            BsdfRef::Inline(_) => None,
+        }
+    }
+
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            BsdfRef::Resolved { index, .. } => Some(*index),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for BsdfRef {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer {
+        match self {
+            BsdfRef::Name(name) | BsdfRef::Resolved { name, .. } => serializer.serialize_str(name),
+            BsdfRef::Inline(material) => material.serialize(serializer),
+        }
+    }
+}
+
+impl Default for BsdfRef {
+    fn default() -> Self {
+        BsdfRef::Name(String::new())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Material {
+    /// Empty for BSDFs defined inline
+    #[serde(default)]
     pub name: String,
 
     #[serde(flatten)]
+    #[serde(deserialize_with = "tolerant")]
     pub bsdf: MaterialType,
 
-    #[serde(deserialize_with = "vector_or_scalar")]
-    pub albedo: [f32; 3],
+    #[serde(default = "texture_one")]
+    pub albedo: Texture,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum MaterialType {
     Null,
     Lambert {},
+    Mirror {},
+    /// A smooth metal
+    Conductor {
+        /// Name of the metal, which is used if eta and k aren't given
+        #[serde(default = "default_metal")]
+        material: String,
+        #[serde(default)]
+        #[serde(deserialize_with = "optional_vector_or_scalar")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        eta: Option<[f32; 3]>,
+        #[serde(default)]
+        #[serde(deserialize_with = "optional_vector_or_scalar")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        k: Option<[f32; 3]>,
+    },
     RoughConductor {
+        #[serde(default = "default_roughness")]
         roughness: f32,
+        #[serde(default = "default_metal")]
         material: String,
+        #[serde(default)]
+        #[serde(deserialize_with = "optional_vector_or_scalar")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        eta: Option<[f32; 3]>,
+        #[serde(default)]
+        #[serde(deserialize_with = "optional_vector_or_scalar")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        k: Option<[f32; 3]>,
+        #[serde(default = "default_distribution")]
+        distribution: String,
+    },
+    /// Smooth glass
+    Dielectric {
+        #[serde(default = "default_ior")]
+        ior: f32,
+    },
+    RoughDielectric {
+        #[serde(default = "default_ior")]
+        ior: f32,
+        #[serde(default = "default_roughness")]
+        roughness: f32,
+        #[serde(default = "default_distribution")]
         distribution: String,
     },
+    /// A diffuse base under a smooth dielectric coat
+    Plastic {
+        #[serde(default = "default_ior")]
+        ior: f32,
+    },
+    RoughPlastic {
+        #[serde(default = "default_ior")]
+        ior: f32,
+        #[serde(default = "default_roughness")]
+        roughness: f32,
+        #[serde(default = "default_distribution")]
+        distribution: String,
+    },
+    /// A blend of two other BSDFs
+    Mixed {
+        bsdf0: BsdfRef,
+        bsdf1: BsdfRef,
+        /// The weight of bsdf0
+        #[serde(default = "float_half")]
+        ratio: f32,
+    },
+    /// Another BSDF with parts cut away by an alpha texture
+    Transparency {
+        base: BsdfRef,
+        #[serde(default = "texture_one")]
+        alpha: Texture,
+    },
+    /// Light scatters inside the surface, which should be closed. The albedo is the colour after all the scattering
+    Subsurface {
+        /// Average distance light travels inside between scattering events
+        #[serde(deserialize_with = "vector_or_scalar")]
+        mean_free_path: [f32; 3],
+    },
+    /// A BSDF that couldn't be read, usually because its type isn't known
+    #[serde(skip)]
+    Unsupported {
+        type_name: String,
+        reason: String,
+    },
+}
+
+impl MaterialType {
+    /// The other BSDFs this one is made from
+    pub fn references_mut(&mut self) -> Vec<&mut BsdfRef> {
+        match self {
+            MaterialType::Mixed { bsdf0, bsdf1, .. } => vec![bsdf0, bsdf1],
+            MaterialType::Transparency { base, .. } => vec![base],
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn default_metal() -> String {
+    "Cu".to_owned()
+}
+
+pub(crate) fn default_distribution() -> String {
+    "ggx".to_owned()
+}
+
+fn default_roughness() -> f32 {
+    0.1
+}
+
+pub(crate) fn default_ior() -> f32 {
+    1.5
+}
+
+fn float_half() -> f32 {
+    0.5
+}
+
+/// Types that can stand in for values they failed to read, so that one unsupported item
+///  doesn't stop the rest of a scene loading
+pub trait Tolerant: Sized + for<'de> Deserialize<'de> {
+    fn unsupported(type_name: String, reason: String) -> Self;
+}
+
+impl Tolerant for MaterialType {
+    fn unsupported(type_name: String, reason: String) -> Self {
+        MaterialType::Unsupported { type_name, reason }
+    }
+}
+
+impl Tolerant for PrimitiveType {
+    fn unsupported(type_name: String, reason: String) -> Self {
+        PrimitiveType::Unsupported { type_name, reason }
+    }
+}
+
+impl Tolerant for MediumType {
+    fn unsupported(type_name: String, reason: String) -> Self {
+        MediumType::Unsupported { type_name, reason }
+    }
+}
+
+fn tolerant<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where D: Deserializer<'de>, T: Tolerant {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(T::deserialize(&value).unwrap_or_else(|e| {
+        let type_name = value.get("type").and_then(|t| t.as_str()).unwrap_or("").to_owned();
+        T::unsupported(type_name, e.to_string())
+    }))
+}
+
+/// A colour that can vary over a surface
+#[derive(Debug, Clone)]
+pub enum Texture {
+    Constant([f32; 3]),
+    /// An image, with a path relative to the scene file
+    Bitmap {
+        file: String,
+    },
+    Checker {
+        on_color: [f32; 3],
+        off_color: [f32; 3],
+        res_u: u32,
+        res_v: u32,
+    },
+    Unsupported {
+        type_name: String,
+    },
+}
+
+impl Texture {
+    /// The colour of a texture that is the same everywhere
+    pub fn constant(&self) -> Option<[f32; 3]> {
+        match self {
+            Texture::Constant(c) => Some(*c),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Texture {
+    fn default() -> Self {
+        Texture::Constant([0.0, 0.0, 0.0])
+    }
+}
+
+fn texture_one() -> Texture {
+    Texture::Constant(float3_one())
+}
+
+/// Textures can be written as a number, a colour, a path to an image or an object with a type
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TextureValue {
+    Scalar(f32),
+    Vector([f32; 3]),
+    Path(String),
+    Object(serde_json::Value),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum TextureObject {
+    Constant {
+        #[serde(deserialize_with = "vector_or_scalar")]
+        value: [f32; 3],
+    },
+    Bitmap {
+        file: String,
+    },
+    Checker {
+        #[serde(default = "float3_one")]
+        #[serde(deserialize_with = "vector_or_scalar")]
+        on_color: [f32; 3],
+        #[serde(default)]
+        #[serde(deserialize_with = "vector_or_scalar")]
+        off_color: [f32; 3],
+        #[serde(default = "default_checker_res")]
+        res_u: u32,
+        #[serde(default = "default_checker_res")]
+        res_v: u32,
+    },
+}
+
+fn default_checker_res() -> u32 {
+    20
+}
+
+impl<'de> Deserialize<'de> for Texture {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de> {
+        Ok(match TextureValue::deserialize(deserializer)? {
+            TextureValue::Scalar(s) => Texture::Constant([s, s, s]),
+            TextureValue::Vector(v) => Texture::Constant(v),
+            TextureValue::Path(file) => Texture::Bitmap { file },
+            TextureValue::Object(value) => match TextureObject::deserialize(&value) {
+                Ok(TextureObject::Constant { value }) => Texture::Constant(value),
+                Ok(TextureObject::Bitmap { file }) => Texture::Bitmap { file },
+                Ok(TextureObject::Checker { on_color, off_color, res_u, res_v }) => {
+                    Texture::Checker { on_color, off_color, res_u, res_v }
+                },
+                Err(_) => Texture::Unsupported {
+                    type_name: value.get("type").and_then(|t| t.as_str()).unwrap_or("").to_owned(),
+                },
+            },
+        })
+    }
+}
+
+impl Serialize for Texture {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer {
+        match self {
+            Texture::Constant([r, g, b]) if r == g && g == b => serializer.serialize_f32(*r),
+            Texture::Constant(c) => c.serialize(serializer),
+            Texture::Bitmap { file } => serializer.serialize_str(file),
+            Texture::Checker { on_color, off_color, res_u, res_v } => {
+                let checker = serde_json::json!({
+                    "type": "checker",
+                    "on_color": on_color,
+                    "off_color": off_color,
+                    "res_u": res_u,
+                    "res_v": res_v,
+                });
+                checker.serialize(serializer)
+            },
+            Texture::Unsupported { type_name } => {
+                Err(S::Error::custom(format!("texture type {:?} can't be written", type_name)))
+            },
+        }
+    }
 }
 
 fn float3_one() -> [f32; 3] {
     [1.0, 1.0, 1.0]
 }
 
+fn float_one() -> f32 {
+    1.0
+}
+
+fn bool_true() -> bool {
+    true
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum VectorOrScalar {
@@ -59,30 +534,95 @@ pub fn vector_or_scalar<'de, D>(deserializer: D) -> Result<[f32; 3], D::Error>
     }
 }
 
-#[derive(Deserialize)]
+fn optional_vector_or_scalar<'de, D>(deserializer: D) -> Result<Option<[f32; 3]>, D::Error>
+    where D: Deserializer<'de> {
+    vector_or_scalar(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Primitive {
     #[serde(flatten)]
+    #[serde(deserialize_with = "tolerant")]
     pub primitive: PrimitiveType,
 
+    #[serde(default)]
     pub transform: Transform,
+    /// Transforms at given times for moving primitives. If there are any they replace `transform`
     #[serde(default)]
-    pub bsdf: String,
+    pub keyframes: Vec<Keyframe>,
     #[serde(default)]
-    #[serde(deserialize_with = "vector_or_scalar")]
-    pub emission: [f32; 3],
+    pub bsdf: BsdfRef,
+    #[serde(default)]
+    pub emission: Texture,
+    /// Total power emitted, which overrides the emission of lights that support it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power: Option<f32>,
+    /// Name of the medium inside the primitive
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub int_medium: Option<String>,
+    /// Name of the medium outside the primitive
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ext_medium: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Default, Copy, Clone)]
+impl Primitive {
+    /// A primitive with no transform, BSDF or emission
+    pub fn new(primitive: PrimitiveType) -> Self {
+        Primitive {
+            primitive,
+            transform: Transform::default(),
+            keyframes: Vec::new(),
+            bsdf: BsdfRef::default(),
+            emission: Texture::default(),
+            power: None,
+            int_medium: None,
+            ext_medium: None,
+        }
+    }
+
+    /// A light infinitely far away in a direction, like the sun, giving the irradiance
+    pub fn distant_light(direction: [f32; 3], irradiance: [f32; 3]) -> Self {
+        // The cap is centred on the y axis of the primitive, which is rotated about x and then y to face the light
+        let len = direction.iter().map(|d| d * d).sum::<f32>().sqrt();
+        let [x, y, z] = direction.map(|d| d / len);
+        let cap_angle = DISTANT_LIGHT_ANGLE.to_radians();
+        let solid_angle = 2.0 * std::f32::consts::PI * (1.0 - cap_angle.cos());
+        let mut prim = Primitive::new(PrimitiveType::InfiniteSphereCap {
+            sample: true,
+            cap_angle: DISTANT_LIGHT_ANGLE,
+        });
+        prim.transform.rotation = [y.clamp(-1.0, 1.0).acos().to_degrees(), x.atan2(z).to_degrees(), 0.0];
+        prim.emission = Texture::Constant(irradiance.map(|e| e / solid_angle));
+        prim
+    }
+}
+
+/// Half angle of the cone distant lights are spread over (in degrees), about the size of the sun
+const DISTANT_LIGHT_ANGLE: f32 = 0.5;
+
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone)]
 pub struct Vertex {
     pub pos: [f32; 3],
     pub normal: [f32; 3],
     pub uv: [f32; 2],
+    /// Direction of increasing u, with the handedness of the bitangent in w. Zero if it isn't known
+    #[serde(skip)]
+    pub tangent: [f32; 4],
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TriangleMesh {
     pub verts: Vec<Vertex>,
     pub tris: Vec<[u32; 4]>,
+    /// A colour for each vertex, if the file had them
+    #[serde(skip)]
+    pub colours: Vec<[f32; 3]>,
+    /// Names of the materials the fourth index of each triangle refers to, if the file had them
+    #[serde(skip)]
+    pub materials: Vec<String>,
 }
 
 impl Default for TriangleMesh {
@@ -90,37 +630,220 @@ impl Default for TriangleMesh {
         TriangleMesh {
             verts: Vec::new(),
             tris: Vec::new(),
+            colours: Vec::new(),
+            materials: Vec::new(),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+impl TriangleMesh {
+    /// Replaces the vertex normals with the average of the normals of the triangles around them,
+    ///  weighted by area
+    pub fn compute_normals(&mut self) {
+        let mut normals = vec![[0.0f32; 3]; self.verts.len()];
+        for tri in &self.tris {
+            let [p0, p1, p2] = [0, 1, 2].map(|i| self.verts[tri[i] as usize].pos);
+            let e1 = [p1[0] - p0[0], p1[1] - p0[1], p1[2] - p0[2]];
+            let e2 = [p2[0] - p0[0], p2[1] - p0[1], p2[2] - p0[2]];
+            // The length of the cross product is twice the area
+            let n = [
+                e1[1] * e2[2] - e1[2] * e2[1],
+                e1[2] * e2[0] - e1[0] * e2[2],
+                e1[0] * e2[1] - e1[1] * e2[0],
+            ];
+            for &i in &tri[..3] {
+                for axis in 0..3 {
+                    normals[i as usize][axis] += n[axis];
+                }
+            }
+        }
+        for (vert, n) in self.verts.iter_mut().zip(normals) {
+            let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+            vert.normal = if len > 0.0 { n.map(|c| c / len) } else { [0.0; 3] };
+        }
+    }
+
+    /// Replaces the vertex tangents with the direction of increasing u averaged over the triangles
+    ///  around them, made perpendicular to the normal. Vertices without texture coordinates that vary get zero
+    pub fn compute_tangents(&mut self) {
+        let sub = |a: &[f32], b: &[f32]| [0, 1, 2].map(|i| a.get(i).copied().unwrap_or(0.0) - b.get(i).copied().unwrap_or(0.0));
+        let mut tangents = vec![[0.0f32; 3]; self.verts.len()];
+        let mut bitangents = vec![[0.0f32; 3]; self.verts.len()];
+        for tri in &self.tris {
+            let [v0, v1, v2] = [0, 1, 2].map(|i| &self.verts[tri[i] as usize]);
+            let (e1, e2) = (sub(&v1.pos, &v0.pos), sub(&v2.pos, &v0.pos));
+            let (d1, d2) = (sub(&v1.uv, &v0.uv), sub(&v2.uv, &v0.uv));
+            let det = d1[0] * d2[1] - d2[0] * d1[1];
+            if det == 0.0 {
+                continue;
+            }
+            // Solving e = dpdu * du + dpdv * dv for both edges
+            let t = [0, 1, 2].map(|i| (e1[i] * d2[1] - e2[i] * d1[1]) / det);
+            let b = [0, 1, 2].map(|i| (e2[i] * d1[0] - e1[i] * d2[0]) / det);
+            for &i in &tri[..3] {
+                for axis in 0..3 {
+                    tangents[i as usize][axis] += t[axis];
+                    bitangents[i as usize][axis] += b[axis];
+                }
+            }
+        }
+        for ((vert, t), b) in self.verts.iter_mut().zip(tangents).zip(bitangents) {
+            let n = vert.normal;
+            let n_dot_t = n[0] * t[0] + n[1] * t[1] + n[2] * t[2];
+            let t = [0, 1, 2].map(|i| t[i] - n[i] * n_dot_t);
+            let len = (t[0] * t[0] + t[1] * t[1] + t[2] * t[2]).sqrt();
+            if len == 0.0 || !len.is_finite() {
+                vert.tangent = [0.0; 4];
+                continue;
+            }
+            // The bitangent is n x t, or its opposite if the texture is mirrored
+            let n_cross_t = [
+                n[1] * t[2] - n[2] * t[1],
+                n[2] * t[0] - n[0] * t[2],
+                n[0] * t[1] - n[1] * t[0],
+            ];
+            let handedness = if n_cross_t[0] * b[0] + n_cross_t[1] * b[1] + n_cross_t[2] * b[2] < 0.0 { -1.0 } else { 1.0 };
+            vert.tangent = [t[0] / len, t[1] / len, t[2] / len, handedness];
+        }
+    }
+
+    /// Merges each vertex into an earlier one whose position, normal, texture coordinates and tangent
+    ///  are all within the tolerance distance of its own, or equal if it is zero, and drops the
+    ///  triangles that collapse
+    pub fn weld(&mut self, tolerance: f32) {
+        let is_near = |a: &[f32], b: &[f32]| {
+            a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>() <= tolerance * tolerance
+        };
+        let is_close = |a: &Vertex, b: &Vertex| {
+            is_near(&a.pos, &b.pos) && is_near(&a.normal, &b.normal) && is_near(&a.uv, &b.uv) && is_near(&a.tangent, &b.tangent)
+        };
+        // Kept vertices are bucketed by position in cells the size of the tolerance, so close ones
+        //  are in the same cell or a neighbouring one
+        let cell = |v: &Vertex| if tolerance > 0.0 {
+            v.pos.map(|p| (p / tolerance).floor() as i64)
+        } else {
+            v.pos.map(|p| (p + 0.0).to_bits() as i64)
+        };
+        let neighbours: &[i64] = if tolerance > 0.0 { &[-1, 0, 1] } else { &[0] };
+        let mut cells: HashMap<[i64; 3], Vec<u32>> = HashMap::new();
+        let mut verts: Vec<Vertex> = Vec::new();
+        let mut colours = Vec::new();
+        let remap: Vec<u32> = self.verts.iter().enumerate().map(|(i, vert)| {
+            let [x, y, z] = cell(vert);
+            let existing = neighbours.iter()
+                .flat_map(|&dx| neighbours.iter().flat_map(move |&dy| neighbours.iter().map(move |&dz| [x + dx, y + dy, z + dz])))
+                .filter_map(|key| cells.get(&key))
+                .flatten()
+                .copied()
+                .filter(|&j| is_close(&verts[j as usize], vert))
+                .min();
+            existing.unwrap_or_else(|| {
+                verts.push(*vert);
+                colours.extend(self.colours.get(i));
+                let index = verts.len() as u32 - 1;
+                cells.entry([x, y, z]).or_default().push(index);
+                index
+            })
+        }).collect();
+        self.verts = verts;
+        self.colours = colours;
+        self.tris = self.tris.iter()
+            .map(|t| [remap[t[0] as usize], remap[t[1] as usize], remap[t[2] as usize], t[3]])
+            .filter(|t| t[0] != t[1] && t[1] != t[2] && t[2] != t[0])
+            .collect();
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum PrimitiveType {
     Quad,
-    Sphere {
-        #[serde(default)]
-        power: f32,
-    },
+    Sphere {},
     Cube,
+    /// A disk with a diameter of one in the xz plane, facing up
+    Disk {},
     Mesh {
         file: String,
+        #[serde(default = "bool_true")]
         smooth: bool,
+        #[serde(default)]
         backface_culling: bool,
+        #[serde(default)]
         recompute_normals: bool,
-        #[serde(skip_deserializing)]
+        #[serde(skip)]
         mesh_data: TriangleMesh,
 
     },
+    /// Copies of one mesh placed with each of the transforms. The primitive's transform
+    ///  moves them all together
+    Instances {
+        file: String,
+        transforms: Vec<Transform>,
+        #[serde(skip)]
+        mesh_data: TriangleMesh,
+    },
+    /// Distant light from a cone of directions around the primitive's y axis, like the sun.
+    ///  Its power is the irradiance it gives, otherwise the emission is its radiance
     InfiniteSphereCap {
-        power: f32,
+        #[serde(default = "bool_true")]
         sample: bool,
         cap_angle: f32,
     },
+    /// Light arriving from every direction, given by the primitive's emission
+    InfiniteSphere {
+        #[serde(default = "bool_true")]
+        sample: bool,
+    },
+    /// A light with no size. Its emission is the radiant intensity
+    Point {},
+    /// A physically based sky
+    Skydome {
+        #[serde(default = "default_sky_temperature")]
+        temperature: f32,
+        #[serde(default = "float_one")]
+        gamma_scale: f32,
+        #[serde(default = "default_turbidity")]
+        turbidity: f32,
+        #[serde(default = "default_sky_intensity")]
+        intensity: f32,
+    },
+    /// Hair or fur, from a file of curves
+    Curves {
+        file: String,
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        curve_thickness: Option<f32>,
+    },
+    /// A primitive that couldn't be read, usually because its type isn't known
+    #[serde(skip)]
+    Unsupported {
+        type_name: String,
+        reason: String,
+    },
 }
 
-#[derive(Deserialize)]
+impl PrimitiveType {
+    /// Whether light scatters off the primitive, so that it needs a BSDF
+    pub fn needs_bsdf(&self) -> bool {
+        matches!(self, PrimitiveType::Quad | PrimitiveType::Sphere {} | PrimitiveType::Cube | PrimitiveType::Disk {}
+            | PrimitiveType::Mesh { .. } | PrimitiveType::Instances { .. } | PrimitiveType::Curves { .. })
+    }
+}
+
+fn default_sky_temperature() -> f32 {
+    5777.0
+}
+
+fn default_turbidity() -> f32 {
+    3.0
+}
+
+fn default_sky_intensity() -> f32 {
+    2.0
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Transform {
     #[serde(default)]
     pub position: [f32; 3],
@@ -132,6 +855,13 @@ pub struct Transform {
     pub rotation: [f32; 3],
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct Keyframe {
+    pub time: f32,
+    #[serde(flatten)]
+    pub transform: Transform,
+}
+
 impl Default for Transform {
     fn default() -> Self {
         Transform {
@@ -143,7 +873,7 @@ impl Default for Transform {
 }
 
 #[derive(Debug, Clone)]
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Camera {
     pub tonemap: String,
     pub resolution: [u32; 2],
@@ -151,19 +881,135 @@ pub struct Camera {
     pub transform: CameraTransform,
     /// Field of view (in degrees)
     pub fov: f32,
+    /// Which side of the image the field of view spans
+    #[serde(default)]
+    pub fov_axis: FovAxis,
     #[serde(rename = "type")]
     pub camera_type: String,
+    /// Radius of the lens aperture for thin lens cameras
+    #[serde(default = "default_aperture_size")]
+    pub aperture_size: f32,
+    /// Distance from the camera to the plane in focus
+    #[serde(default = "default_focus_distance")]
+    pub focus_distance: f32,
+    /// A point that should be in focus. Overrides focus_distance if given
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focus_pivot: Option<[f32; 3]>,
+    /// Number of aperture blades. Zero gives a circular aperture
+    #[serde(default)]
+    pub aperture_blades: u32,
+    /// Rotation of the aperture blades (in degrees)
+    #[serde(default)]
+    pub aperture_rotation: f32,
+    /// Strength of the cat's-eye vignetting towards the edges of the image
+    #[serde(default)]
+    pub cateye: f32,
+    /// Width of the view for orthographic cameras (in world units).
+    ///  Defaults to the width of the perspective view at the look at point
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ortho_width: Option<f32>,
+    /// Time the shutter opens, in the same units as keyframe times
+    #[serde(default)]
+    pub shutter_open: f32,
+    /// Time the shutter closes, in the same units as keyframe times
+    #[serde(default = "default_shutter_close")]
+    pub shutter_close: f32,
+    /// Name of the medium the camera is in
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+}
+
+impl Camera {
+    /// A pinhole camera with Tungsten's defaults
+    pub fn look_at(position: [f32; 3], look_at: [f32; 3], up: [f32; 3], fov: f32) -> Self {
+        Camera {
+            tonemap: "filmic".to_owned(),
+            resolution: [1280, 720],
+            reconstruction_filter: "tent".to_owned(),
+            transform: CameraTransform::LookAt { position, look_at, up },
+            fov,
+            fov_axis: FovAxis::default(),
+            camera_type: "pinhole".to_owned(),
+            aperture_size: default_aperture_size(),
+            focus_distance: default_focus_distance(),
+            focus_pivot: None,
+            aperture_blades: 0,
+            aperture_rotation: 0.0,
+            cateye: 0.0,
+            ortho_width: None,
+            shutter_open: 0.0,
+            shutter_close: default_shutter_close(),
+            medium: None,
+        }
+    }
+
+    /// Looks at the centre of the bounds from the front and slightly above, far enough away
+    ///  that their bounding sphere fits in the view
+    pub fn framing(bounds_min: [f32; 3], bounds_max: [f32; 3]) -> Self {
+        let centre = [0, 1, 2].map(|i| 0.5 * (bounds_min[i] + bounds_max[i]));
+        let radius = 0.5 * [0, 1, 2].iter()
+            .map(|&i| (bounds_max[i] - bounds_min[i]).powi(2))
+            .sum::<f32>().sqrt();
+        let radius = radius.max(1e-3);
+        let distance = radius / (0.5 * FRAMING_FOV.to_radians()).sin();
+
+        let dir = [0.0, 0.3, 1.0f32];
+        let len = dir.iter().map(|d| d * d).sum::<f32>().sqrt();
+        let position = [0, 1, 2].map(|i| centre[i] + dir[i] / len * distance);
+
+        let mut camera = Camera::look_at(position, centre, [0.0, 1.0, 0.0], FRAMING_FOV);
+        camera.fov_axis = FovAxis::Smaller;
+        camera.focus_distance = distance;
+        camera
+    }
+}
+
+/// Field of view of cameras fitted to a scene (in degrees)
+const FRAMING_FOV: f32 = 45.0;
+
+fn default_aperture_size() -> f32 {
+    0.001
+}
+
+fn default_focus_distance() -> f32 {
+    2.0
+}
+
+fn default_shutter_close() -> f32 {
+    1.0
 }
 
 #[derive(Debug, Clone)]
-#[derive(Deserialize)]
-pub struct CameraTransform {
-    pub position: [f32; 3],
-    pub look_at: [f32; 3],
-    pub up: [f32; 3],
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CameraTransform {
+    LookAt {
+        position: [f32; 3],
+        look_at: [f32; 3],
+        up: [f32; 3],
+    },
+    /// A row-major camera to world matrix. The camera looks along +z with +y up
+    Matrix([f32; 16]),
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FovAxis {
+    /// Tungsten's convention
+    #[default]
+    Horizontal,
+    Vertical,
+    /// Whichever of the width and height is smaller
+    Smaller,
+    /// Whichever of the width and height is larger
+    Larger,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct IntegratorSettings {
     #[serde(rename = "type")]
     pub integrator_type: String,
@@ -173,11 +1019,25 @@ pub struct IntegratorSettings {
     pub enable_two_sided_shading: bool,
     #[serde(default)]
     pub enable_light_sampling: bool,
-    #[serde(default)]
+    #[serde(default = "bool_true")]
     pub enable_volume_light_sampling: bool,
 }
 
-#[derive(Deserialize)]
+impl Default for IntegratorSettings {
+    fn default() -> Self {
+        IntegratorSettings {
+            integrator_type: "path_tracer".to_owned(),
+            min_bounces: 0,
+            max_bounces: 64,
+            enable_consistency_checks: false,
+            enable_two_sided_shading: true,
+            enable_light_sampling: true,
+            enable_volume_light_sampling: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct RendererSettings {
     pub output_file: String,
     pub resume_render_file: String,
@@ -193,6 +1053,168 @@ pub struct RendererSettings {
     pub hdr_output_file: String,
 }
 
-#[derive(Deserialize)]
+impl Default for RendererSettings {
+    fn default() -> Self {
+        RendererSettings {
+            output_file: "TungstenRender.png".to_owned(),
+            resume_render_file: "TungstenRenderState.dat".to_owned(),
+            overwrite_output_files: true,
+            adaptive_sampling: true,
+            enable_resume_render: false,
+            stratified_sampler: true,
+            scene_bvh: true,
+            spp: 64,
+            spp_step: 16,
+            checkpoint_interval: "0".to_owned(),
+            timeout: "0".to_owned(),
+            hdr_output_file: "TungstenRender.exr".to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Medium {
-}
\ No newline at end of file
+    pub name: String,
+
+    #[serde(flatten)]
+    #[serde(deserialize_with = "tolerant")]
+    pub medium: MediumType,
+
+    #[serde(default)]
+    pub phase_function: PhaseFunction,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum MediumType {
+    Homogeneous {
+        /// Absorption coefficient (per unit length)
+        #[serde(deserialize_with = "vector_or_scalar")]
+        sigma_a: [f32; 3],
+        /// Scattering coefficient (per unit length)
+        #[serde(deserialize_with = "vector_or_scalar")]
+        sigma_s: [f32; 3],
+        /// Scales both coefficients
+        #[serde(default = "float_one")]
+        density: f32,
+    },
+    /// A medium whose coefficients are scaled by the densities in a voxel grid
+    Voxel {
+        #[serde(deserialize_with = "vector_or_scalar")]
+        sigma_a: [f32; 3],
+        #[serde(deserialize_with = "vector_or_scalar")]
+        sigma_s: [f32; 3],
+        /// Scales the grid's densities
+        #[serde(default = "float_one")]
+        density: f32,
+        /// Path to the grid, relative to the scene file
+        file: String,
+        /// Places the grid's bounding box in the scene
+        #[serde(default)]
+        transform: Transform,
+        #[serde(skip)]
+        grid_data: DensityGrid,
+    },
+    /// A medium that couldn't be read, usually because its type isn't known
+    #[serde(skip)]
+    Unsupported {
+        type_name: String,
+        reason: String,
+    },
+}
+
+/// A dense grid of densities, with x varying fastest, then y, then z
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+    pub resolution: [u32; 3],
+    /// The box the grid fills before it is transformed
+    pub bounds_min: [f32; 3],
+    pub bounds_max: [f32; 3],
+    pub data: Vec<f32>,
+}
+
+impl Default for DensityGrid {
+    fn default() -> Self {
+        DensityGrid {
+            resolution: [0, 0, 0],
+            bounds_min: [0.0, 0.0, 0.0],
+            bounds_max: [1.0, 1.0, 1.0],
+            data: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum PhaseFunction {
+    #[default]
+    Isotropic,
+    HenyeyGreenstein {
+        /// Mean cosine of the scattering angle, in (-1, 1). Positive values scatter forwards
+        g: f32,
+    },
+}
+
+#[test]
+fn test_unsupported_items_are_kept() {
+    let bsdfs: Vec<Material> = serde_json::from_str(r#"[
+        { "name": "a", "type": "lambert", "albedo": 0.5 },
+        { "name": "b", "type": "thinsheet", "albedo": [0.1, 0.2, 0.3] },
+        { "name": "c", "type": "rough_conductor", "roughness": "nope" },
+        { "name": "d", "type": "lambert", "albedo": { "type": "checker", "on_color": 1, "off_color": 0 } },
+        { "name": "e", "type": "lambert", "albedo": "wood.png" }
+    ]"#).unwrap();
+    assert_eq!(bsdfs.len(), 5);
+    assert!(matches!(bsdfs[0].bsdf, MaterialType::Lambert {}));
+    assert_eq!(bsdfs[0].albedo.constant(), Some([0.5; 3]));
+    assert!(matches!(&bsdfs[1].bsdf, MaterialType::Unsupported { type_name, .. } if type_name == "thinsheet"));
+    assert!(matches!(&bsdfs[2].bsdf, MaterialType::Unsupported { type_name, .. } if type_name == "rough_conductor"));
+    assert!(matches!(bsdfs[3].albedo, Texture::Checker { .. }));
+    assert!(matches!(&bsdfs[4].albedo, Texture::Bitmap { file } if file == "wood.png"));
+}
+
+#[test]
+fn test_weld() {
+    let vert = |x: f32, y: f32| Vertex { pos: [x, y, 0.0], normal: [0.0, 0.0, 1.0], ..Default::default() };
+    // Two triangles of a square with their own copies of the diagonal, and a sliver that welding collapses
+    let mut mesh = TriangleMesh {
+        verts: vec![vert(0.0, 0.0), vert(1.0, 0.0), vert(1.0, 1.0), vert(0.0, 0.0), vert(1.0, 1.0), vert(0.0, 1.0),
+            vert(0.0, 1.0001)],
+        tris: vec![[0, 1, 2, 0], [3, 4, 5, 1], [2, 5, 6, 0]],
+        ..Default::default()
+    };
+    mesh.weld(0.0);
+    assert_eq!(mesh.verts.len(), 5);
+    assert_eq!(mesh.tris, vec![[0, 1, 2, 0], [0, 2, 3, 1], [2, 3, 4, 0]]);
+    mesh.weld(0.001);
+    assert_eq!(mesh.verts.len(), 4);
+    assert_eq!(mesh.tris, vec![[0, 1, 2, 0], [0, 2, 3, 1]]);
+
+    // Vertices either side of a multiple of the tolerance are still merged, and ones further apart aren't
+    let mut mesh = TriangleMesh {
+        verts: vec![vert(0.0999, 0.0), vert(0.1001, 0.0), vert(0.1025, 0.0)],
+        ..Default::default()
+    };
+    mesh.weld(0.001);
+    assert_eq!(mesh.verts.len(), 2);
+}
+
+#[test]
+fn test_tangents() {
+    let vert = |x: f32, y: f32, u: f32| Vertex { pos: [x, y, 0.0], normal: [0.0, 0.0, 1.0], uv: [u, y], ..Default::default() };
+    let mut mesh = TriangleMesh {
+        verts: vec![vert(0.0, 0.0, 0.0), vert(1.0, 0.0, 1.0), vert(0.0, 1.0, 0.0),
+            // A mirrored copy, with u decreasing along x
+            vert(0.0, 0.0, 0.0), vert(1.0, 0.0, -1.0), vert(0.0, 1.0, 0.0),
+            // Texture coordinates that don't vary
+            vert(0.0, 0.0, 0.0), vert(1.0, 0.0, 0.0), vert(1.0, 0.0, 0.0)],
+        tris: vec![[0, 1, 2, 0], [3, 4, 5, 0], [6, 7, 8, 0]],
+        ..Default::default()
+    };
+    mesh.compute_tangents();
+    assert_eq!(mesh.verts[0].tangent, [1.0, 0.0, 0.0, 1.0]);
+    assert_eq!(mesh.verts[4].tangent, [-1.0, 0.0, 0.0, -1.0]);
+    assert_eq!(mesh.verts[6].tangent, [0.0; 4]);
+}
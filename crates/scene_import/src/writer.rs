@@ -0,0 +1,158 @@
+use std::fs::{create_dir_all, write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::error::SceneImportError;
+use crate::tungsten_scene::*;
+
+/// Writes a scene as Tungsten JSON. Meshes are written as .wo3 files and voxel grids as .vol files in
+///  a directory named after the scene, and the scene is changed to refer to them. Image paths are kept,
+///  so they stay relative to wherever the scene was loaded from
+pub(crate) fn save_tungsten_scene(scene: &mut SceneDescription, path: &Path) -> Result<(), SceneImportError> {
+    for problem in scene.problems() {
+        if let SceneImportError::Unsupported { feature, .. } = problem {
+            return Err(SceneImportError::Unsupported { feature, reason: "it can't be written".to_owned() });
+        }
+    }
+
+    let base_path = path.parent().unwrap_or_else(|| Path::new(""));
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("scene");
+    let data_dir = format!("{}_data", stem);
+    for (i, prim) in scene.primitives.iter_mut().enumerate() {
+        if let PrimitiveType::Mesh { file, mesh_data, .. } | PrimitiveType::Instances { file, mesh_data, .. } = &mut prim.primitive {
+            *file = format!("{}/Mesh{:03}.wo3", data_dir, i);
+            save_wo3(mesh_data, &base_path.join(&*file))?;
+        }
+    }
+    for (i, medium) in scene.media.iter_mut().enumerate() {
+        if let MediumType::Voxel { file, grid_data, .. } = &mut medium.medium {
+            *file = format!("{}/Medium{:03}.vol", data_dir, i);
+            write_file(&base_path.join(&*file), &vol_bytes(grid_data))?;
+        }
+    }
+
+    // Indented like the scenes Tungsten writes
+    let mut json = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut json, formatter);
+    scene.serialize(&mut serializer).map_err(|e| SceneImportError::Unsupported {
+        feature: "scene".to_owned(),
+        reason: e.to_string(),
+    })?;
+    json.push(b'\n');
+    write_file(path, &json)
+}
+
+/// Bincode lays a mesh out like Tungsten's .wo3 files: the number of vertices as a u64, the vertices'
+///  positions, normals and texture coordinates, then the number of triangles and their indices.
+///  Tangents, if the mesh has any, come after that in the same way, where Tungsten doesn't look
+pub(crate) fn save_wo3(mesh: &TriangleMesh, path: &Path) -> Result<(), SceneImportError> {
+    let to_error = |e: bincode::Error| SceneImportError::Unsupported {
+        feature: "mesh".to_owned(),
+        reason: e.to_string(),
+    };
+    let mut bytes = bincode::serialize(mesh).map_err(to_error)?;
+    if mesh.verts.iter().any(|v| v.tangent != [0.0; 4]) {
+        let tangents: Vec<[f32; 4]> = mesh.verts.iter().map(|v| v.tangent).collect();
+        bytes.extend(bincode::serialize(&tangents).map_err(to_error)?);
+    }
+    write_file(path, &bytes)
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<(), SceneImportError> {
+    let io_error = |source| SceneImportError::Io { path: path.to_owned(), source };
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).map_err(io_error)?;
+    }
+    write(path, bytes).map_err(io_error)
+}
+
+/// A grid in Mitsuba's binary volume format, with one channel of floats
+fn vol_bytes(grid: &DensityGrid) -> Vec<u8> {
+    let mut bytes = b"VOL\x03".to_vec();
+    let [x, y, z] = grid.resolution;
+    for int in [1, x as i32, y as i32, z as i32, 1] {
+        bytes.extend_from_slice(&int.to_le_bytes());
+    }
+    for float in grid.bounds_min.iter().chain(&grid.bounds_max).chain(&grid.data) {
+        bytes.extend_from_slice(&float.to_le_bytes());
+    }
+    bytes
+}
+
+#[test]
+fn test_round_trip() {
+    let dir = std::env::temp_dir().join("phosphor_test_round_trip");
+    let scenes = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../scenes");
+    let mut count = 0;
+    for entry in std::fs::read_dir(scenes).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let saved = dir.join(path.file_name().unwrap());
+        let original = crate::load_scene(&path).unwrap();
+        let mut scene = crate::load_scene(&path).unwrap();
+        save_tungsten_scene(&mut scene, &saved).unwrap();
+        let text = std::fs::read_to_string(&saved).unwrap();
+
+        // What was saved loads as the original scene, apart from the files meshes are in
+        let mut reloaded = crate::load_scene(&saved).unwrap();
+        let value = |scene: &SceneDescription| {
+            let mut value = serde_json::to_value(scene).unwrap();
+            for prim in value["primitives"].as_array_mut().unwrap() {
+                prim.as_object_mut().unwrap().remove("file");
+            }
+            value
+        };
+        let (expected, actual) = (value(&original), value(&reloaded));
+        for field in ["bsdfs", "primitives", "camera", "integrator"] {
+            assert_eq!(actual[field], expected[field], "{} of {} changed", field, path.display());
+        }
+
+        // Saving what was saved gives the same files
+        save_tungsten_scene(&mut reloaded, &saved).unwrap();
+        assert_eq!(std::fs::read_to_string(&saved).unwrap(), text, "{} changed", path.display());
+        for (prim, reloaded_prim) in original.primitives.iter().zip(&reloaded.primitives) {
+            if let (PrimitiveType::Mesh { mesh_data, .. }, PrimitiveType::Mesh { mesh_data: reloaded_data, .. })
+                = (&prim.primitive, &reloaded_prim.primitive) {
+                assert!(!mesh_data.tris.is_empty());
+                assert_eq!(bincode::serialize(mesh_data).unwrap(), bincode::serialize(reloaded_data).unwrap());
+            }
+        }
+        count += 1;
+    }
+    assert!(count >= 4);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let grid = DensityGrid {
+        resolution: [2, 1, 1],
+        bounds_min: [0.0, -1.0, 0.0],
+        bounds_max: [1.0, 1.0, 2.0],
+        data: vec![0.25, 4.0],
+    };
+    let read = crate::parse_vol(&vol_bytes(&grid)).unwrap();
+    assert_eq!((read.resolution, read.bounds_min, read.bounds_max), (grid.resolution, grid.bounds_min, grid.bounds_max));
+    assert_eq!(read.data, grid.data);
+}
+
+#[test]
+fn test_wo3_tangents() {
+    let path = std::env::temp_dir().join("phosphor_test_tangents.wo3");
+    let vert = |x: f32, y: f32| Vertex { pos: [x, y, 0.0], normal: [0.0, 0.0, 1.0], uv: [x, y], ..Default::default() };
+    let mut mesh = TriangleMesh {
+        verts: vec![vert(0.0, 0.0), vert(1.0, 0.0), vert(0.0, 1.0)],
+        tris: vec![[0, 1, 2, 0]],
+        ..Default::default()
+    };
+    save_wo3(&mesh, &path).unwrap();
+    // Without tangents the file is what Tungsten writes
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), bincode::serialized_size(&mesh).unwrap());
+    mesh.compute_tangents();
+    save_wo3(&mesh, &path).unwrap();
+    let read = crate::load_mesh(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(read.verts.len(), 3);
+    assert!(read.verts.iter().all(|v| v.tangent == [1.0, 0.0, 0.0, 1.0]));
+}
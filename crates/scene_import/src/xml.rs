@@ -0,0 +1,206 @@
+use std::error::Error;
+
+/// An element of an XML document. Text between elements is dropped, which is all scene formats need
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct XmlElement {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<XmlElement>,
+}
+
+impl XmlElement {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses a document and returns its root element
+pub(crate) fn parse_xml(text: &str) -> Result<XmlElement, Box<dyn Error + Send + Sync>> {
+    let mut parser = Parser { text, pos: 0 };
+    let mut root = None;
+    while parser.skip_misc()? {
+        if root.is_some() {
+            return Err(parser.error("More than one root element"));
+        }
+        root = Some(parser.element()?);
+    }
+    root.ok_or_else(|| "XML document has no elements".into())
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn error(&self, message: &str) -> Box<dyn Error + Send + Sync> {
+        let line = self.text[..self.pos].matches('\n').count() + 1;
+        format!("{} on line {} of XML", message, line).into()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Moves past everything up to and including the terminator
+    fn skip_past(&mut self, terminator: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.rest().find(terminator) {
+            Some(i) => {
+                self.pos += i + terminator.len();
+                Ok(())
+            },
+            None => Err(self.error(&format!("Missing {}", terminator))),
+        }
+    }
+
+    /// Skips text, comments, declarations and processing instructions. Returns whether an element starts next
+    fn skip_misc(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        loop {
+            match self.rest().find('<') {
+                None => {
+                    self.pos = self.text.len();
+                    return Ok(false);
+                },
+                Some(i) => self.pos += i,
+            }
+            let rest = self.rest();
+            if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<![CDATA[") {
+                self.skip_past("]]>")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(!rest.starts_with("</"));
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, Box<dyn Error + Send + Sync>> {
+        let rest = self.rest();
+        let len = rest.find(|c: char| c.is_whitespace() || "/>=".contains(c)).unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("Expected a name"));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    fn element(&mut self) -> Result<XmlElement, Box<dyn Error + Send + Sync>> {
+        self.pos += 1;
+        let mut element = XmlElement {
+            name: self.name()?.to_owned(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+        };
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                return Ok(element);
+            } else if rest.starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let name = self.name()?.to_owned();
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(self.error(&format!("Attribute {} has no value", name)));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(q) if q == '"' || q == '\'' => q,
+                _ => return Err(self.error(&format!("Value of attribute {} isn't quoted", name))),
+            };
+            self.pos += 1;
+            let len = self.rest().find(quote).ok_or_else(|| self.error("Unterminated attribute value"))?;
+            let value = decode_entities(&self.rest()[..len]);
+            self.pos += len + 1;
+            element.attributes.push((name, value));
+        }
+
+        while self.skip_misc()? {
+            element.children.push(self.element()?);
+        }
+        if self.pos >= self.text.len() {
+            return Err(self.error(&format!("Element {} isn't closed", element.name)));
+        }
+        self.pos += 2;
+        let closing = self.name()?;
+        if closing != element.name {
+            return Err(self.error(&format!("Element {} is closed by {}", element.name, closing)));
+        }
+        self.skip_past(">")?;
+        Ok(element)
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_owned();
+    }
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity.strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[test]
+fn test_parse_xml() {
+    let doc = parse_xml(r#"<?xml version="1.0" encoding="utf-8"?>
+        <!-- A comment with <tags> in it -->
+        <scene version='0.6.0'>
+            <float name="a&amp;b" value="1&#x2e;5"/>
+            <bsdf type="diffuse" id="white">text is dropped<rgb name="reflectance" value="0.5, 0.5, 0.5"/></bsdf >
+        </scene>
+    "#).unwrap();
+    assert_eq!(doc.name, "scene");
+    assert_eq!(doc.attr("version"), Some("0.6.0"));
+    assert_eq!(doc.children.len(), 2);
+    assert_eq!(doc.children[0].attr("name"), Some("a&b"));
+    assert_eq!(doc.children[0].attr("value"), Some("1.5"));
+    assert_eq!(doc.children[1].children[0].name, "rgb");
+
+    assert!(parse_xml("<scene><shape></scene>").is_err());
+    assert!(parse_xml("<scene>").is_err());
+}
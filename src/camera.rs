@@ -7,45 +7,404 @@ use crate::geometry::*;
 
 pub struct Camera {
     pub origin: Vec3,
+    /// Unit vectors of the camera frame
     forward: Vec3,
     down: Vec3,
     right: Vec3,
-    upper_left: Vec3,
+    projection: Projection,
+}
+
+/// How points on the image map to rays leaving the camera
+#[derive(Debug, Clone, Copy)]
+pub enum Projection {
+    /// The half extents of the image plane are at unit distance in front of the camera.
+    ///  Without a lens this is a pinhole camera
+    Perspective { half_width: f32, half_height: f32, lens: Option<ThinLens> },
+    /// Parallel rays from a rectangle of the given half extents (in world units)
+    Orthographic { half_width: f32, half_height: f32 },
+    /// Longitude along x and latitude along y, covering every direction with forward in the centre
+    Equirectangular,
+    /// The six faces of a cube in a horizontal strip, in the order +x, -x, +y, -y, +z, -z of the camera frame
+    ///  (x is right, y is up and the camera looks along -z). The image should be six times as wide as it is tall
+    CubeMap,
+    /// An equidistant fisheye lens with the given field of view (in radians) across the largest circle
+    ///  that fits in the image. Points outside the circle see nothing
+    Fisheye { fov: f32, aspect_ratio: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Aperture {
+    Circular,
+    /// A regular polygon formed by the aperture blades. The rotation is in radians
+    Polygonal { blades: u32, rotation: f32 },
+}
+
+/// A lens that focuses on a plane in front of the camera, giving depth of field
+#[derive(Debug, Clone, Copy)]
+pub struct ThinLens {
+    pub radius: f32,
+    pub focus_distance: f32,
+    pub aperture: Aperture,
+    /// Offsets a second circle clipping the aperture towards the edges of the image,
+    ///  which gives the cat's-eye shaped bokeh of real lenses
+    pub cateye: f32,
 }
 
 impl From<scene_import::Camera> for Camera {
     fn from(camera: scene_import::Camera) -> Self {
         let res = camera.resolution;
         let aspect = (res[0] as f32) / (res[1] as f32);
-        Camera::new(
-            camera.transform.position.into(),
-            camera.transform.look_at.into(),
-            camera.transform.up.into(),
-            camera.fov / aspect,
-            aspect
-        )
+        // How far away the camera is looking, used to size orthographic views
+        let (position, forward, up, view_distance): (Vec3, Vec3, Vec3, f32) = match camera.transform {
+            scene_import::CameraTransform::LookAt { position, look_at, up } => {
+                let position = Vec3::from(position);
+                let forward = Vec3::from(look_at) - position;
+                (position, forward, up.into(), forward.length())
+            },
+            scene_import::CameraTransform::Matrix(m) => {
+                let m = Mat4::from_cols_array(&m).transpose();
+                (m.w_axis.truncate(), m.z_axis.truncate(), m.y_axis.truncate(), camera.focus_distance)
+            },
+        };
+        let fov = vertical_fov_degrees(camera.fov, camera.fov_axis, aspect);
+        let perspective = Camera::new(position, position + forward, up, fov, aspect);
+
+        let projection = match camera.camera_type.as_str() {
+            "pinhole" => return perspective,
+            "thinlens" => {
+                let focus_distance = match camera.focus_pivot {
+                    Some(pivot) => dot(Vec3::from(pivot) - position, perspective.forward).max(1e-3),
+                    None => camera.focus_distance,
+                };
+                let aperture = if camera.aperture_blades >= 3 {
+                    Aperture::Polygonal {
+                        blades: camera.aperture_blades,
+                        rotation: camera.aperture_rotation.to_radians(),
+                    }
+                } else {
+                    Aperture::Circular
+                };
+                return perspective.with_lens(ThinLens {
+                    radius: camera.aperture_size,
+                    focus_distance,
+                    aperture,
+                    cateye: camera.cateye,
+                });
+            },
+            "orthographic" => {
+                // Default to the size of the perspective view at the look at point
+                let half_width = match camera.ortho_width {
+                    Some(width) => width / 2.0,
+                    None => view_distance * (fov.to_radians() / 2.0).tan() * aspect,
+                };
+                Projection::Orthographic {
+                    half_width,
+                    half_height: half_width / aspect,
+                }
+            },
+            "equirectangular" => Projection::Equirectangular,
+            "cubemap" => Projection::CubeMap,
+            "fisheye" => Projection::Fisheye {
+                fov: camera.fov.to_radians(),
+                aspect_ratio: aspect,
+            },
+            t => {
+                log::warn!("Unknown camera type {}, using a pinhole camera", t);
+                return perspective;
+            },
+        };
+        Camera::with_frame(position, forward, up, projection)
     }
 }
 
+/// Converts a field of view along the given axis of an image to the vertical field of view
+pub fn vertical_fov_degrees(fov_degrees: f32, axis: scene_import::FovAxis, aspect_ratio: f32) -> f32 {
+    let is_horizontal = match axis {
+        scene_import::FovAxis::Horizontal => true,
+        scene_import::FovAxis::Vertical => false,
+        scene_import::FovAxis::Smaller => aspect_ratio < 1.0,
+        scene_import::FovAxis::Larger => aspect_ratio >= 1.0,
+    };
+    if !is_horizontal {
+        return fov_degrees;
+    }
+    let half_height = (fov_degrees.to_radians() / 2.0).tan() / aspect_ratio;
+    (2.0 * half_height.atan()).to_degrees()
+}
+
 impl Camera {
+    /// A pinhole camera
     pub fn new(origin: Vec3, look_at: Vec3, up: Vec3, fov_vertical_degrees: f32, aspect_ratio: f32) -> Self {
-        let forward = (look_at - origin).normalize();
         let fov: f32 = fov_vertical_degrees * PI / 180.0;
         let half_height = (fov / 2.0).tan();
         let half_width  = half_height * aspect_ratio;
-        let right = forward.cross(up).normalize();
+        Camera::look_at(origin, look_at, up, Projection::Perspective {
+            half_width,
+            half_height,
+            lens: None,
+        })
+    }
+
+    pub fn look_at(origin: Vec3, look_at: Vec3, up: Vec3, projection: Projection) -> Self {
+        Camera::with_frame(origin, look_at - origin, up, projection)
+    }
+
+    /// The camera looks along forward. up only needs to be roughly perpendicular to forward
+    pub fn with_frame(origin: Vec3, forward: Vec3, up: Vec3, projection: Projection) -> Self {
+        let forward = forward.normalize();
+        let mut right = forward.cross(up);
+        if right.length_squared() <= 1e-8 * up.length_squared() || right.length_squared() == 0.0 {
+            log::warn!("Camera up vector {} is parallel to the view direction", up);
+            let up = if forward.z.abs() < 0.9 { Vec3::Z } else { Vec3::X };
+            right = forward.cross(up);
+        }
+        let right = right.normalize();
         let down = forward.cross(right).normalize();
         Camera {
-            origin: origin,
-            forward: forward,
-            down: 2.0 * half_height * down,
-            right: 2.0 * half_width * right,
-            upper_left: -half_width * right + -half_height * down,
+            origin,
+            forward,
+            down,
+            right,
+            projection,
+        }
+    }
+
+    /// Adds depth of field to a perspective camera
+    pub fn with_lens(mut self, lens: ThinLens) -> Self {
+        match &mut self.projection {
+            Projection::Perspective { lens: l, .. } => *l = Some(lens),
+            p => log::warn!("Can't use a thin lens with a {:?} camera", p),
+        }
+        self
+    }
+
+    /// Generates a ray through the image at (x, y) in [0, 1]. lens_xi picks the point on the lens.
+    ///  Returns None if no ray passes through that point, such as when it is blocked by the aperture
+    pub fn get_ray(&self, x: f32, y: f32, lens_xi: [f32; 2]) -> Option<Ray> {
+        // Image coordinates in [-1, 1]
+        let sx = 2.0 * x - 1.0;
+        let sy = 2.0 * y - 1.0;
+        match &self.projection {
+            Projection::Perspective { half_width, half_height, lens } => {
+                let dir = sx * half_width * self.right + sy * half_height * self.down + self.forward;
+                let lens = match lens {
+                    Some(lens) => lens,
+                    None => return Some(Ray::new(self.origin, dir.normalize(), f32::MAX)),
+                };
+
+                let uv = lens.aperture.sample(lens_xi);
+                if lens.cateye > 0.0 {
+                    // The exit pupil moves away from the centre for points towards the edge of the image
+                    let offset = Vec2::new(sx, sy) * lens.cateye;
+                    if (uv - offset).length_squared() > 1.0 {
+                        return None;
+                    }
+                }
+
+                // dir has unit length along forward so this is on the focal plane
+                let focus_point = self.origin + dir * lens.focus_distance;
+                let lens_point = self.origin + lens.radius * (uv.x * self.right + uv.y * self.down);
+                Some(Ray::new(lens_point, (focus_point - lens_point).normalize(), f32::MAX))
+            },
+            Projection::Orthographic { half_width, half_height } => {
+                let origin = self.origin + sx * half_width * self.right + sy * half_height * self.down;
+                Some(Ray::new(origin, self.forward, f32::MAX))
+            },
+            Projection::Equirectangular => {
+                let phi = sx * PI;
+                let theta = y * PI;
+                let dir = theta.sin() * (phi.sin() * self.right + phi.cos() * self.forward) - theta.cos() * self.down;
+                Some(Ray::new(self.origin, dir.normalize(), f32::MAX))
+            },
+            Projection::CubeMap => {
+                let face_x = x * 6.0;
+                let face = (face_x as u32).min(5);
+                let s = 2.0 * (face_x - face as f32) - 1.0;
+                let t = sy;
+                let local = match face {
+                    0 => Vec3::new(1.0, -t, -s),
+                    1 => Vec3::new(-1.0, -t, s),
+                    2 => Vec3::new(s, 1.0, t),
+                    3 => Vec3::new(s, -1.0, -t),
+                    4 => Vec3::new(s, -t, 1.0),
+                    _ => Vec3::new(-s, -t, -1.0),
+                };
+                Some(Ray::new(self.origin, self.to_world(local).normalize(), f32::MAX))
+            },
+            Projection::Fisheye { fov, aspect_ratio } => {
+                // Scale so the circle fits the shorter side of the image
+                let (px, py) = if *aspect_ratio >= 1.0 { (sx * aspect_ratio, sy) } else { (sx, sy / aspect_ratio) };
+                let r = (px * px + py * py).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                // The angle from forward is proportional to the distance from the centre
+                let theta = r * fov / 2.0;
+                let (cos_phi, sin_phi) = if r > 0.0 { (px / r, py / r) } else { (1.0, 0.0) };
+                let dir = theta.sin() * (cos_phi * self.right + sin_phi * self.down) + theta.cos() * self.forward;
+                Some(Ray::new(self.origin, dir.normalize(), f32::MAX))
+            },
+        }
+    }
+
+    /// Transforms from the camera frame, where x is right, y is up and the camera looks along -z
+    fn to_world(&self, v: Vec3) -> Vec3 {
+        v.x * self.right - v.y * self.down - v.z * self.forward
+    }
+}
+
+impl Aperture {
+    /// Uniformly samples a point on the aperture, which fits in the unit disk
+    fn sample(&self, xi: [f32; 2]) -> Vec2 {
+        match *self {
+            Aperture::Circular => sample_concentric_disk(xi),
+            Aperture::Polygonal { blades, rotation } => {
+                // Choose one of the triangles between the centre and an edge, then sample it uniformly
+                let scaled = xi[0] * (blades as f32);
+                let i = (scaled as u32).min(blades - 1);
+                let u = scaled - (i as f32);
+                let angle = 2.0 * PI / (blades as f32);
+                let a0 = rotation + angle * (i as f32);
+                let v0 = Vec2::new(a0.cos(), a0.sin());
+                let v1 = Vec2::new((a0 + angle).cos(), (a0 + angle).sin());
+                let su = u.sqrt();
+                v0 * (su * (1.0 - xi[1])) + v1 * (su * xi[1])
+            },
         }
     }
+}
 
-    pub fn get_ray(&self, x: f32, y: f32) -> Ray {
-        let dir = self.upper_left + x * self.right + y * self.down + self.forward;
-        Ray::new(self.origin, dir.normalize(), f32::MAX)
+/// Maps the unit square to the unit disk while preserving relative areas. From
+///  Shirley & Chiu, "A Low Distortion Map Between Disk and Square"
+fn sample_concentric_disk(xi: [f32; 2]) -> Vec2 {
+    let a = 2.0 * xi[0] - 1.0;
+    let b = 2.0 * xi[1] - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec2::ZERO;
     }
-}
\ No newline at end of file
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, (PI / 4.0) * (b / a))
+    } else {
+        (b, (PI / 2.0) - (PI / 4.0) * (a / b))
+    };
+    Vec2::new(r * theta.cos(), r * theta.sin())
+}
+
+#[test]
+fn test_thin_lens_focus() {
+    let lens = ThinLens {
+        radius: 0.1,
+        focus_distance: 5.0,
+        aperture: Aperture::Polygonal { blades: 6, rotation: 0.3 },
+        cateye: 0.0,
+    };
+    let pinhole_camera = Camera::new(Vec3::ZERO, Vec3::new(0.0, 0.0, -1.0), Vec3::Y, 40.0, 1.5);
+    let pinhole = pinhole_camera.get_ray(0.3, 0.8, [0.5, 0.5]).unwrap();
+    let camera = pinhole_camera.with_lens(lens);
+    let expected = pinhole.origin + pinhole.dir * (5.0 / -pinhole.dir.z);
+
+    for i in 0..8 {
+        for j in 0..8 {
+            let xi = [(i as f32 + 0.5) / 8.0, (j as f32 + 0.5) / 8.0];
+            let ray = camera.get_ray(0.3, 0.8, xi).unwrap();
+            // Every ray through a pixel meets at the focal plane
+            assert!(ray.origin.length() <= 0.1 + 1e-5 && ray.origin.z == 0.0);
+            let p = ray.origin + ray.dir * (5.0 / -ray.dir.z);
+            assert!((p - expected).length() < 1e-3, "{:?} isn't in focus at {:?}", p, expected);
+        }
+    }
+}
+
+#[test]
+fn test_projections() {
+    let origin = Vec3::new(1.0, 2.0, 3.0);
+    let look_at = origin + Vec3::new(0.0, 0.0, -1.0);
+    let camera = |projection| Camera::look_at(origin, look_at, Vec3::Y, projection);
+    let dir = |camera: &Camera, x, y| camera.get_ray(x, y, [0.5, 0.5]).unwrap().dir;
+    let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-4;
+
+    let ortho = camera(Projection::Orthographic { half_width: 2.0, half_height: 1.0 });
+    let ray = ortho.get_ray(1.0, 0.0, [0.5, 0.5]).unwrap();
+    assert!(close(ray.dir, -Vec3::Z));
+    assert!(close(ray.origin, origin + Vec3::new(2.0, 1.0, 0.0)));
+
+    let equirect = camera(Projection::Equirectangular);
+    assert!(close(dir(&equirect, 0.5, 0.5), -Vec3::Z));
+    assert!(close(dir(&equirect, 0.75, 0.5), Vec3::X));
+    assert!(close(dir(&equirect, 0.0, 0.5), Vec3::Z));
+    assert!(close(dir(&equirect, 0.3, 0.0), Vec3::Y));
+
+    let cube = camera(Projection::CubeMap);
+    let faces = [Vec3::X, -Vec3::X, Vec3::Y, -Vec3::Y, Vec3::Z, -Vec3::Z];
+    for (i, face) in faces.iter().enumerate() {
+        assert!(close(dir(&cube, (i as f32 + 0.5) / 6.0, 0.5), *face));
+    }
+    // The top of the side faces looks up
+    assert!(dir(&cube, 4.5 / 6.0, 0.01).y > 0.0);
+
+    let fisheye = camera(Projection::Fisheye { fov: PI, aspect_ratio: 2.0 });
+    assert!(close(dir(&fisheye, 0.5, 0.5), -Vec3::Z));
+    assert!(close(dir(&fisheye, 0.75, 0.5), Vec3::X));
+    assert!(close(dir(&fisheye, 0.5, 1.0), -Vec3::Y));
+    assert!(fisheye.get_ray(0.0, 0.5, [0.5, 0.5]).is_none());
+}
+
+#[test]
+fn test_camera_frames() {
+    let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-4;
+    let horizontal_angle = |d: Vec3| d.x.atan2(-d.z).to_degrees();
+    let vertical_angle = |d: Vec3| d.y.atan2(-d.z).to_degrees();
+
+    for &(width, height) in [(200.0, 100.0), (100.0, 200.0)].iter() {
+        let aspect = width / height;
+        let fov = vertical_fov_degrees(90.0, scene_import::FovAxis::Horizontal, aspect);
+        let camera = Camera::new(Vec3::ZERO, -Vec3::Z, Vec3::Y, fov, aspect);
+        let edge = camera.get_ray(1.0, 0.5, [0.5, 0.5]).unwrap().dir;
+        assert!((horizontal_angle(edge) - 45.0).abs() < 1e-3, "Horizontal fov is wrong for {}x{}", width, height);
+        let top = camera.get_ray(0.5, 0.0, [0.5, 0.5]).unwrap().dir;
+        let expected = (1.0 / aspect).atan().to_degrees();
+        assert!((vertical_angle(top) - expected).abs() < 1e-3, "Vertical fov is wrong for {}x{}", width, height);
+
+        let fov = vertical_fov_degrees(90.0, scene_import::FovAxis::Smaller, aspect);
+        let camera = Camera::new(Vec3::ZERO, -Vec3::Z, Vec3::Y, fov, aspect);
+        let smaller = if aspect > 1.0 { camera.get_ray(0.5, 0.0, [0.5, 0.5]) } else { camera.get_ray(1.0, 0.5, [0.5, 0.5]) };
+        let smaller = smaller.unwrap().dir;
+        assert!((horizontal_angle(smaller).abs().max(vertical_angle(smaller).abs()) - 45.0).abs() < 1e-3);
+    }
+
+    // Looking straight down with the default up vector
+    let camera = Camera::new(Vec3::new(0.0, 5.0, 0.0), Vec3::ZERO, Vec3::Y, 40.0, 1.0);
+    assert!(close(camera.forward, -Vec3::Y));
+    assert!(camera.right.is_finite() && camera.down.is_finite());
+    assert!(dot(camera.right, camera.forward).abs() < 1e-5 && dot(camera.down, camera.forward).abs() < 1e-5);
+    assert!(close(camera.get_ray(0.5, 0.5, [0.5, 0.5]).unwrap().dir, -Vec3::Y));
+
+    // Tungsten's look at matrix for a camera at (1, 2, 3) looking along -x
+    let camera: Camera = scene_import::Camera {
+        tonemap: String::new(),
+        resolution: [100, 100],
+        reconstruction_filter: String::new(),
+        transform: scene_import::CameraTransform::Matrix([
+            0.0, 0.0, -1.0, 1.0,
+            0.0, 1.0,  0.0, 2.0,
+            1.0, 0.0,  0.0, 3.0,
+            0.0, 0.0,  0.0, 1.0,
+        ]),
+        fov: 90.0,
+        fov_axis: scene_import::FovAxis::Horizontal,
+        camera_type: "pinhole".to_string(),
+        aperture_size: 0.0,
+        focus_distance: 1.0,
+        focus_pivot: None,
+        aperture_blades: 0,
+        aperture_rotation: 0.0,
+        cateye: 0.0,
+        ortho_width: None,
+        shutter_open: 0.0,
+        shutter_close: 1.0,
+        medium: None,
+    }.into();
+    assert!(close(camera.origin, Vec3::new(1.0, 2.0, 3.0)));
+    assert!(close(camera.forward, -Vec3::X));
+    assert!(close(camera.down, -Vec3::Y));
+}
@@ -5,6 +5,8 @@ use std::marker::PhantomData;
 pub trait ColourSpace {
     const NAME: &'static str;
     const XYZ_TO_RGB: [f32; 9];
+    /// Per-channel weights used to calculate luminance (the Y component of XYZ)
+    const LUMINANCE: [f32; 3];
 }
 
 // Colour space reference: http://www.brucelindbloom.com/index.html?Math.html
@@ -20,7 +22,7 @@ impl ColourSpace for Rec709 {
         -0.9, 1.8, 0.04,
         0.05, -0.2, 1.05
     ];
-    // TODO: Add per-channel weights to calculate luminance?
+    const LUMINANCE: [f32; 3] = [0.2126, 0.7152, 0.0722];
 }
 
 /// RGB colour vector in a linear colour space
@@ -69,6 +71,11 @@ impl<S: ColourSpace> Rgb<S> {
         self.r == 0.0 && self.g == 0.0 && self.b == 0.0
     }
 
+    pub fn luminance(&self) -> f32 {
+        let w = S::LUMINANCE;
+        w[0] * self.r + w[1] * self.g + w[2] * self.b
+    }
+
     // pub fn black() -> Self {
     //     Self::zero()
     // }
@@ -2,8 +2,10 @@ pub mod quad;
 pub mod ray;
 pub mod sphere;
 pub mod traits;
+pub mod triangle;
 
 pub use self::quad::*;
 pub use self::ray::*;
 pub use self::sphere::*;
-pub use self::traits::*;
\ No newline at end of file
+pub use self::traits::*;
+pub use self::triangle::*;
\ No newline at end of file
@@ -1,21 +1,49 @@
+use std::str::FromStr;
+
 use crate::math::*;
+use embree::Bounds;
 use crate::colour::*;
 use crate::geometry::{SampleableEmitter, LightSample};
+use crate::light_sampler::LightBounds;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuadSampling {
+    /// Sample points uniformly over the area of the quad
+    Area,
+    /// Sample directions uniformly over the solid angle subtended by the quad.
+    ///  Only possible for rectangles, other quads fall back to area sampling
+    SolidAngle,
+}
+
+impl FromStr for QuadSampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "area" => Ok(QuadSampling::Area),
+            "solid-angle" => Ok(QuadSampling::SolidAngle),
+            _ => Err(format!("Unknown quad sampling strategy {} (expected area or solid-angle)", s)),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Quad {
     p0: Vec3,
     edge1: Vec3,
     edge2: Vec3,
+    /// Faces the side light is emitted from
     normal: Vec3,
     pub emission: Colour,
+    pub sampling: QuadSampling,
 }
 
 impl Quad {
     pub fn new(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3) -> Quad {
         let edge1 = p1 - p0;
         let edge2 = p3 - p0;
-        let normal = edge1.cross(edge2).normalize();
+        // Light is emitted on the side the edges turn clockwise around, like Tungsten's quads
+        let normal = edge2.cross(edge1).normalize();
         assert!((p0 + edge1 + edge2 - p2).length() < EPSILON, "Using edge representation for a quad causes accuracy problems");
         assert!(normal.length() > EPSILON, "Quad is degenerate");
         Quad {
@@ -24,6 +52,7 @@ impl Quad {
             edge2: edge2,
             normal: normal,
             emission: Colour::zero(),
+            sampling: QuadSampling::SolidAngle,
         }
     }
 
@@ -37,31 +66,175 @@ impl Quad {
          self.p0 + self.edge1 + self.edge2,
          self.p0 + self.edge2]
     }
-}
 
-impl SampleableEmitter for Quad {
-    fn eval_emission_at(&self, initial: Vec3, p: Vec3) -> LightSample {
-        let pdf = PdfA(1.0 / self.surface_area());
+    pub fn is_rectangle(&self) -> bool {
+        dot(self.edge1, self.edge2).abs() < 1e-4 * self.edge1.length() * self.edge2.length()
+    }
+
+    /// The spherical rectangle seen from p if it should be used for sampling
+    fn spherical_rectangle(&self, p: Vec3) -> Option<SphericalRectangle> {
+        if self.sampling != QuadSampling::SolidAngle || !self.is_rectangle() {
+            return None;
+        }
+        let rect = SphericalRectangle::new(self.p0, self.edge1, self.edge2, p);
+        // Very small solid angles are too inaccurate in single precision
+        if rect.solid_angle < MIN_SPHERICAL_SAMPLE_SOLID_ANGLE {
+            return None;
+        }
+        Some(rect)
+    }
+
+    fn light_sample(&self, initial: Vec3, p: Vec3, rect: Option<&SphericalRectangle>) -> LightSample {
         let dist = (p - initial).length();
         let dir = (p - initial) / dist;
-        let cos_theta = dot(self.normal, dir).max(0.0);
+        let cos_theta = -dot(self.normal, dir);
+        // Only the front of the quad emits light
+        let facing = cos_theta > 0.0;
+        let pdf = match rect {
+            _ if !facing => PdfW(0.0),
+            Some(rect) => PdfW(1.0 / rect.solid_angle),
+            None => PdfA(1.0 / self.surface_area()).to_pdfw(dist * dist, cos_theta),
+        };
         LightSample {
             dir: dir,
             distance: dist * 1.1,
-            radiance: self.emission,
-            pdf: pdf.to_pdfw(dist * dist, cos_theta),
+            radiance: if facing { self.emission } else { Colour::zero() },
+            pdf,
         }
     }
+}
+
+const MIN_SPHERICAL_SAMPLE_SOLID_ANGLE: f32 = 3e-4;
+
+/// The projection of a rectangle onto the unit sphere around a point, which can be
+///  sampled uniformly by solid angle.
+/// Reference: [Urena13] Ureña, Fajardo & King, "An Area-Preserving Parametrization for Spherical Rectangles"
+#[derive(Debug, Clone, Copy)]
+struct SphericalRectangle {
+    origin: Vec3,
+    /// Local frame with x and y along the edges and z pointing away from the rectangle
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+    z0: f32,
+    x0: f32,
+    y0: f32,
+    x1: f32,
+    y1: f32,
+    b0: f32,
+    b1: f32,
+    k: f32,
+    solid_angle: f32,
+}
+
+impl SphericalRectangle {
+    fn new(s: Vec3, ex: Vec3, ey: Vec3, origin: Vec3) -> Self {
+        let ex_len = ex.length();
+        let ey_len = ey.length();
+        let x = ex / ex_len;
+        let y = ey / ey_len;
+        let mut z = x.cross(y);
+
+        let d = s - origin;
+        let mut z0 = dot(d, z);
+        if z0 > 0.0 {
+            z = -z;
+            z0 = -z0;
+        }
+        let x0 = dot(d, x);
+        let y0 = dot(d, y);
+        let x1 = x0 + ex_len;
+        let y1 = y0 + ey_len;
+
+        // Normals of the planes through the origin and each edge
+        let v00 = Vec3::new(x0, y0, z0);
+        let v01 = Vec3::new(x0, y1, z0);
+        let v10 = Vec3::new(x1, y0, z0);
+        let v11 = Vec3::new(x1, y1, z0);
+        let n0 = v00.cross(v10).normalize_or_zero();
+        let n1 = v10.cross(v11).normalize_or_zero();
+        let n2 = v11.cross(v01).normalize_or_zero();
+        let n3 = v01.cross(v00).normalize_or_zero();
+
+        // Interior angles of the spherical rectangle
+        let g0 = (-dot(n0, n1)).clamp(-1.0, 1.0).acos();
+        let g1 = (-dot(n1, n2)).clamp(-1.0, 1.0).acos();
+        let g2 = (-dot(n2, n3)).clamp(-1.0, 1.0).acos();
+        let g3 = (-dot(n3, n0)).clamp(-1.0, 1.0).acos();
+
+        let k = 2.0 * PI - g2 - g3;
+        SphericalRectangle {
+            origin,
+            x,
+            y,
+            z,
+            z0,
+            x0,
+            y0,
+            x1,
+            y1,
+            b0: n0.z,
+            b1: n2.z,
+            k,
+            solid_angle: (g0 + g1 - k).max(0.0),
+        }
+    }
+
+    fn sample(&self, xi: [f32; 2]) -> Vec3 {
+        // Choose the x coordinate by the area of the spherical rectangle to its left
+        let au = xi[0] * self.solid_angle + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = (1.0 / (fu * fu + self.b0 * self.b0).sqrt()).copysign(fu).clamp(-1.0, 1.0);
+        let xu = (-(cu * self.z0) / (1.0 - cu * cu).max(0.0).sqrt()).clamp(self.x0, self.x1);
+
+        // Then choose y uniformly in solid angle along that line
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + xi[1] * (h1 - h0);
+        let hv2 = hv * hv;
+        let yv = if hv2 < 1.0 - 1e-6 { (hv * d) / (1.0 - hv2).sqrt() } else { self.y1 };
+
+        self.origin + self.x * xu + self.y * yv.clamp(self.y0, self.y1) + self.z * self.z0
+    }
+}
+
+impl SampleableEmitter for Quad {
+    fn eval_emission_at(&self, initial: Vec3, p: Vec3) -> LightSample {
+        let rect = self.spherical_rectangle(initial);
+        self.light_sample(initial, p, rect.as_ref())
+    }
 
     fn sample(&self, xi: [f32; 2], initial: Vec3) -> LightSample {
-        let p = self.p0 + self.edge1 * xi[0] + self.edge2 * xi[1];
+        let rect = self.spherical_rectangle(initial);
+        let p = match &rect {
+            Some(rect) => rect.sample(xi),
+            None => self.p0 + self.edge1 * xi[0] + self.edge2 * xi[1],
+        };
 
-        self.eval_emission_at(initial, p)
+        self.light_sample(initial, p, rect.as_ref())
     }
 
     fn surface_area(&self) -> f32 {
         self.edge1.cross(self.edge2).length()
     }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        // Diffuse emission from one side
+        PI * self.surface_area() * self.emission.luminance()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        let bounds = self.points().iter().fold(Bounds::empty(), |b, p| b.union_point(*p));
+        Some(LightBounds {
+            bounds,
+            w: self.normal,
+            phi: self.power(0.0),
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        })
+    }
 }
 
 impl Transformable for Quad {
@@ -71,4 +244,61 @@ impl Transformable for Quad {
         self.edge2 = transform.transform_vector(self.edge2);
         self.normal = transform.transform_normal(self.normal);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_quad_solid_angle_sampling() {
+    let mut quad = Quad::new(
+        Vec3::new(-1.0, 1.0, -1.0),
+        Vec3::new( 1.0, 1.0, -1.0),
+        Vec3::new( 1.0, 1.0,  1.0),
+        Vec3::new(-1.0, 1.0,  1.0));
+    quad.emission = Colour::splat(1.0);
+    // Above the quad, on the side it emits towards
+    let p = Vec3::new(0.0, 2.0, 0.0);
+
+    // A square with half-width a at distance d subtends 4 asin(a^2 / (a^2 + d^2))
+    let expected = 4.0 * (0.5f32).asin();
+    let rect = quad.spherical_rectangle(p).unwrap();
+    assert!((rect.solid_angle - expected).abs() < 1e-4, "Solid angle {}, expected {}", rect.solid_angle, expected);
+
+    for i in 0..16 {
+        for j in 0..16 {
+            let xi = [(i as f32 + 0.5) / 16.0, (j as f32 + 0.5) / 16.0];
+            let sample = quad.sample(xi, p);
+            assert!((sample.pdf.0 * expected - 1.0).abs() < 1e-3);
+            let hit = p + sample.dir * (sample.distance / 1.1);
+            assert!(hit.x.abs() <= 1.0 + 1e-4 && hit.z.abs() <= 1.0 + 1e-4 && (hit.y - 1.0).abs() < 1e-4, "Sample {:?} is outside the quad", hit);
+            let eval = quad.eval_emission_at(p, hit);
+            assert!((eval.pdf.0 - sample.pdf.0).abs() < 1e-4);
+        }
+    }
+
+    // The first half of the solid angle maps to the half of the quad along -x
+    let sample = quad.sample([0.25, 0.5], p);
+    assert!(sample.dir.x < 0.0);
+
+    quad.sampling = QuadSampling::Area;
+    let sample = quad.sample([0.5, 0.5], p);
+    assert!((sample.pdf.0 - 0.25).abs() < 1e-5);
+}
+
+#[test]
+fn test_quad_back_face() {
+    let mut quad = Quad::new(
+        Vec3::new(-1.0, 1.0, -1.0),
+        Vec3::new( 1.0, 1.0, -1.0),
+        Vec3::new( 1.0, 1.0,  1.0),
+        Vec3::new(-1.0, 1.0,  1.0));
+    quad.emission = Colour::splat(1.0);
+
+    let above = quad.sample([0.5, 0.5], Vec3::new(0.0, 2.0, 0.0));
+    assert!(!above.radiance.is_zero());
+    // Seen from below, the quad is dark whether it is sampled or hit
+    let below = Vec3::new(0.0, 0.0, 0.0);
+    for sampling in [QuadSampling::SolidAngle, QuadSampling::Area] {
+        quad.sampling = sampling;
+        assert!(quad.sample([0.5, 0.5], below).radiance.is_zero());
+        assert!(quad.eval_emission_at(below, Vec3::new(0.2, 1.0, 0.3)).radiance.is_zero());
+    }
+}
@@ -7,6 +7,8 @@ pub struct Ray {
     pub origin: Vec3,
     pub dir: Vec3,
     pub tfar: f32,
+    /// Time within the shutter interval in [0,1], for motion blur
+    pub time: f32,
 }
 
 impl Ray {
@@ -16,9 +18,15 @@ impl Ray {
             origin: origin,
             dir: dir,
             tfar: tfar,
+            time: 0.0,
         }
     }
 
+    pub fn at_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
     // pub fn in_range(&self, t: f32) -> bool {
     //     t >= 0.0 && t < self.tfar
     // }
@@ -35,6 +43,8 @@ impl Ray {
 
 impl Into<embree::Ray> for Ray {
     fn into(self) -> embree::Ray {
-        embree::Ray::new(self.origin, self.dir, 0.0, self.tfar)
+        let mut ray = embree::Ray::new(self.origin, self.dir, 0.0, self.tfar);
+        ray.set_time(self.time);
+        ray
     }
 }
\ No newline at end of file
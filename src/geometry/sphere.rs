@@ -2,6 +2,7 @@ use crate::math::*;
 use embree::{Ray, UserPrimHit, UserPrimitive, Bounds};
 use crate::colour::*;
 use crate::geometry::{SampleableEmitter, LightSample};
+use crate::light_sampler::LightBounds;
 
 #[derive(Debug, Clone)]
 pub struct Sphere {
@@ -36,28 +37,66 @@ fn sample_cone(xi: [f32; 2], cos_theta_max: f32) -> Vec3 {
     v
 }
 
+impl Sphere {
+    /// Points inside or on the sphere can't see it as a cone of directions,
+    ///  so lights are sampled by area from there instead
+    fn contains(&self, p: Vec3) -> bool {
+        self.center.distance_squared(p) <= self.radius * self.radius * 1.0001
+    }
+
+    fn cone_pdf(&self, initial: Vec3) -> PdfW {
+        let sin_theta_max2 = self.radius * self.radius / self.center.distance_squared(initial);
+        let cos_theta_max = (1.0 - sin_theta_max2).sqrt();
+        PdfW(1.0 / (2.0 * PI * (1.0 - cos_theta_max)))
+    }
+
+    /// The light arriving at initial from a point p on the sphere when sampling by area.
+    ///  Emission from the inside of the sphere is the same as from the outside
+    fn area_sample(&self, initial: Vec3, p: Vec3) -> LightSample {
+        let dist = p.distance(initial);
+        if dist == 0.0 {
+            return LightSample {
+                dir: Vec3::ZERO,
+                distance: 0.0,
+                radiance: Colour::zero(),
+                pdf: PdfW(0.0),
+            };
+        }
+        let dir = (p - initial) / dist;
+        let normal = (p - self.center) / self.radius;
+        let pdf = PdfA(1.0 / self.surface_area());
+        LightSample {
+            dir,
+            distance: dist * 1.1,
+            radiance: self.emission,
+            pdf: pdf.to_pdfw(dist * dist, dot(normal, dir)),
+        }
+    }
+}
+
 impl SampleableEmitter for Sphere {
     fn eval_emission_at(&self, initial: Vec3, p: Vec3) -> LightSample {
-        let distance = self.center.distance_squared(initial);
-        let sin_theta_max2 = self.radius * self.radius / (distance * distance);
-        let cos_theta_max = (1.0 - sin_theta_max2).sqrt();
-        let pdf = 1.0 / (2.0 * PI * (1.0 - cos_theta_max));
+        if self.contains(initial) {
+            return self.area_sample(initial, p);
+        }
         LightSample {
             dir: (p - initial).normalize(),
-            distance: distance,
+            distance: self.center.distance(initial),
             radiance: self.emission,
-            pdf: PdfW(pdf),
+            pdf: self.cone_pdf(initial),
         }
     }
 
     fn sample(&self, xi: [f32; 2], initial: Vec3) -> LightSample {
+        if self.contains(initial) {
+            // Sample by uniform area
+            let p = self.center + self.radius * sample_cone(xi, -1.0);
+            return self.area_sample(initial, p);
+        }
+
         // See https://www.akalin.com/sampling-visible-sphere
         //  if a point on the sphere (rather than a direction) is needed
         let sin_theta_max2 = self.radius * self.radius / self.center.distance_squared(initial);
-        if sin_theta_max2 >= 1.0 {
-            // Sample by uniform area
-            unimplemented!();
-        }
         let cos_theta_max = (1.0 - sin_theta_max2).sqrt();
 
         let v = sample_cone(xi, cos_theta_max);
@@ -73,13 +112,29 @@ impl SampleableEmitter for Sphere {
             dir: d,
             distance: dist,
             radiance: self.emission,
-            pdf: PdfW(1.0 / (2.0 * PI * (1.0 - cos_theta_max))),
+            pdf: self.cone_pdf(initial),
         }
     }
 
     fn surface_area(&self) -> f32 {
         4.0 * PI * self.radius * self.radius
     }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        PI * self.surface_area() * self.emission.luminance()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            bounds: UserPrimitive::bounds(self),
+            w: Vec3::Z,
+            phi: self.power(0.0),
+            // Normals point in every direction
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        })
+    }
 }
 
 impl Transformable for Sphere {
@@ -89,7 +144,7 @@ impl Transformable for Sphere {
             log::warn!("Can't transform sphere by non-uniform scale");
         }
         self.center = transform.transform_point(self.center);
-        self.radius *= transform.scale.x;
+        self.radius *= transform.scale.x.abs();
     }
 }
 
@@ -156,6 +211,57 @@ impl UserPrimitive for Sphere {
     }
 }
 
+#[test]
+fn test_sphere_sampling_inside_and_outside() {
+    let mut sphere = Sphere::unit();
+    sphere.center = Vec3::new(1.0, 2.0, 3.0);
+    sphere.radius = 2.0;
+    sphere.emission = Colour::splat(1.0);
+
+    let points = [
+        // Inside
+        sphere.center,
+        sphere.center + Vec3::new(0.5, -1.0, 0.2),
+        // On the surface
+        sphere.center + Vec3::new(0.0, 2.0, 0.0),
+        // Just outside
+        sphere.center + Vec3::new(2.001, 0.0, 0.0),
+        sphere.center + Vec3::new(0.0, 0.0, -5.0),
+    ];
+    for &initial in points.iter() {
+        let n = 32;
+        let mut solid_angle = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let xi = [(i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32];
+                let sample = sphere.sample(xi, initial);
+                assert!(sample.pdf.0.is_finite() && sample.pdf.0 >= 0.0, "Bad pdf {} at {:?}", sample.pdf.0, initial);
+                assert!(sample.dir.is_finite());
+                if sample.pdf.0 == 0.0 {
+                    continue;
+                }
+                assert!(sample.radiance.luminance() > 0.0);
+                solid_angle += 1.0 / (sample.pdf.0 * (n * n) as f32);
+
+                // The sampled direction must point at the sphere
+                let to_center = sphere.center - initial;
+                let closest = to_center - sample.dir * dot(to_center, sample.dir);
+                assert!(closest.length() <= sphere.radius * 1.001, "Sample from {:?} missed the sphere", initial);
+
+                if sphere.contains(initial) {
+                    let p = initial + sample.dir * (sample.distance / 1.1);
+                    let eval = sphere.eval_emission_at(initial, p);
+                    assert!((eval.pdf.0 - sample.pdf.0).abs() <= 1e-3 * sample.pdf.0);
+                }
+            }
+        }
+        if sphere.contains(initial) && initial.distance(sphere.center) < sphere.radius {
+            // The whole sphere of directions is covered from inside
+            assert!((solid_angle - 4.0 * PI).abs() < 0.05 * 4.0 * PI, "Solid angle {} from {:?}", solid_angle, initial);
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InfiniteSphereCap {
     pub cap_dir: Vec3,
@@ -188,4 +294,14 @@ impl SampleableEmitter for InfiniteSphereCap {
     fn surface_area(&self) -> f32 {
         f32::INFINITY
     }
+
+    fn power(&self, scene_radius: f32) -> f32 {
+        // The light arriving at a disc covering the scene
+        let solid_angle = 2.0 * PI * (1.0 - self.cap_angle.cos());
+        PI * scene_radius * scene_radius * solid_angle * self.emission.luminance()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        None
+    }
 }
\ No newline at end of file
@@ -1,11 +1,19 @@
 use crate::math::*;
 use crate::colour::*;
+use crate::light_sampler::LightBounds;
 
 pub trait SampleableEmitter: Send + Sync {
     fn eval_emission_at(&self, initial: Vec3, p: Vec3) -> LightSample;
     fn sample(&self, xi: [f32; 2], initial: Vec3) -> LightSample;
 
     fn surface_area(&self) -> f32;
+
+    /// An estimate of the total power emitted, used to choose which light to sample.
+    /// scene_radius is the radius of a sphere bounding the scene, for lights at infinity
+    fn power(&self, scene_radius: f32) -> f32;
+
+    /// Bounds on the region and directions light is emitted in. None for lights at infinity
+    fn light_bounds(&self) -> Option<LightBounds>;
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -0,0 +1,136 @@
+use crate::math::*;
+use embree::Bounds;
+use crate::colour::*;
+use crate::geometry::{SampleableEmitter, LightSample};
+use crate::light_sampler::LightBounds;
+
+/// A single triangle from an emissive mesh.
+/// Light is emitted from the front face, which has counter-clockwise winding
+#[derive(Debug, Clone)]
+pub struct Triangle {
+    p0: Vec3,
+    edge1: Vec3,
+    edge2: Vec3,
+    /// Faces the side light is emitted from
+    normal: Vec3,
+    area: f32,
+    pub emission: Colour,
+}
+
+impl Triangle {
+    pub fn new(p0: Vec3, p1: Vec3, p2: Vec3) -> Self {
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+        let cross = edge1.cross(edge2);
+        let area = 0.5 * cross.length();
+        Triangle {
+            p0,
+            edge1,
+            edge2,
+            // Degenerate triangles have no area so they're never sampled
+            normal: if area > 0.0 { cross.normalize() } else { Vec3::ZERO },
+            area,
+            emission: Colour::zero(),
+        }
+    }
+
+    pub fn points(&self) -> [Vec3; 3] {
+        [self.p0, self.p0 + self.edge1, self.p0 + self.edge2]
+    }
+}
+
+impl SampleableEmitter for Triangle {
+    fn eval_emission_at(&self, initial: Vec3, p: Vec3) -> LightSample {
+        let pdf = PdfA(1.0 / self.area);
+        let dist = (p - initial).length();
+        let dir = (p - initial) / dist;
+        let cos_theta = -dot(self.normal, dir);
+        let facing = cos_theta > 0.0;
+        LightSample {
+            dir,
+            distance: dist * 1.1,
+            radiance: if facing { self.emission } else { Colour::zero() },
+            pdf: if facing { pdf.to_pdfw(dist * dist, cos_theta) } else { PdfW(0.0) },
+        }
+    }
+
+    fn sample(&self, xi: [f32; 2], initial: Vec3) -> LightSample {
+        // Uniformly sample barycentric coordinates
+        let su = xi[0].sqrt();
+        let b1 = su * (1.0 - xi[1]);
+        let b2 = su * xi[1];
+        let p = self.p0 + self.edge1 * b1 + self.edge2 * b2;
+
+        self.eval_emission_at(initial, p)
+    }
+
+    fn surface_area(&self) -> f32 {
+        self.area
+    }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        PI * self.area * self.emission.luminance()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        let bounds = self.points().iter().fold(Bounds::empty(), |b, p| b.union_point(*p));
+        Some(LightBounds {
+            bounds,
+            w: self.normal,
+            phi: self.power(0.0),
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        })
+    }
+}
+
+#[test]
+fn test_triangle_matches_quad() {
+    use crate::geometry::{Quad, QuadSampling};
+
+    // A quad emitting towards +y, and two triangles covering it with their fronts the same way
+    let mut quad = Quad::new(
+        Vec3::new(-1.0, 1.0, -1.0),
+        Vec3::new( 1.0, 1.0, -1.0),
+        Vec3::new( 1.0, 1.0,  1.0),
+        Vec3::new(-1.0, 1.0,  1.0));
+    quad.emission = Colour::splat(1.0);
+    quad.sampling = QuadSampling::Area;
+    let mut triangles = [
+        Triangle::new(Vec3::new(-1.0, 1.0, -1.0), Vec3::new(-1.0, 1.0, 1.0), Vec3::new(1.0, 1.0, 1.0)),
+        Triangle::new(Vec3::new(-1.0, 1.0, -1.0), Vec3::new(1.0, 1.0, 1.0), Vec3::new(1.0, 1.0, -1.0)),
+    ];
+    for triangle in triangles.iter_mut() {
+        triangle.emission = Colour::splat(1.0);
+        let (quad_bounds, bounds) = (quad.light_bounds().unwrap(), triangle.light_bounds().unwrap());
+        assert!((quad_bounds.w - bounds.w).length() < 1e-6);
+        assert!((quad.power(0.0) - 2.0 * triangle.power(0.0)).abs() < 1e-4);
+    }
+
+    let above = Vec3::new(0.3, 2.0, -0.2);
+    for i in 0..8 {
+        for j in 0..8 {
+            let xi = [(i as f32 + 0.5) / 8.0, (j as f32 + 0.5) / 8.0];
+            let triangle = &triangles[(i + j) % 2];
+            let sample = triangle.sample(xi, above);
+            assert!(!sample.radiance.is_zero());
+            let hit = above + sample.dir * (sample.distance / 1.1);
+            // Evaluating where a sample landed gives the pdf it was sampled with
+            let eval = triangle.eval_emission_at(above, hit);
+            assert!((eval.pdf.0 - sample.pdf.0).abs() <= 1e-3 * sample.pdf.0);
+            // Picking one of the two triangles by power samples the square like the quad does
+            let quad_eval = quad.eval_emission_at(above, hit);
+            assert!((0.5 * eval.pdf.0 - quad_eval.pdf.0).abs() <= 1e-3 * quad_eval.pdf.0);
+            assert!(!quad_eval.radiance.is_zero());
+        }
+    }
+
+    // Neither emits towards the back
+    let below = Vec3::new(0.3, 0.0, -0.2);
+    let hit = Vec3::new(-0.5, 1.0, 0.5);
+    for eval in [triangles[0].eval_emission_at(below, hit), quad.eval_emission_at(below, hit), triangles[0].sample([0.5, 0.5], below)] {
+        assert!(eval.radiance.is_zero());
+        assert_eq!(eval.pdf.0, 0.0);
+    }
+}
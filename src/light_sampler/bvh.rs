@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use embree::Bounds;
+
+use crate::math::*;
+
+use super::light_bounds::*;
+use super::Light;
+
+const NUM_BUCKETS: usize = 12;
+
+#[derive(Debug, Clone, Copy)]
+struct LightBvhNode {
+    light_bounds: LightBounds,
+    /// For interior nodes this is the index of the second child (the first child directly follows
+    ///  its parent). For leaves this is the index of the light
+    child_or_light_index: u32,
+    is_leaf: bool,
+}
+
+/// Chooses lights proportional to an estimate of their contribution at the shading point by
+///  traversing a bounding volume hierarchy over the lights.
+/// Lights at infinity can't be bounded and are chosen uniformly instead
+pub struct BvhLightSampler {
+    nodes: Vec<LightBvhNode>,
+    infinite_lights: Vec<usize>,
+    /// Maps the index of a light to the path taken through the tree to reach it.
+    ///  Bit i is set if the second child is taken at depth i
+    bit_trails: HashMap<usize, u64>,
+}
+
+impl BvhLightSampler {
+    pub fn new(lights: &[Light], scene_radius: f32) -> Self {
+        let mut bvh_lights = Vec::new();
+        let mut infinite_lights = Vec::new();
+        for (i, light) in lights.iter().enumerate() {
+            let light = &light.emitter;
+            match light.light_bounds() {
+                Some(bounds) => {
+                    if bounds.phi > 0.0 {
+                        bvh_lights.push((i, bounds));
+                    }
+                },
+                None => {
+                    if light.power(scene_radius) > 0.0 {
+                        infinite_lights.push(i);
+                    }
+                },
+            }
+        }
+
+        let mut sampler = BvhLightSampler {
+            nodes: Vec::new(),
+            infinite_lights,
+            bit_trails: HashMap::new(),
+        };
+        if !bvh_lights.is_empty() {
+            let n = bvh_lights.len();
+            sampler.build(&mut bvh_lights, 0, n, 0, 0);
+        }
+        sampler
+    }
+
+    fn build(&mut self, bvh_lights: &mut [(usize, LightBounds)], start: usize, end: usize, bit_trail: u64, depth: u32) -> LightBounds {
+        debug_assert!(start < end);
+        if end - start == 1 {
+            let (light_index, light_bounds) = bvh_lights[start];
+            self.nodes.push(LightBvhNode {
+                light_bounds,
+                child_or_light_index: light_index as u32,
+                is_leaf: true,
+            });
+            self.bit_trails.insert(light_index, bit_trail);
+            return light_bounds;
+        }
+        debug_assert!(depth < 64, "Light BVH is too deep for the bit trail");
+
+        let mut bounds = Bounds::empty();
+        let mut centroid_bounds = Bounds::empty();
+        for (_, lb) in &bvh_lights[start..end] {
+            bounds = bounds.union(&lb.bounds);
+            centroid_bounds = centroid_bounds.union_point(lb.centroid());
+        }
+
+        // Find the split with the lowest cost by bucketing the lights along each axis
+        let mut min_cost = f32::INFINITY;
+        let mut min_cost_split: Option<(usize, usize)> = None;
+        for dim in 0..3 {
+            let extent = centroid_bounds.upper[dim] - centroid_bounds.lower[dim];
+            if extent == 0.0 {
+                continue;
+            }
+            let mut buckets: [Option<LightBounds>; NUM_BUCKETS] = [None; NUM_BUCKETS];
+            for (_, lb) in &bvh_lights[start..end] {
+                let b = bucket_index(lb.centroid(), &centroid_bounds, dim);
+                buckets[b] = Some(match buckets[b] {
+                    Some(existing) => existing.union(lb),
+                    None => *lb,
+                });
+            }
+
+            for split in 0..(NUM_BUCKETS - 1) {
+                let b0 = union_buckets(&buckets[..=split]);
+                let b1 = union_buckets(&buckets[(split + 1)..]);
+                let cost = evaluate_cost(b0.as_ref(), &bounds, dim) + evaluate_cost(b1.as_ref(), &bounds, dim);
+                if cost > 0.0 && cost < min_cost {
+                    min_cost = cost;
+                    min_cost_split = Some((dim, split));
+                }
+            }
+        }
+
+        let mut mid = (start + end) / 2;
+        if let Some((dim, split)) = min_cost_split {
+            let slice = &mut bvh_lights[start..end];
+            slice.sort_by_key(|(_, lb)| bucket_index(lb.centroid(), &centroid_bounds, dim) > split);
+            let count = slice.iter().filter(|(_, lb)| bucket_index(lb.centroid(), &centroid_bounds, dim) <= split).count();
+            mid = start + count;
+        }
+        if mid == start || mid == end {
+            mid = (start + end) / 2;
+            let slice = &mut bvh_lights[start..end];
+            let axis = max_dimension(centroid_bounds.diagonal());
+            slice.sort_by(|a, b| a.1.centroid()[axis].partial_cmp(&b.1.centroid()[axis]).unwrap());
+        }
+
+        let node_index = self.nodes.len();
+        // Placeholder until the children are built
+        self.nodes.push(LightBvhNode {
+            light_bounds: bvh_lights[start].1,
+            child_or_light_index: 0,
+            is_leaf: false,
+        });
+        let child0 = self.build(bvh_lights, start, mid, bit_trail, depth + 1);
+        let second_child = self.nodes.len() as u32;
+        let child1 = self.build(bvh_lights, mid, end, bit_trail | (1u64 << depth), depth + 1);
+
+        let light_bounds = child0.union(&child1);
+        self.nodes[node_index] = LightBvhNode {
+            light_bounds,
+            child_or_light_index: second_child,
+            is_leaf: false,
+        };
+        light_bounds
+    }
+
+    fn infinite_probability(&self) -> f32 {
+        let n_infinite = self.infinite_lights.len() as f32;
+        let n_bvh = if self.nodes.is_empty() { 0.0 } else { 1.0 };
+        if n_infinite + n_bvh == 0.0 {
+            return 0.0;
+        }
+        n_infinite / (n_infinite + n_bvh)
+    }
+
+    /// Returns the index of the chosen light and the probability it was chosen
+    pub fn sample(&self, p: Vec3, n: Vec3, u: f32) -> Option<(usize, f32)> {
+        let p_infinite = self.infinite_probability();
+        if u < p_infinite {
+            let count = self.infinite_lights.len();
+            let i = ((u / p_infinite * (count as f32)) as usize).min(count - 1);
+            return Some((self.infinite_lights[i], p_infinite / (count as f32)));
+        }
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut u = ((u - p_infinite) / (1.0 - p_infinite)).min(ONE_MINUS_EPSILON);
+        let mut pmf = 1.0 - p_infinite;
+        let mut node_index = 0;
+        loop {
+            let node = &self.nodes[node_index];
+            if node.is_leaf {
+                if node_index > 0 || node.light_bounds.importance(p, n) > 0.0 {
+                    return Some((node.child_or_light_index as usize, pmf));
+                }
+                return None;
+            }
+
+            let children = [node_index + 1, node.child_or_light_index as usize];
+            let ci = [
+                self.nodes[children[0]].light_bounds.importance(p, n),
+                self.nodes[children[1]].light_bounds.importance(p, n),
+            ];
+            if ci[0] == 0.0 && ci[1] == 0.0 {
+                return None;
+            }
+
+            // Choose a child and remap u so it can be reused further down the tree
+            let p0 = ci[0] / (ci[0] + ci[1]);
+            if u < p0 {
+                u = (u / p0).min(ONE_MINUS_EPSILON);
+                pmf *= p0;
+                node_index = children[0];
+            } else {
+                u = ((u - p0) / (1.0 - p0)).min(ONE_MINUS_EPSILON);
+                pmf *= 1.0 - p0;
+                node_index = children[1];
+            }
+        }
+    }
+
+    /// Probability of choosing the light with the given index at point p with normal n
+    pub fn pmf(&self, p: Vec3, n: Vec3, light_index: usize) -> f32 {
+        let mut bit_trail = match self.bit_trails.get(&light_index) {
+            Some(&b) => b,
+            None => {
+                if self.infinite_lights.contains(&light_index) {
+                    return self.infinite_probability() / (self.infinite_lights.len() as f32);
+                }
+                return 0.0;
+            },
+        };
+
+        let mut pmf = 1.0 - self.infinite_probability();
+        let mut node_index = 0;
+        loop {
+            let node = &self.nodes[node_index];
+            if node.is_leaf {
+                return pmf;
+            }
+            let children = [node_index + 1, node.child_or_light_index as usize];
+            let ci = [
+                self.nodes[children[0]].light_bounds.importance(p, n),
+                self.nodes[children[1]].light_bounds.importance(p, n),
+            ];
+            let child = (bit_trail & 1) as usize;
+            if ci[child] == 0.0 {
+                return 0.0;
+            }
+            pmf *= ci[child] / (ci[0] + ci[1]);
+            node_index = children[child];
+            bit_trail >>= 1;
+        }
+    }
+}
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+fn bucket_index(centroid: Vec3, centroid_bounds: &Bounds, dim: usize) -> usize {
+    let offset = (centroid[dim] - centroid_bounds.lower[dim]) / (centroid_bounds.upper[dim] - centroid_bounds.lower[dim]);
+    ((NUM_BUCKETS as f32 * offset) as usize).min(NUM_BUCKETS - 1)
+}
+
+fn union_buckets(buckets: &[Option<LightBounds>]) -> Option<LightBounds> {
+    buckets.iter().flatten().fold(None, |acc, b| match acc {
+        Some(a) => Some(b.union(&a)),
+        None => Some(*b),
+    })
+}
+
+fn max_dimension(v: Vec3) -> usize {
+    if v.x > v.y && v.x > v.z {
+        0
+    } else if v.y > v.z {
+        1
+    } else {
+        2
+    }
+}
+
+/// Surface area orientation heuristic from [Conty2018Importance]
+fn evaluate_cost(b: Option<&LightBounds>, bounds: &Bounds, dim: usize) -> f32 {
+    let b = match b {
+        Some(b) => b,
+        None => return 0.0,
+    };
+    let theta_o = b.cos_theta_o.clamp(-1.0, 1.0).acos();
+    let theta_e = b.cos_theta_e.clamp(-1.0, 1.0).acos();
+    let theta_w = (theta_o + theta_e).min(PI);
+    let sin_theta_o = (1.0 - b.cos_theta_o * b.cos_theta_o).max(0.0).sqrt();
+    let m_omega = 2.0 * PI * (1.0 - b.cos_theta_o) +
+        PI / 2.0 * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos() - 2.0 * theta_o * sin_theta_o + b.cos_theta_o);
+
+    // Penalise splitting along short axes to avoid thin nodes
+    let d = bounds.diagonal();
+    let k_r = d.max_element() / d[dim];
+    b.phi * m_omega * k_r * b.bounds.surface_area()
+}
+
+#[test]
+fn test_bvh_pmf_matches_sampling() {
+    use embree::GeomID;
+
+    use crate::colour::Colour;
+    use crate::geometry::{Quad, Sphere};
+
+    let mut lights = Vec::new();
+    for i in 0..9 {
+        let x = (i % 3) as f32 * 2.0;
+        let z = (i / 3) as f32 * 2.0;
+        let mut quad = Quad::new(
+            Vec3::new(x, 4.0, z),
+            Vec3::new(x + 1.0, 4.0, z),
+            Vec3::new(x + 1.0, 4.0, z + 1.0),
+            Vec3::new(x, 4.0, z + 1.0));
+        quad.emission = Colour::splat(1.0 + i as f32);
+        lights.push(Light::new(GeomID::new(i), Box::new(quad)));
+    }
+    let mut sphere = Sphere::unit();
+    sphere.center = Vec3::new(-3.0, 1.0, 0.0);
+    sphere.emission = Colour::splat(5.0);
+    lights.push(Light::new(GeomID::new(9), Box::new(sphere)));
+
+    let sampler = BvhLightSampler::new(&lights, 10.0);
+    let p = Vec3::new(1.0, 0.0, 1.0);
+    let n = Vec3::Y;
+
+    let total: f32 = (0..lights.len()).map(|i| sampler.pmf(p, n, i)).sum();
+    assert!((total - 1.0).abs() < 1e-4, "Light probabilities sum to {}", total);
+
+    for i in 0..1000 {
+        let u = (i as f32 + 0.5) / 1000.0;
+        let (index, pmf) = sampler.sample(p, n, u).unwrap();
+        let expected = sampler.pmf(p, n, index);
+        assert!((pmf - expected).abs() < 1e-5, "Sampled pmf {} doesn't match {}", pmf, expected);
+    }
+}
@@ -0,0 +1,173 @@
+use embree::Bounds;
+
+use crate::math::*;
+
+// Reference: [Conty2018Importance] Conty Estevez & Kulla, "Importance Sampling of Many Lights with
+//  Adaptive Tree Splitting", and the light BVH in PBRT-v4 (Section 12.6.3)
+
+/// A cone of directions around a central axis
+#[derive(Debug, Clone, Copy)]
+pub struct DirectionCone {
+    pub w: Vec3,
+    pub cos_theta: f32,
+}
+
+impl DirectionCone {
+    pub fn new(w: Vec3, cos_theta: f32) -> Self {
+        DirectionCone {
+            w: w.normalize(),
+            cos_theta,
+        }
+    }
+
+    pub fn entire_sphere() -> Self {
+        DirectionCone {
+            w: Vec3::Z,
+            cos_theta: -1.0,
+        }
+    }
+
+    /// The smallest cone containing both cones
+    pub fn union(&self, other: &DirectionCone) -> DirectionCone {
+        let theta_a = safe_acos(self.cos_theta);
+        let theta_b = safe_acos(other.cos_theta);
+        let theta_d = safe_acos(dot(self.w, other.w));
+
+        // One of the cones contains the other
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return *self;
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return *other;
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        if theta_o >= PI {
+            return DirectionCone::entire_sphere();
+        }
+
+        // Rotate the axis of this cone towards the other to find the new axis
+        let theta_r = theta_o - theta_a;
+        let wr = self.w.cross(other.w);
+        if wr.length_squared() == 0.0 {
+            return DirectionCone::entire_sphere();
+        }
+        let w = Quat::from_axis_angle(wr.normalize(), theta_r) * self.w;
+        DirectionCone::new(w, theta_o.cos())
+    }
+}
+
+/// Conservative bounds on the position, emission directions and power of a set of lights
+#[derive(Debug, Clone, Copy)]
+pub struct LightBounds {
+    pub bounds: Bounds,
+    /// Central axis of the emitting surface normals
+    pub w: Vec3,
+    /// Emitted power
+    pub phi: f32,
+    /// Cosine of the angle around w bounding the surface normals
+    pub cos_theta_o: f32,
+    /// Cosine of the angle past theta_o that light is emitted in
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    pub fn centroid(&self) -> Vec3 {
+        self.bounds.center()
+    }
+
+    pub fn union(&self, other: &LightBounds) -> LightBounds {
+        if self.phi == 0.0 {
+            return *other;
+        }
+        if other.phi == 0.0 {
+            return *self;
+        }
+        let cone = DirectionCone::new(self.w, self.cos_theta_o).union(&DirectionCone::new(other.w, other.cos_theta_o));
+        LightBounds {
+            bounds: self.bounds.union(&other.bounds),
+            w: cone.w,
+            phi: self.phi + other.phi,
+            cos_theta_o: cone.cos_theta,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    /// An estimate of the light arriving at point p with surface normal n.
+    /// A zero normal is used for points that aren't on a surface
+    pub fn importance(&self, p: Vec3, n: Vec3) -> f32 {
+        let pc = self.centroid();
+        // Clamp the distance so points inside the bounds don't get unbounded importance
+        let d2 = p.distance_squared(pc).max(self.bounds.diagonal().length() / 2.0);
+
+        // Cosine and sine of the angle between the cone axis and the direction to p
+        let wi = (p - pc).normalize_or_zero();
+        let mut cos_theta_w = dot(self.w, wi);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // The angle subtended by the bounds as seen from p
+        let cos_theta_b = bound_subtended_directions(&self.bounds, p).cos_theta;
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b * cos_theta_b);
+
+        // Minimum angle between an emitter normal and the direction to p
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * cos_theta_p / d2;
+
+        if n != Vec3::ZERO {
+            // Minimum angle between the surface normal and a direction to the bounds
+            let cos_theta_i = dot(wi, n).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+        importance.max(0.0)
+    }
+}
+
+/// The cone of directions from p that contains the bounds
+fn bound_subtended_directions(bounds: &Bounds, p: Vec3) -> DirectionCone {
+    let center = bounds.center();
+    let radius_sq = bounds.diagonal().length_squared() / 4.0;
+    let dist_sq = p.distance_squared(center);
+    if dist_sq < radius_sq {
+        return DirectionCone::entire_sphere();
+    }
+    let sin_theta_max2 = radius_sq / dist_sq;
+    let cos_theta_max = safe_sqrt(1.0 - sin_theta_max2);
+    DirectionCone::new(center - p, cos_theta_max)
+}
+
+/// cos(max(0, θa - θb))
+fn cos_sub_clamped(sin_theta_a: f32, cos_theta_a: f32, sin_theta_b: f32, cos_theta_b: f32) -> f32 {
+    if cos_theta_a > cos_theta_b {
+        return 1.0;
+    }
+    cos_theta_a * cos_theta_b + sin_theta_a * sin_theta_b
+}
+
+/// sin(max(0, θa - θb))
+fn sin_sub_clamped(sin_theta_a: f32, cos_theta_a: f32, sin_theta_b: f32, cos_theta_b: f32) -> f32 {
+    if cos_theta_a > cos_theta_b {
+        return 0.0;
+    }
+    sin_theta_a * cos_theta_b - cos_theta_a * sin_theta_b
+}
+
+fn safe_sqrt(x: f32) -> f32 {
+    x.max(0.0).sqrt()
+}
+
+fn safe_acos(x: f32) -> f32 {
+    x.clamp(-1.0, 1.0).acos()
+}
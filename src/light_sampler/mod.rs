@@ -0,0 +1,140 @@
+pub mod bvh;
+pub mod light_bounds;
+pub mod power;
+
+pub use self::bvh::*;
+pub use self::light_bounds::*;
+pub use self::power::*;
+
+use std::str::FromStr;
+
+use embree::{GeomID, Hit};
+use vec_map::VecMap;
+
+use crate::geometry::SampleableEmitter;
+use crate::math::*;
+
+/// An emitter and the geometry it is attached to
+pub struct Light {
+    /// Invalid for lights at infinity, which have no geometry
+    pub geom_id: GeomID,
+    /// The triangle this light covers when it is one part of an emissive mesh
+    pub prim_id: Option<u32>,
+    pub emitter: Box<dyn SampleableEmitter>,
+}
+
+impl Light {
+    pub fn new(geom_id: GeomID, emitter: Box<dyn SampleableEmitter>) -> Self {
+        Light {
+            geom_id,
+            prim_id: None,
+            emitter,
+        }
+    }
+
+    pub fn with_primitive(geom_id: GeomID, prim_id: u32, emitter: Box<dyn SampleableEmitter>) -> Self {
+        Light {
+            geom_id,
+            prim_id: Some(prim_id),
+            emitter,
+        }
+    }
+
+    /// Whether the hit is on the surface of this light
+    pub fn is_hit_by(&self, hit: &Hit) -> bool {
+        // Lights aren't instanced
+        if self.geom_id.is_invalid() || hit.is_instanced() || hit.geom_id != self.geom_id {
+            return false;
+        }
+        match self.prim_id {
+            Some(prim_id) => hit.prim_id.id == prim_id,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightSamplingStrategy {
+    /// Choose lights proportional to their power
+    Power,
+    /// Choose lights proportional to their estimated contribution at the shading point
+    Bvh,
+}
+
+impl FromStr for LightSamplingStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "power" => Ok(LightSamplingStrategy::Power),
+            "bvh" => Ok(LightSamplingStrategy::Bvh),
+            _ => Err(format!("Unknown light sampling strategy {} (expected power or bvh)", s)),
+        }
+    }
+}
+
+enum Sampler {
+    Power(PowerLightSampler),
+    Bvh(BvhLightSampler),
+}
+
+/// Chooses which light to sample at a shading point
+pub struct LightSampler {
+    sampler: Sampler,
+    /// Maps a geometry ID to the index of its first light, and whether it is a mesh.
+    ///  The lights of an emissive mesh are stored contiguously so the light for a triangle
+    ///  is offset by its primitive ID
+    light_indices: VecMap<(usize, bool)>,
+}
+
+impl LightSampler {
+    pub fn new(strategy: LightSamplingStrategy, lights: &[Light], scene_radius: f32) -> Self {
+        let sampler = match strategy {
+            LightSamplingStrategy::Power => Sampler::Power(PowerLightSampler::new(lights, scene_radius)),
+            LightSamplingStrategy::Bvh => Sampler::Bvh(BvhLightSampler::new(lights, scene_radius)),
+        };
+
+        let mut light_indices = VecMap::new();
+        for (i, light) in lights.iter().enumerate() {
+            if !light.geom_id.is_invalid() && !light_indices.contains_key(light.geom_id.id as usize) {
+                debug_assert!(light.prim_id.unwrap_or(0) == 0, "Mesh lights must be stored in primitive order");
+                light_indices.insert(light.geom_id.id as usize, (i, light.prim_id.is_some()));
+            }
+        }
+
+        LightSampler {
+            sampler,
+            light_indices,
+        }
+    }
+
+    /// Returns the index of the chosen light and the probability it was chosen.
+    /// n is the surface normal at p, or zero if p isn't on a surface
+    pub fn sample(&self, p: Vec3, n: Vec3, u: f32) -> Option<(usize, f32)> {
+        match &self.sampler {
+            Sampler::Power(s) => s.sample(u),
+            Sampler::Bvh(s) => s.sample(p, n, u),
+        }
+    }
+
+    /// Probability of choosing the light with the given index at point p with normal n
+    pub fn pmf(&self, p: Vec3, n: Vec3, light_index: usize) -> f32 {
+        match &self.sampler {
+            Sampler::Power(s) => s.pmf(light_index),
+            Sampler::Bvh(s) => s.pmf(p, n, light_index),
+        }
+    }
+
+    /// Finds the light that a ray hit landed on
+    pub fn light_index(&self, hit: &Hit) -> Option<usize> {
+        if hit.geom_id.is_invalid() || hit.is_instanced() {
+            return None;
+        }
+        let (first, is_mesh) = *self.light_indices.get(hit.geom_id.id as usize)?;
+        if is_mesh {
+            Some(first + hit.prim_id.id as usize)
+        } else {
+            Some(first)
+        }
+    }
+}
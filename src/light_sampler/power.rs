@@ -0,0 +1,31 @@
+use crate::sampling::AliasTable;
+
+use super::Light;
+
+/// Chooses which light to sample, proportional to the power each light emits
+pub struct PowerLightSampler {
+    distribution: AliasTable,
+}
+
+impl PowerLightSampler {
+    pub fn new(lights: &[Light], scene_radius: f32) -> Self {
+        let powers: Vec<f32> = lights.iter().map(|light| light.emitter.power(scene_radius).max(0.0)).collect();
+
+        PowerLightSampler {
+            distribution: AliasTable::new(&powers),
+        }
+    }
+
+    /// Returns the index of the chosen light and the probability it was chosen
+    pub fn sample(&self, u: f32) -> Option<(usize, f32)> {
+        if self.distribution.is_empty() {
+            return None;
+        }
+        Some(self.distribution.sample(u))
+    }
+
+    /// Probability of choosing the light with the given index
+    pub fn pmf(&self, light_index: usize) -> f32 {
+        self.distribution.pmf(light_index)
+    }
+}
@@ -5,8 +5,10 @@ mod colour;
 mod geometry;
 mod materials;
 mod sampling;
+mod media;
 
 mod scene;
+mod light_sampler;
 mod path_integrator;
 mod camera;
 mod render_buffer;
@@ -17,12 +19,15 @@ use std::fs::File;
 use std::io::BufWriter;
 use std::error::Error;
 use std::fmt;
+use std::path::Path;
 
 use argh::FromArgs;
 
-use scene_import::load_scene;
+use scene_import::{load_mesh, load_scene, save_mesh};
 
 use crate::scene::*;
+use crate::geometry::QuadSampling;
+use crate::light_sampler::LightSamplingStrategy;
 use crate::path_integrator::*;
 use crate::render_buffer::*;
 
@@ -31,13 +36,51 @@ use crate::render_buffer::*;
 struct Args {
     /// input scene file
     #[argh(positional)]
-    scene_file: String,
+    scene_file: Option<String>,
     /// samples per pixel
     #[argh(option, short = 'n')]
     samples: Option<u32>,
     /// output image file
     #[argh(option, short = 'o')]
     output: Option<String>,
+    /// how lights are chosen for sampling: bvh (default) or power
+    #[argh(option)]
+    light_sampler: Option<LightSamplingStrategy>,
+    /// how points on quad lights are sampled: solid-angle (default) or area
+    #[argh(option)]
+    quad_sampling: Option<QuadSampling>,
+    /// load the scene and report its problems without rendering
+    #[argh(switch)]
+    validate: bool,
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Convert(ConvertArgs),
+}
+
+/// Convert an OBJ, PLY or .wo3 mesh into a .wo3 file, which loads faster
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "convert")]
+struct ConvertArgs {
+    /// input mesh file
+    #[argh(positional)]
+    input: String,
+    /// output .wo3 file, the input with its extension changed by default
+    #[argh(positional)]
+    output: Option<String>,
+    /// merge vertices with positions, normals and texture coordinates within this distance, or identical ones if it is 0
+    #[argh(option)]
+    weld: Option<f32>,
+    /// replace the normals with ones averaged from the triangles around each vertex
+    #[argh(switch)]
+    recompute_normals: bool,
+    /// generate tangents from the texture coordinates, for normal maps
+    #[argh(switch)]
+    tangents: bool,
 }
 
 struct Timer {
@@ -69,6 +112,62 @@ impl fmt::Display for Timer {
     }
 }
 
+/// Logs everything wrong with a scene and returns how many problems there were
+fn validate(scene_file: &str) -> usize {
+    let scene = match load_scene(scene_file) {
+        Ok(scene) => scene,
+        Err(e) => {
+            log::error!("{}", e);
+            return 1;
+        },
+    };
+    for warning in &scene.warnings {
+        log::warn!("{}", warning);
+    }
+    let problems = scene.problems();
+    for problem in &problems {
+        log::warn!("{}", problem);
+    }
+    let count = problems.len() + scene.warnings.len();
+    if count == 0 {
+        log::info!("{} has no problems", scene_file);
+    } else {
+        log::info!("{} has {} problems", scene_file, count);
+    }
+    count
+}
+
+fn convert(args: &ConvertArgs) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let timer = Timer::start();
+    let mut mesh = load_mesh(&args.input)?;
+    if args.recompute_normals {
+        // The normals are replaced anyway, and different ones would stop vertices being welded
+        for vert in &mut mesh.verts {
+            vert.normal = [0.0; 3];
+        }
+    }
+    if let Some(tolerance) = args.weld {
+        let vertex_count = mesh.verts.len();
+        mesh.weld(tolerance);
+        log::info!("Welded {} vertices into {}", vertex_count, mesh.verts.len());
+    }
+    if args.recompute_normals {
+        mesh.compute_normals();
+    }
+    if args.tangents {
+        mesh.compute_tangents();
+    }
+
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => Path::new(&args.input).with_extension("wo3").to_string_lossy().into_owned(),
+    };
+    save_mesh(&mesh, &output)?;
+    log::info!("{:>16} took: {}", "Conversion", timer);
+    log::info!("Mesh written to {}", output);
+    Ok(())
+}
+
 const DEFAULT_SPP: u32 = 8;
 const DEFAULT_BOUNCES: u32 = 4;
 
@@ -81,6 +180,18 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>>{
         // .expect("Unable to initialize logger");
     
     let config: Args = argh::from_env();
+    if let Some(Command::Convert(args)) = &config.command {
+        if let Err(e) = convert(args) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let scene_file = config.scene_file.ok_or("No scene file given")?;
+    if config.validate {
+        let problem_count = validate(&scene_file);
+        std::process::exit(if problem_count == 0 { 0 } else { 1 });
+    }
 
     let build_timer = Timer::start();
     
@@ -88,8 +199,14 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>>{
     embree::set_flush_to_zero_mode();
 
     let mut scene_builder = SceneBuilder::new(&device);
+    if let Some(strategy) = config.light_sampler {
+        scene_builder.set_light_sampling(strategy);
+    }
+    if let Some(sampling) = config.quad_sampling {
+        scene_builder.set_quad_sampling(sampling);
+    }
 
-    let scene_desc = load_scene(config.scene_file)?;
+    let scene_desc = load_scene(scene_file)?;
 
     let camera = scene_desc.camera.clone().into();
 
@@ -100,6 +217,7 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>>{
 
     let spp = config.samples.unwrap_or(DEFAULT_SPP);
     let mut path_integrator = PathIntegrator::new(scene_builder.build(), spp, DEFAULT_BOUNCES);
+    path_integrator.volume_light_sampling = scene_desc.integrator.enable_volume_light_sampling;
     if cfg!(not(debug_assertions)) {
         path_integrator.spp = scene_desc.renderer.spp;
         path_integrator.max_depth = scene_desc.integrator.max_bounces;
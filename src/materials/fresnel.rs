@@ -31,6 +31,13 @@ impl SchlickFresnel {
             r0: Colour::new(r0(ior.n[0], ior.k[0]), r0(ior.n[1], ior.k[1]), r0(ior.n[2], ior.k[2])),
         }
     }
+
+    /// Uses the reflectance at normal incidence directly
+    pub fn from_reflectance(r0: Colour) -> Self {
+        SchlickFresnel {
+            r0,
+        }
+    }
     
     pub fn fresnel(&self, cos_t: f32) -> Colour {
         self.r0 + (Colour::one() - self.r0) * (1.0 - cos_t).powi(5)
@@ -3,9 +3,11 @@ pub mod diffuse;
 pub mod ggx;
 pub mod fresnel;
 pub mod glossy;
+pub mod subsurface;
 
 pub use self::bsdf::*;
 pub use self::diffuse::*;
 pub use self::ggx::*;
 pub use self::fresnel::*;
-pub use self::glossy::*;
\ No newline at end of file
+pub use self::glossy::*;
+pub use self::subsurface::*;
\ No newline at end of file
@@ -0,0 +1,110 @@
+use super::bsdf::*;
+use super::diffuse::*;
+use crate::colour::*;
+use crate::math::*;
+use crate::media::*;
+
+/// A translucent material where light scatters around inside the surface before leaving it again.
+///  Light goes through the boundary diffusely and takes a random walk through a medium inside,
+///  so this should only be used on closed surfaces
+#[derive(Debug, Clone)]
+pub struct Subsurface {
+    /// The colour of the surface after all the scattering inside it
+    pub albedo: Colour,
+    /// Average distance light travels inside the surface between scattering events
+    pub mean_free_path: Colour,
+}
+
+impl Subsurface {
+    pub fn new(albedo: Colour, mean_free_path: Colour) -> Self {
+        Subsurface {
+            albedo,
+            mean_free_path,
+        }
+    }
+
+    /// The medium the random walk takes place in
+    pub fn medium(&self) -> HomogeneousMedium {
+        let sigma_t = |mfp: f32| 1.0 / mfp.max(1e-6);
+        let sigma_t = Colour::new(sigma_t(self.mean_free_path.r), sigma_t(self.mean_free_path.g), sigma_t(self.mean_free_path.b));
+        let alpha = Colour::new(
+            single_scattering_albedo(self.albedo.r),
+            single_scattering_albedo(self.albedo.g),
+            single_scattering_albedo(self.albedo.b));
+        let sigma_s = sigma_t * alpha;
+        HomogeneousMedium {
+            sigma_a: sigma_t - sigma_s,
+            sigma_s,
+            phase: PhaseFunction::Isotropic,
+        }
+    }
+
+    fn boundary(&self) -> Lambert {
+        Lambert::new(Colour::one())
+    }
+}
+
+/// The albedo of each scattering event that gives a random walk an overall albedo of a.
+///  This is the fit to van de Hulst's tables from [Christensen15]
+fn single_scattering_albedo(a: f32) -> f32 {
+    let a = a.clamp(0.0, 1.0);
+    let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+    (1.0 - s * s).clamp(0.0, 1.0)
+}
+
+/// The boundary transmits light diffusely. The basis normal should be on the side the light leaves towards
+impl Bsdf for Subsurface {
+    fn sample(&self, xi: [f32; 2], basis: &TangentFrame, w_o: Vec3) -> BsdfSample {
+        self.boundary().sample(xi, basis, w_o)
+    }
+
+    fn eval(&self, basis: &TangentFrame, w_o: Vec3, w_i: Vec3) -> BsdfSample {
+        self.boundary().eval(basis, w_o, w_i)
+    }
+
+    fn albedo(&self) -> Colour {
+        self.albedo
+    }
+
+    fn reflectivity(&self) -> f32 {
+        0.0
+    }
+}
+
+#[test]
+fn test_random_walk_albedo() {
+    use crate::sampling::PathSample;
+
+    // Walks into a half space below z = 0 should come back out with the given albedo
+    let albedo = Colour::new(0.2, 0.5, 0.9);
+    let subsurface = Subsurface::new(albedo, Colour::one());
+    let medium = subsurface.medium();
+    let mut rng = PathSample::from_seed(3);
+    let walks = 20000;
+    let mut reflected = Colour::zero();
+    for _ in 0..walks {
+        let [u1, u2] = rng.next_2d();
+        let r = u1.sqrt();
+        let mut dir = Vec3::new(r * (2.0 * PI * u2).cos(), r * (2.0 * PI * u2).sin(), -(1.0 - u1).sqrt());
+        let mut p = Vec3::ZERO;
+        let mut weight = Colour::one();
+        for _ in 0..1000 {
+            let t_max = if dir.z > 0.0 { -p.z / dir.z } else { f32::INFINITY };
+            let sample = medium.sample_distance(t_max, rng.next_2d());
+            weight *= sample.weight;
+            match sample.t {
+                Some(t) => {
+                    p += dir * t;
+                    dir = medium.phase.sample(rng.next_2d(), -dir).w_i;
+                },
+                None => {
+                    reflected += weight / walks as f32;
+                    break;
+                },
+            }
+        }
+    }
+    for &(a, b) in [(reflected.r, albedo.r), (reflected.g, albedo.g), (reflected.b, albedo.b)].iter() {
+        assert!((a - b).abs() < 0.05, "Reflected {} expected {}", reflected, albedo);
+    }
+}
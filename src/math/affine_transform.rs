@@ -6,7 +6,7 @@ pub trait Transformable {
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
-/// A 3D affine transformation
+/// A 3D affine transformation. Points are scaled, then rotated, then translated
 pub struct AffineTransform {
     pub rotation: Mat3,
     pub scale: Vec3,
@@ -15,16 +15,22 @@ pub struct AffineTransform {
 
 impl AffineTransform {
     pub fn transform_point(&self, p: Vec3) -> Vec3 {
-        let scale = Vec3::new(self.scale.x, self.scale.y, self.scale.z);
-        (self.rotation * p) * scale + self.translation
+        self.rotation * (p * self.scale) + self.translation
     }
 
-    pub fn transform_normal(&self, v: Vec3) -> Vec3 {
-        self.rotation * v
+    /// Normals are transformed by the inverse transpose so they stay perpendicular to surfaces
+    ///  under non-uniform scales. The result is normalised
+    pub fn transform_normal(&self, n: Vec3) -> Vec3 {
+        (self.rotation * (n / self.scale)).normalize()
     }
 
     pub fn transform_vector(&self, v: Vec3) -> Vec3 {
-        (self.rotation * v) * self.scale
+        self.rotation * (v * self.scale)
+    }
+
+    /// Whether the transform mirrors space, which turns the winding order of triangles around
+    pub fn flips_orientation(&self) -> bool {
+        self.scale.x * self.scale.y * self.scale.z < 0.0
     }
 
     pub fn to_matrix(&self) -> Mat4 {
@@ -35,6 +41,20 @@ impl AffineTransform {
         m
     }
 
+    /// Blends towards other as t goes from 0 to 1. Rotations are interpolated along the shortest arc
+    pub fn interpolate(&self, other: &AffineTransform, t: f32) -> AffineTransform {
+        let q0 = Quat::from_rotation_mat3(&self.rotation);
+        let mut q1 = Quat::from_rotation_mat3(&other.rotation);
+        if q0.dot(q1) < 0.0 {
+            q1 = -q1;
+        }
+        AffineTransform {
+            rotation: Mat3::from_quat(q0.slerp(q1, t)),
+            scale: self.scale.lerp(other.scale, t),
+            translation: self.translation.lerp(other.translation, t),
+        }
+    }
+
     pub fn is_similarity(&self) -> bool {
         self.scale.x == self.scale.y && self.scale.y == self.scale.z
     }
@@ -42,4 +62,46 @@ impl AffineTransform {
     pub fn _is_isometry(&self) -> bool {
         self.scale.x.abs() == 1.0 && self.scale.y.abs() == 1.0 && self.scale.z.abs() == 1.0
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_interpolate() {
+    let a = AffineTransform {
+        rotation: Mat3::IDENTITY,
+        scale: Vec3::ONE,
+        translation: Vec3::ZERO,
+    };
+    let b = AffineTransform {
+        rotation: Mat3::from_axis_angle(Vec3::Y, PI / 2.0),
+        scale: Vec3::splat(3.0),
+        translation: Vec3::new(2.0, 0.0, 0.0),
+    };
+    let mid = a.interpolate(&b, 0.5);
+    let expected = Mat3::from_axis_angle(Vec3::Y, PI / 4.0);
+    // glam's slerp uses an approximate acos
+    assert!((mid.rotation * Vec3::X - expected * Vec3::X).length() < 1e-3);
+    assert!((mid.scale - Vec3::splat(2.0)).length() < 1e-5);
+    assert!((mid.translation - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-5);
+}
+
+#[test]
+fn test_transform_matches_matrix() {
+    let transform = AffineTransform {
+        rotation: Mat3::from_axis_angle(Vec3::new(1.0, 2.0, -0.5).normalize(), 0.8),
+        scale: Vec3::new(2.0, -0.5, 3.0),
+        translation: Vec3::new(1.0, -2.0, 0.5),
+    };
+    let matrix = transform.to_matrix();
+    let p = Vec3::new(0.3, -1.2, 2.0);
+    assert!((transform.transform_point(p) - matrix.transform_point3(p)).length() < 1e-5);
+    assert!((transform.transform_vector(p) - matrix.transform_vector3(p)).length() < 1e-5);
+
+    // Normals stay perpendicular to transformed tangents
+    let n = Vec3::new(1.0, 1.0, 0.0).normalize();
+    let tangent = Vec3::new(1.0, -1.0, 2.0);
+    assert!(dot(n, tangent).abs() < 1e-6);
+    let n_t = transform.transform_normal(n);
+    assert!(dot(n_t, transform.transform_vector(tangent)).abs() < 1e-5);
+    assert!((n_t.length() - 1.0).abs() < 1e-5);
+    assert!(transform.flips_orientation());
+}
@@ -0,0 +1,304 @@
+use crate::math::*;
+use crate::colour::*;
+use crate::geometry::Ray;
+use crate::sampling::PathSample;
+
+use super::*;
+
+/// Number of density voxels along each axis of a majorant cell
+const MAJORANT_CELL_SIZE: usize = 8;
+
+/// Densities at the centres of the voxels of a grid that fills the unit cube
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    data: Vec<f32>,
+}
+
+impl VoxelGrid {
+    /// data is stored with x varying fastest, then y, then z
+    pub fn new(resolution: [usize; 3], data: Vec<f32>) -> Self {
+        assert_eq!(resolution[0] * resolution[1] * resolution[2], data.len(), "Grid data doesn't match its resolution");
+        VoxelGrid { resolution, data }
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.data[(z * self.resolution[1] + y) * self.resolution[0] + x]
+    }
+
+    /// Trilinearly interpolated density at a point in the unit cube
+    pub fn density(&self, p: Vec3) -> f32 {
+        let mut i = [0; 3];
+        let mut j = [0; 3];
+        let mut f = [0.0; 3];
+        for axis in 0..3 {
+            let res = self.resolution[axis];
+            let x = (p[axis] * res as f32 - 0.5).clamp(0.0, (res - 1) as f32);
+            i[axis] = x as usize;
+            j[axis] = (i[axis] + 1).min(res - 1);
+            f[axis] = x - i[axis] as f32;
+        }
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let x00 = lerp(self.voxel(i[0], i[1], i[2]), self.voxel(j[0], i[1], i[2]), f[0]);
+        let x10 = lerp(self.voxel(i[0], j[1], i[2]), self.voxel(j[0], j[1], i[2]), f[0]);
+        let x01 = lerp(self.voxel(i[0], i[1], j[2]), self.voxel(j[0], i[1], j[2]), f[0]);
+        let x11 = lerp(self.voxel(i[0], j[1], j[2]), self.voxel(j[0], j[1], j[2]), f[0]);
+        lerp(lerp(x00, x10, f[1]), lerp(x01, x11, f[1]), f[2])
+    }
+}
+
+/// The maximum density over coarse blocks of voxels. Tracking steps through the cells
+///  so that thin regions of the grid don't have to take the small steps the densest region needs
+#[derive(Debug, Clone)]
+struct MajorantGrid {
+    resolution: [usize; 3],
+    data: Vec<f32>,
+}
+
+impl MajorantGrid {
+    fn new(grid: &VoxelGrid) -> Self {
+        let resolution = grid.resolution.map(|res| res.div_ceil(MAJORANT_CELL_SIZE));
+        let mut data = vec![0.0f32; resolution[0] * resolution[1] * resolution[2]];
+        for z in 0..resolution[2] {
+            for y in 0..resolution[1] {
+                for x in 0..resolution[0] {
+                    // Interpolation inside a cell can reach the voxels just outside of it
+                    let range = |c: usize, axis: usize| {
+                        let start = (c * MAJORANT_CELL_SIZE).saturating_sub(1);
+                        let end = ((c + 1) * MAJORANT_CELL_SIZE + 1).min(grid.resolution[axis]);
+                        start..end
+                    };
+                    let mut max = 0.0f32;
+                    for vz in range(z, 2) {
+                        for vy in range(y, 1) {
+                            for vx in range(x, 0) {
+                                max = max.max(grid.voxel(vx, vy, vz));
+                            }
+                        }
+                    }
+                    data[(z * resolution[1] + y) * resolution[0] + x] = max;
+                }
+            }
+        }
+        MajorantGrid { resolution, data }
+    }
+
+    /// Calls f with the majorant of each cell the ray passes through between t_min and t_max, in order.
+    ///  origin and dir are in the unit cube's space. Stops early if f returns false
+    fn traverse(&self, origin: Vec3, dir: Vec3, t_min: f32, t_max: f32, mut f: impl FnMut(f32, f32, f32) -> bool) {
+        let res = Vec3::new(self.resolution[0] as f32, self.resolution[1] as f32, self.resolution[2] as f32);
+        let start = (origin + dir * t_min) * res;
+        let mut cell = [0i32; 3];
+        let mut step = [0i32; 3];
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            let last = self.resolution[axis] as i32 - 1;
+            cell[axis] = (start[axis] as i32).clamp(0, last);
+            let d = dir[axis] * res[axis];
+            if d > 0.0 {
+                step[axis] = 1;
+                t_delta[axis] = 1.0 / d;
+                t_next[axis] = t_min + ((cell[axis] + 1) as f32 - start[axis]) / d;
+            } else if d < 0.0 {
+                step[axis] = -1;
+                t_delta[axis] = -1.0 / d;
+                t_next[axis] = t_min + (cell[axis] as f32 - start[axis]) / d;
+            }
+        }
+
+        let mut t = t_min;
+        while t < t_max {
+            let axis = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] { 0 } else { 2 }
+            } else if t_next[1] < t_next[2] { 1 } else { 2 };
+            let t_end = t_next[axis].min(t_max);
+            let index = (cell[2] as usize * self.resolution[1] + cell[1] as usize) * self.resolution[0] + cell[0] as usize;
+            if !f(t, t_end, self.data[index]) {
+                return;
+            }
+            t = t_end;
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= self.resolution[axis] as i32 {
+                return;
+            }
+            t_next[axis] += t_delta[axis];
+        }
+    }
+}
+
+/// A medium whose density varies over a voxel grid. Its coefficients are scaled by the density
+#[derive(Debug, Clone)]
+pub struct GridMedium {
+    pub sigma_a: Colour,
+    pub sigma_s: Colour,
+    pub phase: PhaseFunction,
+    grid: VoxelGrid,
+    majorants: MajorantGrid,
+    world_to_grid: Mat4,
+}
+
+impl GridMedium {
+    /// grid_to_world maps the unit cube the grid fills into the scene
+    pub fn new(sigma_a: Colour, sigma_s: Colour, phase: PhaseFunction, grid: VoxelGrid, grid_to_world: Mat4) -> Self {
+        let majorants = MajorantGrid::new(&grid);
+        GridMedium {
+            sigma_a,
+            sigma_s,
+            phase,
+            grid,
+            majorants,
+            world_to_grid: grid_to_world.inverse(),
+        }
+    }
+
+    /// The ray in grid space and the range of t it overlaps the grid for.
+    ///  Distances along the ray are the same in both spaces since the direction isn't normalised
+    fn clip(&self, ray: &Ray, t_max: f32) -> Option<(Vec3, Vec3, f32, f32)> {
+        let origin = self.world_to_grid.transform_point3(ray.origin);
+        let dir = self.world_to_grid.transform_vector3(ray.dir);
+        let mut t0 = 0.0f32;
+        let mut t1 = t_max;
+        for axis in 0..3 {
+            let inv_d = 1.0 / dir[axis];
+            let mut near = -origin[axis] * inv_d;
+            let mut far = (1.0 - origin[axis]) * inv_d;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // NaNs from rays parallel to the slab on its boundary are ignored by max and min
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+        }
+        if t0 < t1 { Some((origin, dir, t0, t1)) } else { None }
+    }
+
+    fn max_sigma_t(&self) -> f32 {
+        let sigma_t = self.sigma_a + self.sigma_s;
+        sigma_t.r.max(sigma_t.g).max(sigma_t.b)
+    }
+
+    /// Spectral delta tracking. Tentative collisions are sampled from the majorant and each
+    ///  is chosen to be a real or null collision in proportion to its (weighted) coefficients
+    pub fn sample_distance(&self, ray: &Ray, t_max: f32, rng: &mut PathSample) -> MediumSample {
+        let mut weight = Colour::one();
+        let (origin, dir, t0, t1) = match self.clip(ray, t_max) {
+            Some(clipped) => clipped,
+            None => return MediumSample { t: None, weight },
+        };
+        let max_sigma_t = self.max_sigma_t();
+        let mut scatter = None;
+
+        self.majorants.traverse(origin, dir, t0, t1, |start, end, majorant| {
+            let mu = majorant * max_sigma_t;
+            if mu <= 0.0 {
+                return true;
+            }
+            let mut t = start;
+            loop {
+                t -= (1.0 - rng.next_f32()).ln() / mu;
+                if t >= end {
+                    return true;
+                }
+                let density = self.grid.density(origin + dir * t);
+                let sigma_s = self.sigma_s * density;
+                let sigma_a = self.sigma_a * density;
+                let sigma_n = Colour::splat(mu) - sigma_s - sigma_a;
+
+                let max_of = |c: Colour| c.r.max(c.g).max(c.b).max(0.0);
+                let p_s = max_of(sigma_s * weight);
+                let p_a = max_of(sigma_a * weight);
+                let p_n = max_of(sigma_n * weight);
+                let total = p_s + p_a + p_n;
+                if total <= 0.0 {
+                    weight = Colour::zero();
+                    return false;
+                }
+                let xi = rng.next_f32() * total;
+                if xi < p_s {
+                    weight *= sigma_s * (total / (mu * p_s));
+                    scatter = Some(t);
+                    return false;
+                } else if xi < p_s + p_a {
+                    weight = Colour::zero();
+                    return false;
+                }
+                weight *= sigma_n * (total / (mu * p_n));
+            }
+        });
+
+        MediumSample { t: scatter, weight }
+    }
+
+    /// Ratio tracking, which weights each tentative collision by the chance of it being a null collision
+    pub fn transmittance(&self, ray: &Ray, t_max: f32, rng: &mut PathSample) -> Colour {
+        let mut tr = Colour::one();
+        let (origin, dir, t0, t1) = match self.clip(ray, t_max) {
+            Some(clipped) => clipped,
+            None => return tr,
+        };
+        let sigma_t = self.sigma_a + self.sigma_s;
+        let max_sigma_t = self.max_sigma_t();
+
+        self.majorants.traverse(origin, dir, t0, t1, |start, end, majorant| {
+            let mu = majorant * max_sigma_t;
+            if mu <= 0.0 {
+                return true;
+            }
+            let mut t = start;
+            loop {
+                t -= (1.0 - rng.next_f32()).ln() / mu;
+                if t >= end {
+                    return true;
+                }
+                let density = self.grid.density(origin + dir * t);
+                tr *= Colour::one() - sigma_t * (density / mu);
+                if tr.is_zero() {
+                    return false;
+                }
+            }
+        });
+        tr
+    }
+}
+
+#[test]
+fn test_grid_tracking() {
+    // A grid that is empty in its lower half and has a density of 2 in the upper half
+    let n = 16;
+    let mut data = vec![0.0; n * n * n];
+    for z in 0..n {
+        for y in n / 2..n {
+            for x in 0..n {
+                data[(z * n + y) * n + x] = 2.0;
+            }
+        }
+    }
+    let grid = VoxelGrid::new([n, n, n], data);
+    let grid_to_world = Mat4::from_scale(Vec3::splat(4.0));
+    let medium = GridMedium::new(Colour::new(0.1, 0.2, 0.3), Colour::new(0.3, 0.2, 0.1), PhaseFunction::Isotropic, grid, grid_to_world);
+
+    // Through the dense half, away from where the density is interpolated between the halves
+    let ray = Ray::new(Vec3::new(-1.0, 3.0, 2.0), Vec3::X, 10.0);
+    let expected = beer_lambert(Colour::splat(0.4) * 2.0, 4.0);
+    let mut rng = PathSample::from_seed(7);
+    let samples = 20000;
+    let mut tr = Colour::zero();
+    let mut transmitted = Colour::zero();
+    for _ in 0..samples {
+        tr += medium.transmittance(&ray, 10.0, &mut rng) / samples as f32;
+        let sample = medium.sample_distance(&ray, 10.0, &mut rng);
+        match sample.t {
+            Some(t) => assert!(t > 1.0 && t < 5.0),
+            None => transmitted += sample.weight / samples as f32,
+        }
+    }
+    assert!((tr.g - expected.g).abs() < 0.02, "Transmittance {} expected {}", tr, expected);
+    assert!((transmitted.g - expected.g).abs() < 0.02, "Transmitted {} expected {}", transmitted, expected);
+
+    // The empty half and rays that miss the grid let everything through
+    let ray = Ray::new(Vec3::new(-1.0, 1.0, 2.0), Vec3::X, 10.0);
+    assert!((medium.transmittance(&ray, 10.0, &mut rng) - Colour::one()).is_zero());
+    let ray = Ray::new(Vec3::new(-1.0, 5.0, 2.0), Vec3::X, 10.0);
+    assert!(medium.sample_distance(&ray, 10.0, &mut rng).t.is_none());
+}
@@ -0,0 +1,47 @@
+use crate::colour::*;
+
+use super::*;
+
+/// A medium with the same density everywhere
+#[derive(Debug, Clone)]
+pub struct HomogeneousMedium {
+    pub sigma_a: Colour,
+    pub sigma_s: Colour,
+    pub phase: PhaseFunction,
+}
+
+impl HomogeneousMedium {
+    pub fn sigma_t(&self) -> Colour {
+        self.sigma_a + self.sigma_s
+    }
+
+    pub fn transmittance(&self, distance: f32) -> Colour {
+        beer_lambert(self.sigma_t(), distance)
+    }
+
+    /// Samples a distance to scatter at proportional to the transmittance of a randomly chosen channel.
+    ///  This is unbiased for all channels by using the average pdf of the channels
+    pub fn sample_distance(&self, t_max: f32, xi: [f32; 2]) -> MediumSample {
+        let sigma_t = self.sigma_t();
+        let channel = ((xi[0] * 3.0) as usize).min(2);
+        let sigma_c = channel_of(sigma_t, channel);
+        let t = if sigma_c > 0.0 { -(1.0 - xi[1]).ln() / sigma_c } else { f32::INFINITY };
+
+        if t < t_max {
+            let tr = beer_lambert(sigma_t, t);
+            let density = sigma_t * tr;
+            let pdf = (density.r + density.g + density.b) / 3.0;
+            MediumSample {
+                t: Some(t),
+                weight: if pdf > 0.0 { tr * self.sigma_s / pdf } else { Colour::zero() },
+            }
+        } else {
+            let tr = beer_lambert(sigma_t, t_max);
+            let pdf = (tr.r + tr.g + tr.b) / 3.0;
+            MediumSample {
+                t: None,
+                weight: if pdf > 0.0 { tr / pdf } else { Colour::zero() },
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+pub mod homogeneous;
+pub mod grid;
+pub mod phase;
+
+pub use self::homogeneous::*;
+pub use self::grid::*;
+pub use self::phase::*;
+
+use crate::colour::*;
+use crate::geometry::Ray;
+use crate::sampling::PathSample;
+
+/// A participating medium that light is absorbed and scattered by as it travels through
+#[derive(Debug, Clone)]
+pub enum Medium {
+    Homogeneous(HomogeneousMedium),
+    Grid(GridMedium),
+}
+
+/// The result of sampling a distance along a ray through a medium
+#[derive(Debug, Clone, Copy)]
+pub struct MediumSample {
+    /// Distance along the ray to the scattering point, or None if the ray got through without scattering
+    pub t: Option<f32>,
+    /// Transmittance (and scattering coefficient) divided by the pdf of the sample
+    pub weight: Colour,
+}
+
+impl Medium {
+    /// Samples where a ray scatters before t_max
+    pub fn sample_distance(&self, ray: &Ray, t_max: f32, rng: &mut PathSample) -> MediumSample {
+        match self {
+            Medium::Homogeneous(m) => m.sample_distance(t_max, rng.next_2d()),
+            Medium::Grid(m) => m.sample_distance(ray, t_max, rng),
+        }
+    }
+
+    /// The fraction of light that gets through the medium along the ray up to t_max
+    pub fn transmittance(&self, ray: &Ray, t_max: f32, rng: &mut PathSample) -> Colour {
+        match self {
+            Medium::Homogeneous(m) => m.transmittance(t_max),
+            Medium::Grid(m) => m.transmittance(ray, t_max, rng),
+        }
+    }
+
+    pub fn phase(&self) -> &PhaseFunction {
+        match self {
+            Medium::Homogeneous(m) => &m.phase,
+            Medium::Grid(m) => &m.phase,
+        }
+    }
+}
+
+/// The media on either side of a surface. None is a vacuum.
+///  A surface without any media doesn't change the medium a ray is in
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MediumInterface {
+    pub interior: Option<usize>,
+    pub exterior: Option<usize>,
+}
+
+impl MediumInterface {
+    pub fn is_transition(&self) -> bool {
+        self.interior.is_some() || self.exterior.is_some()
+    }
+}
+
+/// Transmittance over a distance with extinction coefficient sigma_t
+pub fn beer_lambert(sigma_t: Colour, distance: f32) -> Colour {
+    let tr = |sigma: f32| if sigma == 0.0 { 1.0 } else { (-sigma * distance).exp() };
+    Colour::new(tr(sigma_t.r), tr(sigma_t.g), tr(sigma_t.b))
+}
+
+fn channel_of(c: Colour, channel: usize) -> f32 {
+    match channel {
+        0 => c.r,
+        1 => c.g,
+        _ => c.b,
+    }
+}
+
+#[test]
+fn test_homogeneous_distance_sampling() {
+    let medium = HomogeneousMedium {
+        sigma_a: Colour::new(0.1, 0.5, 0.2),
+        sigma_s: Colour::new(0.8, 0.3, 0.0),
+        phase: PhaseFunction::Isotropic,
+    };
+    // The weights of the samples that pass through should average to the transmittance
+    let t_max = 1.5;
+    let n = 512;
+    let mut transmitted = Colour::zero();
+    let mut scattered = Colour::zero();
+    for i in 0..n {
+        for j in 0..n {
+            let xi = [(i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32];
+            let sample = medium.sample_distance(t_max, xi);
+            match sample.t {
+                Some(t) => {
+                    assert!(t < t_max);
+                    scattered += sample.weight / (n * n) as f32;
+                },
+                None => transmitted += sample.weight / (n * n) as f32,
+            }
+        }
+    }
+    let tr = medium.transmittance(t_max);
+    for &(a, b) in [(transmitted.r, tr.r), (transmitted.g, tr.g), (transmitted.b, tr.b)].iter() {
+        assert!((a - b).abs() < 1e-2, "Transmitted {} expected {}", transmitted, tr);
+    }
+    // Integral of sigma_s * Tr(t) over [0, t_max]
+    let sigma_t = medium.sigma_t();
+    let expected = Colour::new(
+        medium.sigma_s.r / sigma_t.r * (1.0 - tr.r),
+        medium.sigma_s.g / sigma_t.g * (1.0 - tr.g),
+        medium.sigma_s.b / sigma_t.b * (1.0 - tr.b));
+    for &(a, b) in [(scattered.r, expected.r), (scattered.g, expected.g), (scattered.b, expected.b)].iter() {
+        assert!((a - b).abs() < 1e-2, "Scattered {} expected {}", scattered, expected);
+    }
+}
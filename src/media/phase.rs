@@ -0,0 +1,77 @@
+use crate::math::*;
+
+/// The angular distribution of light scattered in a medium. Phase functions are normalised
+///  so they are also the pdf of their own samples
+#[derive(Debug, Clone, Copy)]
+pub enum PhaseFunction {
+    Isotropic,
+    /// g in (-1, 1) is the mean cosine of the scattering angle. Positive values scatter forwards
+    HenyeyGreenstein { g: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PhaseSample {
+    pub w_i: Vec3,
+    pub pdf: PdfW,
+}
+
+impl PhaseFunction {
+    /// w_o points back along the incoming ray and w_i is the scattered direction
+    pub fn eval(&self, w_o: Vec3, w_i: Vec3) -> f32 {
+        match *self {
+            PhaseFunction::Isotropic => 1.0 / (4.0 * PI),
+            PhaseFunction::HenyeyGreenstein { g } => henyey_greenstein(dot(-w_o, w_i), g),
+        }
+    }
+
+    pub fn sample(&self, xi: [f32; 2], w_o: Vec3) -> PhaseSample {
+        let cos_theta = match *self {
+            PhaseFunction::Isotropic => 1.0 - 2.0 * xi[0],
+            PhaseFunction::HenyeyGreenstein { g } => {
+                if g.abs() < 1e-3 {
+                    1.0 - 2.0 * xi[0]
+                } else {
+                    let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi[0]);
+                    ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+                }
+            },
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * xi[1];
+
+        // The scattering angle is measured from the direction the light was travelling in
+        let d = -w_o;
+        let (x, y) = make_orthonormal_basis(d);
+        let w_i = sin_theta * phi.cos() * x + sin_theta * phi.sin() * y + cos_theta * d;
+        PhaseSample {
+            w_i,
+            pdf: PdfW(self.eval(w_o, w_i)),
+        }
+    }
+}
+
+/// cos_theta is the cosine of the angle between the incoming and outgoing directions of travel
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.max(0.0).sqrt())
+}
+
+#[test]
+fn test_henyey_greenstein() {
+    let w_o = Vec3::new(0.3, -0.5, 0.8).normalize();
+    for &g in [-0.7, 0.0, 0.4, 0.9].iter() {
+        let phase = PhaseFunction::HenyeyGreenstein { g };
+        let n = 256;
+        let mut mean_cos = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let xi = [(i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32];
+                let sample = phase.sample(xi, w_o);
+                assert!((sample.w_i.length() - 1.0).abs() < 1e-4);
+                assert!((sample.pdf.0 - phase.eval(w_o, sample.w_i)).abs() < 1e-3 * sample.pdf.0);
+                mean_cos += dot(-w_o, sample.w_i) / (n * n) as f32;
+            }
+        }
+        assert!((mean_cos - g).abs() < 1e-2, "Mean cosine {} for g = {}", mean_cos, g);
+    }
+}
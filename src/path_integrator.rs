@@ -9,11 +9,14 @@ use crate::camera::*;
 use crate::render_buffer::*;
 use crate::sampling::*;
 use crate::materials::bsdf::Bsdf;
+use crate::media::*;
 
 pub struct PathIntegrator {
     scene: Scene,
     pub spp: u32,
     pub max_depth: u32,
+    /// Whether lights are sampled from scattering events inside media
+    pub volume_light_sampling: bool,
 }
 
 impl PathIntegrator {
@@ -22,6 +25,7 @@ impl PathIntegrator {
             scene,
             spp,
             max_depth,
+            volume_light_sampling: true,
         }
     }
 
@@ -43,7 +47,14 @@ impl PathIntegrator {
                 let offset_x = r1 * inv_w;
                 let offset_y = r2 * inv_h;
 
-                let camera_ray = camera.get_ray(x + offset_x, y + offset_y);
+                let camera_ray = match camera.get_ray(x + offset_x, y + offset_y, rng.next_2d()) {
+                    Some(ray) => ray.at_time(rng.next_f32()),
+                    None => {
+                        // Blocked by the aperture
+                        pixel.add_sample(Colour::zero());
+                        continue;
+                    },
+                };
                 let mut radiance = self.radiance(&camera_ray, &mut rng);
                 if radiance.is_nan() {
                     log::error!("NaN colour at pixel ({},{}), sample {}", x_i, y_i, sample_i);
@@ -59,77 +70,165 @@ impl PathIntegrator {
 
         let mut radiance = Colour::zero();
         let mut reflectance = Colour::new(1.0, 1.0, 1.0);
-
-        for depth in 0..self.max_depth {
+        // The pdf of the BSDF or phase function sample that generated the current ray.
+        //  None if emission found by the ray couldn't have been found by light sampling
+        let mut bsdf_pdf: Option<PdfW> = None;
+        // Where the path last scattered, which is where lights would have been sampled from
+        let mut origin = ray.origin;
+        let mut origin_normal = Vec3::ZERO;
+        let mut medium = self.scene.camera_medium();
+        let mut depth = 0;
+        let mut crossings = 0;
+
+        while depth < self.max_depth {
             let mut rayhit = RayHit::from_ray(ray.into());
             let ray_intersected = self.scene.intersect(&mut rayhit);
             ray.tfar = rayhit.ray.tfar;
             let mut hit = rayhit.hit;
 
+            if let Some(m) = medium {
+                let m = self.scene.medium(m);
+                let t_max = if ray_intersected { ray.tfar } else { f32::INFINITY };
+                let medium_sample = m.sample_distance(&ray, t_max, rng);
+                reflectance *= medium_sample.weight;
+                if reflectance.is_zero() {
+                    // Absorbed
+                    break;
+                }
+                if let Some(t) = medium_sample.t {
+                    depth += 1;
+                    let p = ray.point_at_dist(t);
+                    let phase = m.phase();
+                    if self.volume_light_sampling {
+                        radiance += reflectance * self.direct_light_sample_medium(rng, &ray, p, phase, medium);
+                    }
+
+                    let phase_sample = phase.sample(rng.next_2d(), -ray.dir);
+                    bsdf_pdf = if self.volume_light_sampling { Some(phase_sample.pdf) } else { None };
+                    origin = p;
+                    origin_normal = Vec3::ZERO;
+                    ray = Ray::new(p, phase_sample.w_i, f32::MAX).at_time(ray.time);
+                    continue;
+                }
+            }
+
             if !ray_intersected {
                 radiance += reflectance * self.scene.skybox_emission(ray.dir);
                 break;
             }
             
+            let geometric_normal = hit.Ng;
             if same_hemisphere(hit.Ng, ray.dir) {
                 hit.Ng = -hit.Ng;
             }
+            let hit_p = ray.point_at_dist(ray.tfar);
         
-            let light_sample = self.scene.emission_at(&camera_ray, &hit);
-            let weight = if depth == 0 { 1.0 } else { 0.0 };
-            radiance += reflectance * weight * light_sample.radiance;
+            let light_sample = self.scene.emission_at(origin, origin_normal, hit_p, &hit);
+            if !light_sample.radiance.is_zero() {
+                // Emission reached by a BSDF sample could also have been found by light sampling
+                let weight = match bsdf_pdf {
+                    Some(pdf) => pdf.combine(light_sample.pdf).0,
+                    None => 1.0,
+                };
+                radiance += reflectance * weight * light_sample.radiance;
+            }
 
+            if self.scene.is_interface(&hit) {
+                // The path carries on in the same direction into the medium on the other side
+                crossings += 1;
+                if crossings > MAX_INTERFACE_CROSSINGS {
+                    break;
+                }
+                medium = self.scene.medium_after(&hit, geometric_normal, ray.dir, medium);
+                ray = Ray::new(hit_p, ray.dir, f32::MAX).at_time(ray.time);
+                ray.offset(-hit.Ng);
+                continue;
+            }
+            depth += 1;
+
+            let shading_normal = if self.scene.is_translucent(&hit) { -hit.Ng } else { hit.Ng };
             let shading = ShadingParameters {
-                basis: TangentFrame::from_normal(hit.Ng),
+                basis: TangentFrame::from_normal(shading_normal),
             };
             let bsdf = self.scene.bsdf_at(&hit);
 
-            radiance += reflectance * self.direct_light_sample(rng, &ray, &hit, &shading, &bsdf);
+            radiance += reflectance * self.direct_light_sample(rng, &ray, &hit, geometric_normal, &shading, &bsdf, medium);
 
             let xi = rng.next_2d();
             let bsdf_sample = bsdf.sample(xi, &shading.basis, -ray.dir);
 
             if bsdf_sample.pdf.0 > EPSILON {
-                reflectance *= bsdf_sample.reflectance * dot(bsdf_sample.w_i, hit.Ng) / bsdf_sample.pdf.0;
+                reflectance *= bsdf_sample.reflectance * dot(bsdf_sample.w_i, hit.Ng).abs() / bsdf_sample.pdf.0;
             } else {
                 reflectance = Colour::zero();
             }
             debug_assert!(reflectance.r >= 0.0 && reflectance.g >= 0.0 && reflectance.b >= 0.0, "Reflectance should be positive");
-
-            ray = Ray::new(ray.point_at_dist(ray.tfar), bsdf_sample.w_i, ::std::f32::MAX);
-            ray.offset(hit.Ng);
+            bsdf_pdf = Some(bsdf_sample.pdf);
+            origin = hit_p;
+            origin_normal = shading.basis.normal;
+            medium = self.scene.medium_after(&hit, geometric_normal, bsdf_sample.w_i, medium);
+
+            let offset_dir = if dot(bsdf_sample.w_i, hit.Ng) >= 0.0 { hit.Ng } else { -hit.Ng };
+            ray = Ray::new(hit_p, bsdf_sample.w_i, f32::MAX).at_time(ray.time);
+            ray.offset(offset_dir);
         }
         radiance
     }
 
-    fn direct_light_sample(&self, rng: &mut PathSample, ray: &Ray, hit: &Hit, shading: &ShadingParameters, bsdf: &impl Bsdf) -> Colour {
-        if self.scene.lights.len() == 0 {
-            return Colour::zero();
-        }
-
-        let (light_id, light) = &self.scene.lights[rng.next_range(0..self.scene.lights.len() as u32) as usize];
-        let light_id = *light_id;
+    #[allow(clippy::too_many_arguments)]
+    fn direct_light_sample(&self, rng: &mut PathSample, ray: &Ray, hit: &Hit, geometric_normal: Vec3,
+            shading: &ShadingParameters, bsdf: &impl Bsdf, medium: Option<usize>) -> Colour {
+        let hit_p = ray.point_at_dist(ray.tfar);
+        let (light, light_pmf) = match self.scene.sample_light(hit_p, shading.basis.normal, rng.next_f32()) {
+            Some(l) => l,
+            None => return Colour::zero(),
+        };
 
-        if hit.geom_id == light_id {
+        if light.is_hit_by(hit) {
             return Colour::zero();
         }
 
-        let hit_p = ray.point_at_dist(ray.tfar);
         let xi = rng.next_2d();
-        let light_sample = light.sample(xi, hit_p);
+        let light_sample = light.emitter.sample(xi, hit_p);
+        let light_pdf = PdfW(light_sample.pdf.0 * light_pmf);
 
         let n_dot_l = dot(shading.basis.normal, light_sample.dir);
-        if n_dot_l > EPSILON && light_sample.pdf.0 > EPSILON {
-            let mut light_ray = Ray::new(hit_p, light_sample.dir, light_sample.distance);
-            light_ray.offset(hit.Ng);
-
-            let mut rayhit = RayHit::from_ray(light_ray.into());
-            self.scene.intersect(&mut rayhit);
-            if light_id == rayhit.hit.geom_id || (light_sample.distance == f32::INFINITY && !rayhit.hit.is_hit()) {
+        if n_dot_l > EPSILON && light_pdf.0 > EPSILON {
+            let offset_dir = if dot(light_sample.dir, hit.Ng) >= 0.0 { hit.Ng } else { -hit.Ng };
+            let origin = hit_p + offset_dir * EPSILON;
+            let medium = self.scene.medium_after(hit, geometric_normal, light_sample.dir, medium);
+            let tr = self.scene.transmittance(origin, light_sample.dir, light_sample.distance, ray.time, medium, light, rng);
+            if !tr.is_zero() {
                 let bsdf_sample = bsdf.eval(&shading.basis, -ray.dir, light_sample.dir);
-                return light_sample.radiance * bsdf_sample.reflectance * n_dot_l / light_sample.pdf.0;
+                // Lights at infinity aren't hit by BSDF samples, so they only get light samples
+                let is_infinite = light_sample.distance == f32::INFINITY;
+                let weight = if is_infinite { 1.0 } else { light_pdf.combine(bsdf_sample.pdf).0 };
+                return tr * light_sample.radiance * bsdf_sample.reflectance * n_dot_l * weight / light_pdf.0;
             }
         }
         Colour::zero()
     }
-}
\ No newline at end of file
+
+    /// Light arriving at point p in a medium and scattered back along the ray
+    fn direct_light_sample_medium(&self, rng: &mut PathSample, ray: &Ray, p: Vec3, phase: &PhaseFunction, medium: Option<usize>) -> Colour {
+        let (light, light_pmf) = match self.scene.sample_light(p, Vec3::ZERO, rng.next_f32()) {
+            Some(l) => l,
+            None => return Colour::zero(),
+        };
+
+        let light_sample = light.emitter.sample(rng.next_2d(), p);
+        let light_pdf = PdfW(light_sample.pdf.0 * light_pmf);
+        if light_pdf.0 <= EPSILON {
+            return Colour::zero();
+        }
+
+        let tr = self.scene.transmittance(p, light_sample.dir, light_sample.distance, ray.time, medium, light, rng);
+        if tr.is_zero() {
+            return Colour::zero();
+        }
+        let phase_value = phase.eval(-ray.dir, light_sample.dir);
+        let is_infinite = light_sample.distance == f32::INFINITY;
+        let weight = if is_infinite { 1.0 } else { light_pdf.combine(PdfW(phase_value)).0 };
+        tr * light_sample.radiance * phase_value * weight / light_pdf.0
+    }
+}
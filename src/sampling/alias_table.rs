@@ -0,0 +1,115 @@
+/// A discrete distribution that can be sampled in constant time using Vose's alias method.
+/// See: https://www.keithschwarz.com/darts-dice-coins/
+#[derive(Debug, Clone)]
+pub struct AliasTable {
+    bins: Vec<AliasBin>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AliasBin {
+    /// Probability of choosing this bin rather than its alias
+    q: f32,
+    alias: u32,
+    pmf: f32,
+}
+
+impl AliasTable {
+    /// Builds a table from non-negative weights. If all the weights are zero
+    ///  then the distribution is uniform
+    pub fn new(weights: &[f32]) -> Self {
+        assert!(weights.len() <= u32::MAX as usize);
+        debug_assert!(weights.iter().all(|w| *w >= 0.0), "Weights must be non-negative");
+
+        let n = weights.len();
+        let sum: f64 = weights.iter().map(|w| *w as f64).sum();
+
+        let mut bins: Vec<AliasBin> = weights.iter().map(|w| {
+            let pmf = if sum > 0.0 { ((*w as f64) / sum) as f32 } else { 1.0 / (n as f32) };
+            AliasBin { q: 0.0, alias: 0, pmf }
+        }).collect();
+
+        // Scaled probabilities: the average bin has a weight of 1
+        let mut p: Vec<f64> = bins.iter().map(|b| (b.pmf as f64) * (n as f64)).collect();
+
+        let mut under = Vec::new();
+        let mut over = Vec::new();
+        for (i, p_i) in p.iter().enumerate() {
+            if *p_i < 1.0 { under.push(i) } else { over.push(i) }
+        }
+
+        while let (Some(&u), Some(&o)) = (under.last(), over.last()) {
+            under.pop();
+            over.pop();
+
+            bins[u].q = p[u] as f32;
+            bins[u].alias = o as u32;
+
+            // Give the leftover probability of the overfull bin to the underfull bin
+            p[o] = (p[o] + p[u]) - 1.0;
+            if p[o] < 1.0 {
+                under.push(o);
+            } else {
+                over.push(o);
+            }
+        }
+        // Any leftovers are due to rounding and should have a probability of one
+        for i in under.into_iter().chain(over) {
+            bins[i].q = 1.0;
+            bins[i].alias = i as u32;
+        }
+
+        AliasTable { bins }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bins.is_empty()
+    }
+
+    /// Probability of the given index being sampled
+    pub fn pmf(&self, index: usize) -> f32 {
+        self.bins[index].pmf
+    }
+
+    /// Chooses an index using a number in [0, 1). Returns the index and its probability
+    pub fn sample(&self, u: f32) -> (usize, f32) {
+        debug_assert!(!self.is_empty());
+        let n = self.bins.len();
+        let scaled = u * (n as f32);
+        let i = (scaled as usize).min(n - 1);
+        // Reuse the remainder as a uniform number for choosing between the bin and its alias
+        let up = (scaled - (i as f32)).min(1.0);
+
+        let bin = &self.bins[i];
+        let index = if up < bin.q { i } else { bin.alias as usize };
+        (index, self.bins[index].pmf)
+    }
+}
+
+#[test]
+fn test_alias_table_distribution() {
+    let weights = [1.0, 0.0, 3.0, 0.5, 2.5, 1.0];
+    let sum: f32 = weights.iter().sum();
+    let table = AliasTable::new(&weights);
+
+    let mut counts = [0u32; 6];
+    let n = 600_000;
+    for i in 0..n {
+        let u = ((i as f32) + 0.5) / (n as f32);
+        let (index, pmf) = table.sample(u);
+        assert_eq!(pmf, table.pmf(index));
+        counts[index] += 1;
+    }
+    for (i, w) in weights.iter().enumerate() {
+        assert!((table.pmf(i) - w / sum).abs() < 1e-6);
+        let freq = (counts[i] as f32) / (n as f32);
+        assert!((freq - w / sum).abs() < 1e-3, "Bin {} sampled with frequency {}, expected {}", i, freq, w / sum);
+    }
+
+    let uniform = AliasTable::new(&[0.0, 0.0]);
+    assert_eq!(uniform.pmf(0), 0.5);
+    assert_eq!(uniform.sample(0.75).0, 1);
+}
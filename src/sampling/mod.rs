@@ -1,3 +1,7 @@
+pub mod alias_table;
+
+pub use self::alias_table::*;
+
 use std::ops::Range;
 
 pub struct WyRand {
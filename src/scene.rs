@@ -3,26 +3,36 @@ use embree;
 use embree::{BuildQuality, SceneFlags, RayHit, Hit, GeomID};
 use vec_map::VecMap;
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use scene_import::SceneDescription;
+use scene_import::{SceneDescription, DISK_SEGMENTS};
 
 use crate::math::*;
 use crate::colour::*;
 use crate::materials::*;
 use crate::geometry::*;
 use crate::geometry::{Sphere};
+use crate::light_sampler::*;
+use crate::media::*;
+use crate::sampling::PathSample;
 
 pub struct Scene {
     scene: embree::Scene,
     primitives: VecMap<Primitive>,
+    /// Normal transforms of the instances at each time step, indexed by instance ID
+    instance_normal_transforms: VecMap<Vec<Mat4>>,
     skybox: Colour,
-    pub lights: Vec<(GeomID, Box<dyn SampleableEmitter>)>,
+    pub lights: Vec<Light>,
+    light_sampler: LightSampler,
+    media: Vec<Medium>,
+    camera_medium: Option<usize>,
 }
 
 #[derive(Debug, Clone)]
 pub enum MaterialType {
     Diffuse(Lambert),
     Glossy(Glossy),
+    Subsurface(Subsurface),
     Null,
 }
 
@@ -38,6 +48,7 @@ impl Bsdf for MaterialType {
             },
             MaterialType::Diffuse(m) => { m.sample(xi, basis, w_o) },
             MaterialType::Glossy(m) => { m.sample(xi, basis, w_o) },
+            MaterialType::Subsurface(m) => { m.sample(xi, basis, w_o) },
         }
     }
 
@@ -45,6 +56,7 @@ impl Bsdf for MaterialType {
         match self {
             MaterialType::Diffuse(m) => { m.eval(basis, w_o, w_i) },
             MaterialType::Glossy(m) => { m.eval(basis, w_o, w_i) },
+            MaterialType::Subsurface(m) => { m.eval(basis, w_o, w_i) },
             MaterialType::Null => {
                 BsdfSample {
                     reflectance: Colour::one(),
@@ -67,6 +79,9 @@ impl Bsdf for MaterialType {
 struct Primitive {
     pub emitter: EmissiveGeometry,
     pub material: MaterialType,
+    pub media: MediumInterface,
+    /// Whether rays pass through the back faces of the primitive
+    pub backface_culling: bool,
     // pub tex_scale: Vec2,
     // pub normal_map: Texture,
 }
@@ -76,16 +91,23 @@ impl Primitive {
         Primitive {
             emitter: EmissiveGeometry::NotEmissive,
             material: material,
+            media: MediumInterface::default(),
+            backface_culling: false,
         }
     }
 }
 
+/// Limits how many surfaces without a BSDF a path or shadow ray can pass through
+pub const MAX_INTERFACE_CROSSINGS: u32 = 256;
+
 #[derive(Clone)]
 // TODO: private
 pub enum EmissiveGeometry {
     NotEmissive,
     Sphere(Sphere),
     Quad(Quad),
+    /// The triangles of a mesh, indexed by primitive ID
+    Mesh(Vec<Triangle>),
 }
 
 pub struct ShadingParameters {
@@ -94,9 +116,51 @@ pub struct ShadingParameters {
 
 impl Scene {
     pub fn intersect(&self, rayhit: &mut RayHit) -> bool {
-        self.scene.intersect(rayhit);
-        rayhit.hit.Ng = rayhit.hit.Ng.normalize();
-        rayhit.hit.is_hit()
+        let tfar = rayhit.ray.tfar;
+        loop {
+            self.scene.intersect(rayhit);
+            if !rayhit.hit.is_hit() {
+                return false;
+            }
+            if rayhit.hit.is_instanced() {
+                // Embree gives the normal in the space of the instanced scene
+                let normal_transform = self.instance_normal_transform(rayhit.hit.inst_id, rayhit.ray.time());
+                rayhit.hit.Ng = normal_transform.transform_vector3(rayhit.hit.Ng);
+            }
+            rayhit.hit.Ng = rayhit.hit.Ng.normalize();
+
+            let is_back_face = dot(rayhit.hit.Ng, rayhit.ray.dir) > 0.0;
+            if !(is_back_face && self.primitive(&rayhit.hit).backface_culling) {
+                return true;
+            }
+            // Carry on past the culled face
+            rayhit.ray.tnear = rayhit.ray.tfar + EPSILON * rayhit.ray.tfar.max(1.0);
+            rayhit.ray.tfar = tfar;
+            rayhit.hit = Hit::empty();
+            if rayhit.ray.tnear >= tfar {
+                return false;
+            }
+        }
+    }
+
+    /// Moving instances blend between the normal transforms of their time steps. This isn't exact,
+    ///  but the error is small for the short motions within a shutter interval
+    fn instance_normal_transform(&self, inst_id: GeomID, time: f32) -> Mat4 {
+        let transforms = &self.instance_normal_transforms[inst_id.id as usize];
+        if transforms.len() == 1 {
+            return transforms[0];
+        }
+        let x = time.clamp(0.0, 1.0) * (transforms.len() - 1) as f32;
+        let i = (x as usize).min(transforms.len() - 2);
+        let t = x - i as f32;
+        transforms[i] * (1.0 - t) + transforms[i + 1] * t
+    }
+
+    /// The primitive that was hit. For instances this is the instance rather than the geometry inside it
+    fn primitive(&self, hit: &Hit) -> &Primitive {
+        debug_assert!(!hit.geom_id.is_invalid());
+        let id = if hit.is_instanced() { hit.inst_id } else { hit.geom_id };
+        &self.primitives[id.id as usize]
     }
 
     pub fn occluded(&self, ray: &mut embree::Ray) -> bool {
@@ -107,24 +171,102 @@ impl Scene {
         self.skybox
     }
 
-    pub fn emission_at(&self, ray: &Ray, hit: &Hit) -> LightSample {
-        let p = ray.point_at_dist(ray.tfar);
-        let e = &self.primitives[hit.geom_id.unwrap() as usize].emitter;
-        match e {
-            EmissiveGeometry::NotEmissive => LightSample {
+    /// The emission from the hit surface at p towards origin. The pdf is that of sampling p
+    ///  with `sample_light` from origin, including the probability of choosing the light.
+    /// origin_normal is the surface normal at origin, or zero if there isn't a surface there
+    pub fn emission_at(&self, origin: Vec3, origin_normal: Vec3, p: Vec3, hit: &Hit) -> LightSample {
+        let e = &self.primitive(hit).emitter;
+        let mut sample = match e {
+            EmissiveGeometry::NotEmissive => return LightSample {
                 dir: Vec3::ZERO,
                 distance: 0.0,
                 radiance: Colour::zero(),
                 pdf: PdfW(1.0),
             },
-            EmissiveGeometry::Sphere(s) => s.eval_emission_at(ray.origin, p),
-            EmissiveGeometry::Quad(q) => q.eval_emission_at(ray.origin, p),
-        }
+            EmissiveGeometry::Sphere(s) => s.eval_emission_at(origin, p),
+            EmissiveGeometry::Quad(q) => q.eval_emission_at(origin, p),
+            EmissiveGeometry::Mesh(tris) => tris[hit.prim_id.unwrap() as usize].eval_emission_at(origin, p),
+        };
+        sample.pdf.0 *= match self.light_sampler.light_index(hit) {
+            Some(index) => self.light_sampler.pmf(origin, origin_normal, index),
+            None => 0.0,
+        };
+        sample
+    }
+
+    /// Chooses a light to sample from point p with surface normal n.
+    /// Returns the light and the probability it was chosen
+    pub fn sample_light(&self, p: Vec3, n: Vec3, u: f32) -> Option<(&Light, f32)> {
+        let (index, pmf) = self.light_sampler.sample(p, n, u)?;
+        Some((&self.lights[index], pmf))
     }
 
     pub fn bsdf_at(&self, hit: &Hit) -> impl Bsdf {
-        debug_assert!(!hit.geom_id.is_invalid());
-        self.primitives[hit.geom_id.id as usize].material.clone()
+        self.primitive(hit).material.clone()
+    }
+
+    /// Surfaces without a BSDF only mark the boundaries of media and emitters, so rays pass straight through them
+    pub fn is_interface(&self, hit: &Hit) -> bool {
+        matches!(self.primitive(hit).material, MaterialType::Null)
+    }
+
+    /// Light leaves translucent surfaces from the opposite side to the one it arrived at
+    pub fn is_translucent(&self, hit: &Hit) -> bool {
+        matches!(self.primitive(hit).material, MaterialType::Subsurface(_))
+    }
+
+    pub fn medium(&self, index: usize) -> &Medium {
+        &self.media[index]
+    }
+
+    /// The medium rays start in from the camera
+    pub fn camera_medium(&self) -> Option<usize> {
+        self.camera_medium
+    }
+
+    /// The medium a ray leaving the hit surface in direction dir is in.
+    ///  geometric_normal is the normal of the surface before it's flipped to face the ray
+    pub fn medium_after(&self, hit: &Hit, geometric_normal: Vec3, dir: Vec3, current: Option<usize>) -> Option<usize> {
+        let media = &self.primitive(hit).media;
+        if !media.is_transition() {
+            return current;
+        }
+        if dot(dir, geometric_normal) < 0.0 { media.interior } else { media.exterior }
+    }
+
+    /// The fraction of the light's emission that reaches origin along dir. The path can pass through
+    ///  interfaces between media but is blocked by any other surface
+    #[allow(clippy::too_many_arguments)]
+    pub fn transmittance(&self, origin: Vec3, dir: Vec3, distance: f32, time: f32, mut medium: Option<usize>,
+            light: &Light, rng: &mut PathSample) -> Colour {
+        let is_infinite = distance == f32::INFINITY;
+        let mut tr = Colour::one();
+        let mut origin = origin;
+        let mut remaining = distance;
+        for _ in 0..MAX_INTERFACE_CROSSINGS {
+            let ray = Ray::new(origin, dir, remaining).at_time(time);
+            let mut rayhit = RayHit::from_ray(ray.into());
+            let is_hit = self.intersect(&mut rayhit);
+            let t = if is_hit { rayhit.ray.tfar } else { remaining };
+            if let Some(m) = medium {
+                tr *= self.media[m].transmittance(&ray, t, rng);
+            }
+
+            let hit = rayhit.hit;
+            if light.is_hit_by(&hit) {
+                return tr;
+            }
+            if !is_hit {
+                return if is_infinite { tr } else { Colour::zero() };
+            }
+            if !self.is_interface(&hit) || tr.is_zero() {
+                return Colour::zero();
+            }
+            medium = self.medium_after(&hit, hit.Ng, dir, medium);
+            origin = ray.point_at_dist(t) + dir * EPSILON;
+            remaining -= t;
+        }
+        Colour::zero()
     }
 }
 
@@ -139,6 +281,133 @@ fn to_affine_transform(transform: &scene_import::Transform) -> AffineTransform {
     }
 }
 
+/// Most time steps embree allows for moving geometry
+const MAX_TIME_STEPS: usize = 129;
+
+/// The number of evenly spaced time steps over the shutter interval that puts one at every keyframe
+///  inside it, so the motion between keyframes is kept. Keyframes that don't line up with any number of
+///  steps embree allows are followed as closely as it can
+fn time_step_count(times: &[f32], shutter_open: f32, shutter_close: f32) -> usize {
+    let inner: Vec<f32> = times.iter()
+        .map(|t| (t - shutter_open) / (shutter_close - shutter_open))
+        .filter(|u| *u > 0.0 && *u < 1.0)
+        .collect();
+    (2..=MAX_TIME_STEPS).find(|&steps| {
+        let segments = (steps - 1) as f32;
+        inner.iter().all(|u| (u * segments - (u * segments).round()).abs() < 1e-3)
+    }).unwrap_or(MAX_TIME_STEPS)
+}
+
+/// Samples the keyframes at evenly spaced times over the shutter interval, which is how embree
+///  interpolates moving geometry. Returns nothing if the primitive doesn't move
+fn motion_transforms(keyframes: &[scene_import::Keyframe], shutter_open: f32, shutter_close: f32) -> Vec<AffineTransform> {
+    if keyframes.is_empty() {
+        return Vec::new();
+    }
+    let mut keyframes: Vec<(f32, AffineTransform)> = keyframes.iter()
+        .map(|k| (k.time, to_affine_transform(&k.transform)))
+        .collect();
+    keyframes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let transform_at = |time: f32| {
+        let i = keyframes.iter().position(|k| k.0 > time).unwrap_or(keyframes.len());
+        if i == 0 {
+            return keyframes[0].1;
+        }
+        if i == keyframes.len() {
+            return keyframes[i - 1].1;
+        }
+        let (t0, a) = keyframes[i - 1];
+        let (t1, b) = keyframes[i];
+        a.interpolate(&b, (time - t0) / (t1 - t0))
+    };
+
+    let times: Vec<f32> = keyframes.iter().map(|k| k.0).collect();
+    let steps = time_step_count(&times, shutter_open, shutter_close);
+    (0..steps).map(|i| {
+        let time = shutter_open + (shutter_close - shutter_open) * (i as f32) / ((steps - 1) as f32);
+        transform_at(time)
+    }).collect()
+}
+
+/// Moves a mesh through a transform at each time step
+fn apply_motion(mesh: &mut embree::TriangleMesh, transforms: &[AffineTransform]) {
+    let vertices = mesh.vertices.clone();
+    let positions_at = |transform: &AffineTransform| {
+        let matrix = transform.to_matrix();
+        vertices.iter().map(|v| matrix.transform_point3(*v)).collect::<Vec<Vec3>>()
+    };
+    // Normals follow the first time step
+    mesh.transform_mesh(transforms[0].to_matrix());
+    for transform in &transforms[1..] {
+        mesh.add_time_step(positions_at(transform));
+    }
+}
+
+/// Smooth normals at the vertices of a mesh, from the area weighted normals of the triangles around them
+fn vertex_normals(mesh: &embree::TriangleMesh) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; mesh.vertices.len()];
+    for tri in &mesh.indices {
+        let v0 = mesh.vertices[tri.v0 as usize];
+        let v1 = mesh.vertices[tri.v1 as usize];
+        let v2 = mesh.vertices[tri.v2 as usize];
+        // The length of the cross product is twice the area
+        let n = (v1 - v0).cross(v2 - v0);
+        for &i in &[tri.v0, tri.v1, tri.v2] {
+            normals[i as usize] += n;
+        }
+    }
+    normals.iter().map(|n| n.normalize_or_zero()).collect()
+}
+
+/// Point lights are spheres this small
+const POINT_LIGHT_RADIUS: f32 = 1e-3;
+
+/// The radiance of a sphere that emits a total power. Each point of its 4 pi r^2 area emits pi * L
+fn sphere_radiance(power: f32, radius: f32) -> Colour {
+    Colour::splat(power / (4.0 * PI * PI * radius * radius))
+}
+
+/// The radiance of the sphere a point light is approximated by. A sphere of radiance L has an
+///  intensity of L * pi * r^2 in every direction
+fn point_light_radiance(intensity: Colour) -> Colour {
+    intensity / (PI * POINT_LIGHT_RADIUS * POINT_LIGHT_RADIUS)
+}
+
+/// A bright pink that makes BSDFs that couldn't be loaded stand out
+fn unsupported_material() -> MaterialType {
+    MaterialType::Diffuse(Lambert::new(Rgb::new(1.00,0.41,0.71)))
+}
+
+fn metal_ior(name: &str, bsdf_name: &str) -> Option<Ior> {
+    let ior = METAL_IOR.iter()
+        .find(|m| m.0 == name || m.1.eq_ignore_ascii_case(name))
+        .map(|m| m.2);
+    if ior.is_none() {
+        log::warn!("Unknown metal {} in BSDF {}", name, bsdf_name);
+    }
+    ior
+}
+
+/// Only constant textures are supported. Others are replaced by their average colour, if it is known
+fn texture_colour(texture: &scene_import::Texture, name: &str) -> Colour {
+    match texture {
+        scene_import::Texture::Constant(c) => Colour::from(*c),
+        scene_import::Texture::Checker { on_color, off_color, .. } => {
+            log::warn!("Checker textures aren't supported, {} uses their average colour", name);
+            (Colour::from(*on_color) + Colour::from(*off_color)) * 0.5
+        },
+        scene_import::Texture::Bitmap { file } => {
+            log::warn!("Bitmap textures aren't supported, {} ignores {}", name, file);
+            Colour::splat(0.5)
+        },
+        scene_import::Texture::Unsupported { type_name } => {
+            log::warn!("Unsupported texture of type {:?} in {}", type_name, name);
+            Colour::splat(0.5)
+        },
+    }
+}
+
 const CUBE_VERTICES: [Vec3; 8] = [
     const_vec3!([-0.5, -0.5, -0.5]),
     const_vec3!([-0.5, -0.5,  0.5]),
@@ -182,8 +451,13 @@ pub struct SceneBuilder {
     pub device: embree::Device,
     scene: embree::SceneBuilder,
     primitives: VecMap<Primitive>,
+    instance_normal_transforms: VecMap<Vec<Mat4>>,
     skybox: Colour,
-    lights: Vec<(GeomID, Box<dyn SampleableEmitter>)>,
+    lights: Vec<Light>,
+    light_sampling: LightSamplingStrategy,
+    quad_sampling: QuadSampling,
+    media: Vec<Medium>,
+    camera_medium: Option<usize>,
 }
 
 impl SceneBuilder {
@@ -197,58 +471,249 @@ impl SceneBuilder {
             skybox: Colour::zero(),
             scene: s,
             primitives: VecMap::new(),
+            instance_normal_transforms: VecMap::new(),
             lights: Vec::new(),
+            light_sampling: LightSamplingStrategy::Bvh,
+            quad_sampling: QuadSampling::SolidAngle,
+            media: Vec::new(),
+            camera_medium: None,
         }
     }
 
+    pub fn set_light_sampling(&mut self, strategy: LightSamplingStrategy) {
+        self.light_sampling = strategy;
+    }
+
+    /// How quads loaded from a scene description are sampled as lights
+    pub fn set_quad_sampling(&mut self, sampling: QuadSampling) {
+        self.quad_sampling = sampling;
+    }
+
+    /// Returns the index used to refer to the medium
+    pub fn add_medium(&mut self, medium: Medium) -> usize {
+        self.media.push(medium);
+        self.media.len() - 1
+    }
+
+    pub fn set_camera_medium(&mut self, medium: Option<usize>) {
+        self.camera_medium = medium;
+    }
+
+    /// Makes rays pass through the back faces of a primitive that has been added
+    pub fn set_backface_culling(&mut self, id: GeomID, cull: bool) {
+        self.primitives[id.unwrap() as usize].backface_culling = cull;
+    }
+
+    /// Sets the media inside and outside a primitive that has been added
+    pub fn set_medium_interface(&mut self, id: GeomID, media: MediumInterface) {
+        self.primitives[id.unwrap() as usize].media = media;
+    }
+
     pub fn load_scene(&mut self, scene: &SceneDescription) {
-        // TODO: do the hashmap stuff in scene_import
-        let mut materials = HashMap::new();
+        for warning in &scene.warnings {
+            log::warn!("{}", warning);
+        }
+        for (path, referenced_by) in &scene.missing_files {
+            log::warn!("{} refers to {}, which doesn't exist, ignoring it", referenced_by, path.display());
+        }
 
-        #[allow(unreachable_patterns)]
-        for mat in &scene.bsdfs {
-            let albedo = mat.albedo.into();
+        // Indexed like scene.bsdfs, which primitives refer to once the scene is resolved
+        let mut materials: Vec<MaterialType> = Vec::with_capacity(scene.bsdfs.len());
+        // Mixed and transparent BSDFs become the one they refer to, which can come after them
+        let mut aliases: Vec<(usize, usize)> = Vec::new();
+        let mut alias = |index: usize, mat: &scene_import::Material, bsdf: &scene_import::BsdfRef| {
+            match bsdf.index().filter(|&i| i < scene.bsdfs.len()) {
+                Some(i) => aliases.push((index, i)),
+                None => log::warn!("BSDF {} refers to unknown BSDF {:?}", mat.name, bsdf.name().unwrap_or("")),
+            }
+            unsupported_material()
+        };
+
+        for (index, mat) in scene.bsdfs.iter().enumerate() {
+            let albedo = texture_colour(&mat.albedo, &mat.name);
             let m = match &mat.bsdf {
                 scene_import::MaterialType::Lambert {} => {
                     MaterialType::Diffuse(Lambert::new(albedo))
                 },
-                scene_import::MaterialType::Null => MaterialType::Diffuse(Lambert::new(Colour::zero())),
-                scene_import::MaterialType::RoughConductor { roughness, material, .. } => {
-                    let m = METAL_IOR.iter().find(|m| m.0 == material).expect("unknown material name");
-                    let fresnel = SchlickFresnel::new(m.2);
-                    let material = Glossy {
-                        specular: fresnel,
-                        ggx: GGX::new(*roughness),
+                scene_import::MaterialType::Null => MaterialType::Null,
+                scene_import::MaterialType::Subsurface { mean_free_path } => {
+                    MaterialType::Subsurface(Subsurface::new(albedo, Colour::from(*mean_free_path)))
+                },
+                scene_import::MaterialType::Mirror {} => {
+                    MaterialType::Glossy(Glossy {
+                        specular: SchlickFresnel::from_reflectance(albedo),
+                        ggx: GGX::new(0.0),
+                    })
+                },
+                scene_import::MaterialType::Conductor { material, eta, k } => {
+                    let ior = match (eta, k) {
+                        (Some(eta), Some(k)) => Some(Ior { n: *eta, k: *k }),
+                        _ => metal_ior(material, &mat.name),
+                    };
+                    match ior {
+                        Some(ior) => MaterialType::Glossy(Glossy {
+                            specular: SchlickFresnel::new(ior),
+                            ggx: GGX::new(0.0),
+                        }),
+                        None => unsupported_material(),
+                    }
+                },
+                scene_import::MaterialType::RoughConductor { roughness, material, eta, k, .. } => {
+                    let ior = match (eta, k) {
+                        (Some(eta), Some(k)) => Some(Ior { n: *eta, k: *k }),
+                        _ => metal_ior(material, &mat.name),
+                    };
+                    match ior {
+                        Some(ior) => MaterialType::Glossy(Glossy {
+                            specular: SchlickFresnel::new(ior),
+                            ggx: GGX::new(*roughness),
+                        }),
+                        None => unsupported_material(),
+                    }
+                },
+                scene_import::MaterialType::Dielectric { ior } | scene_import::MaterialType::RoughDielectric { ior, .. } => {
+                    log::warn!("Refraction isn't supported, BSDF {} only reflects", mat.name);
+                    let roughness = match &mat.bsdf {
+                        scene_import::MaterialType::RoughDielectric { roughness, .. } => *roughness,
+                        _ => 0.0,
                     };
+                    let r0 = ((ior - 1.0) / (ior + 1.0)).powi(2);
+                    MaterialType::Glossy(Glossy {
+                        specular: SchlickFresnel::from_reflectance(Colour::splat(r0)),
+                        ggx: GGX::new(roughness),
+                    })
+                },
+                scene_import::MaterialType::Plastic { .. } | scene_import::MaterialType::RoughPlastic { .. } => {
+                    log::warn!("Plastic coatings aren't supported, BSDF {} is only diffuse", mat.name);
+                    MaterialType::Diffuse(Lambert::new(albedo))
+                },
+                scene_import::MaterialType::Mixed { bsdf0, bsdf1, ratio } => {
+                    log::warn!("Mixed BSDFs aren't supported, BSDF {} uses the one with the larger weight", mat.name);
+                    alias(index, mat, if *ratio >= 0.5 { bsdf0 } else { bsdf1 })
+                },
+                scene_import::MaterialType::Transparency { base, alpha } => {
+                    if alpha.constant() != Some([1.0, 1.0, 1.0]) {
+                        log::warn!("Transparency isn't supported, BSDF {} is opaque", mat.name);
+                    }
+                    alias(index, mat, base)
+                },
+                scene_import::MaterialType::Unsupported { type_name, reason } => {
+                    log::warn!("Unsupported BSDF {} of type {:?}: {}", mat.name, type_name, reason);
+                    unsupported_material()
+                },
+            };
+            materials.push(m);
+        }
+        for &(i, mut target) in &aliases {
+            // Follows chains of aliases, giving up on cycles
+            for _ in 0..aliases.len() {
+                match aliases.iter().find(|(j, _)| *j == target) {
+                    Some(&(_, next)) => target = next,
+                    None => break,
+                }
+            }
+            materials[i] = materials[target].clone();
+        }
 
-                    MaterialType::Glossy(material)
-                    // MaterialType::Diffuse(Lambert::new(Rgb::new(0.72,0.45,0.20)))
+        let mut media = HashMap::new();
+        for medium in &scene.media {
+            let phase = match medium.phase_function {
+                scene_import::PhaseFunction::Isotropic => PhaseFunction::Isotropic,
+                scene_import::PhaseFunction::HenyeyGreenstein { g } => PhaseFunction::HenyeyGreenstein { g },
+            };
+            let m = match &medium.medium {
+                scene_import::MediumType::Homogeneous { sigma_a, sigma_s, density } => {
+                    Medium::Homogeneous(HomogeneousMedium {
+                        sigma_a: Colour::from(*sigma_a) * *density,
+                        sigma_s: Colour::from(*sigma_s) * *density,
+                        phase,
+                    })
+                },
+                scene_import::MediumType::Unsupported { type_name, reason } => {
+                    log::warn!("Unsupported medium {} of type {:?}: {}", medium.name, type_name, reason);
+                    continue;
                 },
-                b => {
-                    log::warn!("Unknown BSDF type: {:?}", b);
-                    MaterialType::Diffuse(Lambert::new(Rgb::new(1.00,0.41,0.71)))
+                scene_import::MediumType::Voxel { sigma_a, sigma_s, density, transform, grid_data, .. } => {
+                    let resolution = grid_data.resolution;
+                    let grid = VoxelGrid::new([resolution[0] as usize, resolution[1] as usize, resolution[2] as usize], grid_data.data.clone());
+                    let bounds_min = Vec3::from(grid_data.bounds_min);
+                    let extent = Vec3::from(grid_data.bounds_max) - bounds_min;
+                    let grid_to_world = to_affine_transform(transform).to_matrix()
+                        * Mat4::from_translation(bounds_min) * Mat4::from_scale(extent);
+                    Medium::Grid(GridMedium::new(
+                        Colour::from(*sigma_a) * *density,
+                        Colour::from(*sigma_s) * *density,
+                        phase,
+                        grid,
+                        grid_to_world))
                 },
             };
-            materials.insert(mat.name.clone(), m);
+            media.insert(medium.name.clone(), self.add_medium(m));
         }
+        let find_medium = |name: &Option<String>| {
+            let name = name.as_ref()?;
+            let index = media.get(name).copied();
+            if index.is_none() {
+                log::warn!("Unknown medium {}", name);
+            }
+            index
+        };
+        self.camera_medium = find_medium(&scene.camera.medium);
+
         for prim in &scene.primitives {
             let default_material = MaterialType::Null;
-            let mat = materials.get(&prim.bsdf).unwrap_or(&default_material);
+            let mat = match prim.bsdf.index().and_then(|i| materials.get(i)) {
+                Some(mat) => mat,
+                None => {
+                    if prim.primitive.needs_bsdf() {
+                        let name = prim.bsdf.name().unwrap_or("");
+                        log::warn!("Primitive refers to unknown BSDF {:?}, it won't be visible", name);
+                    }
+                    &default_material
+                },
+            };
 
-            let transform = to_affine_transform(&prim.transform);
-            let emission = prim.emission.into();
+            let emission = texture_colour(&prim.emission, "emission");
+            let mut motion = motion_transforms(&prim.keyframes, scene.camera.shutter_open, scene.camera.shutter_close);
+            let transform = motion.first().copied().unwrap_or_else(|| to_affine_transform(&prim.transform));
+            if !motion.is_empty() {
+                let is_mesh = matches!(prim.primitive, scene_import::PrimitiveType::Cube
+                    | scene_import::PrimitiveType::Mesh { .. } | scene_import::PrimitiveType::Instances { .. });
+                if !is_mesh {
+                    log::warn!("Motion blur is only supported for meshes, using the first keyframe");
+                    motion.clear();
+                } else if !emission.is_zero() {
+                    log::warn!("Motion blur isn't supported for emissive primitives, using the first keyframe");
+                    motion.clear();
+                }
+            }
 
-            #[allow(unreachable_patterns)]
-            match &prim.primitive {
-                scene_import::PrimitiveType::Sphere { power } => {
+            let id = match &prim.primitive {
+                scene_import::PrimitiveType::Sphere {} => {
                     let mut sphere = Sphere::unit();
                     sphere.transform_by(&transform);
                     sphere.emission = emission;
-                    if *power > 0.0 {
-                        let radiance = power / (4.0 * PI * sphere.radius * sphere.radius);
-                        sphere.emission = Colour::splat(radiance);
+                    if let Some(power) = prim.power {
+                        sphere.emission = sphere_radiance(power, sphere.radius);
                     }
-                    self.add_sphere(sphere, mat.clone());
+                    Some(self.add_sphere(sphere, mat.clone()))
+                },
+                scene_import::PrimitiveType::Point {} => {
+                    // Approximated by a tiny sphere. The emission is the radiant intensity
+                    let mut sphere = Sphere::unit();
+                    sphere.transform_by(&transform);
+                    sphere.radius = POINT_LIGHT_RADIUS;
+                    let intensity = match prim.power {
+                        Some(power) => Colour::splat(power / (4.0 * PI)),
+                        None => emission,
+                    };
+                    sphere.emission = point_light_radiance(intensity);
+                    Some(self.add_sphere(sphere, MaterialType::Null))
+                },
+                scene_import::PrimitiveType::Disk {} => {
+                    let mut disk = self.create_disk();
+                    disk.transform_mesh(transform.to_matrix());
+                    Some(self.add_mesh(disk, mat.clone(), emission))
                 },
                 scene_import::PrimitiveType::Quad => {
                     let mut quad = Quad::new(
@@ -258,50 +723,132 @@ impl SceneBuilder {
                         Vec3::new(-0.5, 0.0,  0.5));
                     quad.transform_by(&transform);
                     quad.emission = emission;
-                    self.add_quad(quad, mat.clone());
+                    quad.sampling = self.quad_sampling;
+                    Some(self.add_quad(quad, mat.clone()))
                 }
                 scene_import::PrimitiveType::Cube => {
                     let mut cube = embree::TriangleMesh::new(&self.device,
                         Vec::from(CUBE_INDICES.as_ref()),
                         Vec::from(CUBE_VERTICES.as_ref()));
-                    let matrix = transform.to_matrix();
-                    cube.transform_mesh(matrix);
-                    self.add_mesh(cube, mat.clone());
+                    if motion.is_empty() {
+                        let matrix = transform.to_matrix();
+                        cube.transform_mesh(matrix);
+                    } else {
+                        apply_motion(&mut cube, &motion);
+                    }
+                    Some(self.add_mesh(cube, mat.clone(), emission))
                 }
-                scene_import::PrimitiveType::Mesh { mesh_data, .. } => {
-                    let mut positions = Vec::with_capacity(mesh_data.verts.len());
-                    let mut normals = Vec::with_capacity(mesh_data.verts.len());
-                    let mut uvs = Vec::with_capacity(mesh_data.verts.len());
-                    for vertex in mesh_data.verts.iter() {
-                        positions.push(vertex.pos.into());
-                        normals.push(vertex.normal.into());
-                        uvs.push(vertex.uv.into());
+                scene_import::PrimitiveType::Mesh { mesh_data, smooth, backface_culling, recompute_normals, .. } => {
+                    let mut mesh = self.create_mesh(mesh_data);
+                    if motion.is_empty() {
+                        let matrix = transform.to_matrix();
+                        mesh.transform_mesh(matrix);
+                    } else {
+                        apply_motion(&mut mesh, &motion);
+                    }
+                    if !smooth {
+                        mesh.normals = None;
+                    } else if *recompute_normals {
+                        mesh.normals = Some(vertex_normals(&mesh));
                     }
-                    let indices = mesh_data.tris.iter().map(|t| embree::IndexedTriangle { v0: t[0], v1: t[1], v2: t[2] }).collect();
-                    let mut mesh = embree::TriangleMesh::new(&self.device, indices, positions);
-                    mesh.normals = Some(normals);
-                    mesh.tex_coords = Some(uvs);
-                    // let matrix = transform.to_matrix();
-                    // mesh.transform_mesh(matrix);
-                    self.add_mesh(mesh, mat.clone());
+                    let id = self.add_mesh(mesh, mat.clone(), emission);
+                    self.set_backface_culling(id, *backface_culling);
+                    Some(id)
                 },
-                scene_import::PrimitiveType::InfiniteSphereCap { power, cap_angle, .. } => {
+                scene_import::PrimitiveType::Instances { mesh_data, transforms, .. } => {
+                    if !emission.is_zero() {
+                        log::warn!("Instances can't be emissive, ignoring their emission");
+                    }
+                    let prototype = self.build_prototype(self.create_mesh(mesh_data));
+                    // The primitive's transform places the whole group of instances
+                    let group: Vec<Mat4> = if motion.is_empty() {
+                        vec![transform.to_matrix()]
+                    } else {
+                        motion.iter().map(|t| t.to_matrix()).collect()
+                    };
+                    let interface = MediumInterface {
+                        interior: find_medium(&prim.int_medium),
+                        exterior: find_medium(&prim.ext_medium),
+                    };
+                    for instance_transform in transforms {
+                        let local = to_affine_transform(instance_transform).to_matrix();
+                        let steps: Vec<Mat4> = group.iter().map(|g| *g * local).collect();
+                        let id = self.add_instance(&prototype, &steps, mat.clone());
+                        self.set_medium_interface(id, interface);
+                    }
+                    None
+                },
+                scene_import::PrimitiveType::InfiniteSphereCap { cap_angle, .. } => {
                     let cap_angle = cap_angle * PI / 180.0;
                     // 2pi * (1 - cosθ) is the solid angle subtended by a cone of angle θ
-                    let radiance = power / (2.0 * PI * (1.0 - cap_angle.cos()));
+                    let radiance = match prim.power {
+                        Some(power) => Colour::splat(power / (2.0 * PI * (1.0 - cap_angle.cos()))),
+                        None => emission,
+                    };
                     let cap = InfiniteSphereCap {
                         cap_dir: transform.transform_vector(Vec3::Y).normalize(),
                         cap_angle: cap_angle,
-                        emission: Colour::splat(radiance),
+                        emission: radiance,
                     };
-                    self.lights.push((GeomID::invalid(), Box::new(cap.clone())));
+                    self.lights.push(Light::new(GeomID::invalid(), Box::new(cap.clone())));
+                    None
+                },
+                scene_import::PrimitiveType::InfiniteSphere { .. } => {
+                    self.skybox += emission;
+                    None
+                },
+                scene_import::PrimitiveType::Skydome { .. } => {
+                    log::warn!("Skydomes aren't supported, ignoring");
+                    None
+                },
+                scene_import::PrimitiveType::Curves { file, .. } => {
+                    log::warn!("Curves aren't supported, ignoring {}", file);
+                    None
+                },
+                scene_import::PrimitiveType::Unsupported { type_name, reason } => {
+                    log::warn!("Unsupported primitive of type {:?}: {}", type_name, reason);
+                    None
                 },
-                t => log::warn!("Unknown primitive type: {:?}", t),
+            };
+            if let Some(id) = id {
+                let interface = MediumInterface {
+                    interior: find_medium(&prim.int_medium),
+                    exterior: find_medium(&prim.ext_medium),
+                };
+                self.set_medium_interface(id, interface);
             }
         }
     }
 
-    pub fn add_sphere(&mut self, sphere: Sphere, material: MaterialType) {
+    /// A disk with a diameter of one in the xz plane. Its triangles face up
+    fn create_disk(&self) -> embree::TriangleMesh {
+        let mut vertices = vec![Vec3::ZERO];
+        let mut indices = Vec::with_capacity(DISK_SEGMENTS as usize);
+        for i in 0..DISK_SEGMENTS {
+            let angle = 2.0 * PI * (i as f32) / (DISK_SEGMENTS as f32);
+            vertices.push(Vec3::new(0.5 * angle.cos(), 0.0, 0.5 * angle.sin()));
+            indices.push(embree::IndexedTriangle::new(0, (i + 1) % DISK_SEGMENTS + 1, i + 1));
+        }
+        embree::TriangleMesh::new(&self.device, indices, vertices)
+    }
+
+    fn create_mesh(&self, mesh_data: &scene_import::TriangleMesh) -> embree::TriangleMesh {
+        let mut positions = Vec::with_capacity(mesh_data.verts.len());
+        let mut normals = Vec::with_capacity(mesh_data.verts.len());
+        let mut uvs = Vec::with_capacity(mesh_data.verts.len());
+        for vertex in mesh_data.verts.iter() {
+            positions.push(vertex.pos.into());
+            normals.push(vertex.normal.into());
+            uvs.push(vertex.uv.into());
+        }
+        let indices = mesh_data.tris.iter().map(|t| embree::IndexedTriangle { v0: t[0], v1: t[1], v2: t[2] }).collect();
+        let mut mesh = embree::TriangleMesh::new(&self.device, indices, positions);
+        mesh.normals = Some(normals);
+        mesh.tex_coords = Some(uvs);
+        mesh
+    }
+
+    pub fn add_sphere(&mut self, sphere: Sphere, material: MaterialType) -> GeomID {
         let emitter;
         if sphere.is_emissive() {
             emitter = EmissiveGeometry::Sphere(sphere.clone());
@@ -311,6 +858,8 @@ impl SceneBuilder {
         let prim = Primitive {
             emitter: emitter,
             material: material,
+            media: MediumInterface::default(),
+            backface_culling: false,
         };
 
         // This seems to be slower than a user geometry, so keep using that
@@ -326,11 +875,12 @@ impl SceneBuilder {
 
         self.primitives.insert(id.unwrap() as usize, prim);
         if sphere.is_emissive() {
-            self.lights.push((id, Box::new(sphere.clone())));
+            self.lights.push(Light::new(id, Box::new(sphere.clone())));
         }
+        id
     }
 
-    pub fn add_quad(&mut self, quad: Quad, material: MaterialType) {
+    pub fn add_quad(&mut self, quad: Quad, material: MaterialType) -> GeomID {
         let emitter;
         if quad.is_emissive() {
             emitter = EmissiveGeometry::Quad(quad.clone());
@@ -340,6 +890,8 @@ impl SceneBuilder {
         let prim = Primitive {
             emitter: emitter,
             material: material,
+            media: MediumInterface::default(),
+            backface_culling: false,
         };
 
         let index = vec![embree::IndexedTriangle::new(0, 1, 2), embree::IndexedTriangle::new(0, 2, 3)];
@@ -347,21 +899,123 @@ impl SceneBuilder {
         let id = self.scene.attach(mesh);
         self.primitives.insert(id.unwrap() as usize, prim);
         if quad.is_emissive() {
-            self.lights.push((id, Box::new(quad)));
+            self.lights.push(Light::new(id, Box::new(quad)));
         }
+        id
     }
 
-    pub fn add_mesh(&mut self, mesh: embree::TriangleMesh, material: MaterialType) {
+    pub fn add_mesh(&mut self, mesh: embree::TriangleMesh, material: MaterialType, emission: Colour) -> GeomID {
+        if emission.is_zero() {
+            let id = self.scene.attach(mesh);
+            self.primitives.insert(id.unwrap() as usize, Primitive::new(material));
+            return id;
+        }
+
+        // Each triangle is a separate light so lights can be chosen by their contribution
+        let triangles: Vec<Triangle> = mesh.indices.iter().map(|t| {
+            let mut tri = Triangle::new(
+                mesh.vertices[t.v0 as usize],
+                mesh.vertices[t.v1 as usize],
+                mesh.vertices[t.v2 as usize]);
+            tri.emission = emission;
+            tri
+        }).collect();
+
         let id = self.scene.attach(mesh);
+        for (i, tri) in triangles.iter().enumerate() {
+            self.lights.push(Light::with_primitive(id, i as u32, Box::new(tri.clone())));
+        }
+        let prim = Primitive {
+            emitter: EmissiveGeometry::Mesh(triangles),
+            material,
+            media: MediumInterface::default(),
+            backface_culling: false,
+        };
+        self.primitives.insert(id.unwrap() as usize, prim);
+        id
+    }
+
+    /// Builds a mesh into a scene of its own, which instances of the mesh share
+    pub fn build_prototype(&self, mesh: embree::TriangleMesh) -> Arc<embree::Scene> {
+        let mut prototype = embree::SceneBuilder::new(&self.device);
+        prototype.set_build_quality(BuildQuality::High);
+        prototype.set_flags(SceneFlags::ROBUST | SceneFlags::COMPACT);
+        prototype.attach(mesh);
+        Arc::new(prototype.build())
+    }
+
+    /// Places a copy of a prototype in the scene. There is a transform for each time step of a moving instance,
+    ///  or just one if it doesn't move. Instances can't be emissive
+    pub fn add_instance(&mut self, prototype: &Arc<embree::Scene>, transforms: &[Mat4], material: MaterialType) -> GeomID {
+        assert!(!transforms.is_empty(), "An instance needs a transform");
+        let mut instance = embree::InstanceGeometry::new(&self.device, prototype.clone(), transforms[0]);
+        for transform in &transforms[1..] {
+            instance.add_time_step(*transform);
+        }
+        let id = self.scene.attach(instance);
         self.primitives.insert(id.unwrap() as usize, Primitive::new(material));
+        let normal_transforms = transforms.iter().map(|m| m.inverse().transpose()).collect();
+        self.instance_normal_transforms.insert(id.unwrap() as usize, normal_transforms);
+        id
     }
 
-    pub fn build(self) -> Scene {
+    pub fn build(mut self) -> Scene {
+        // Subsurface materials scatter light in a medium inside the surface
+        for (_, prim) in self.primitives.iter_mut() {
+            if let MaterialType::Subsurface(m) = &prim.material {
+                self.media.push(Medium::Homogeneous(m.medium()));
+                prim.media.interior = Some(self.media.len() - 1);
+            }
+        }
+
+        let scene = self.scene.build();
+
+        let bounds = scene.bounds();
+        let scene_radius = 0.5 * (bounds.upper - bounds.lower).length();
+        let light_sampler = LightSampler::new(self.light_sampling, &self.lights, scene_radius);
+
         Scene {
-            scene: self.scene.build(),
+            scene,
             primitives: self.primitives,
+            instance_normal_transforms: self.instance_normal_transforms,
             skybox: self.skybox,
             lights: self.lights,
+            light_sampler,
+            media: self.media,
+            camera_medium: self.camera_medium,
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_uneven_keyframes() {
+    let keyframe = |time: f32, x: f32| scene_import::Keyframe {
+        time,
+        transform: scene_import::Transform { position: [x, 0.0, 0.0], ..Default::default() },
+    };
+    // Moving one unit in the first quarter of the shutter interval, then another in the rest
+    let transforms = motion_transforms(&[keyframe(0.0, 0.0), keyframe(0.25, 1.0), keyframe(1.0, 2.0)], 0.0, 1.0);
+    assert_eq!(transforms.len(), 5);
+    assert!((transforms[1].translation.x - 1.0).abs() < 1e-6);
+    assert!((transforms[2].translation.x - 4.0 / 3.0).abs() < 1e-6);
+
+    // Evenly spaced keyframes need no more steps than there are keyframes
+    assert_eq!(time_step_count(&[0.0, 0.5, 1.0], 0.0, 1.0), 3);
+    assert_eq!(time_step_count(&[1.0, 2.0], 1.0, 2.0), 2);
+    // Ones that can't be matched get as many steps as embree allows
+    assert_eq!(time_step_count(&[0.0, 2.0f32.sqrt() - 1.0, 1.0], 0.0, 1.0), MAX_TIME_STEPS);
+}
+
+#[test]
+fn test_light_power() {
+    let mut sphere = Sphere::unit();
+    sphere.radius = 2.0;
+    sphere.emission = sphere_radiance(10.0, sphere.radius);
+    assert!((sphere.power(0.0) - 10.0).abs() < 1e-4, "Sphere power {}", sphere.power(0.0));
+
+    // The same power from a point light, which has that much intensity in every direction of the sphere
+    let mut point = Sphere::unit();
+    point.radius = POINT_LIGHT_RADIUS;
+    point.emission = point_light_radiance(Colour::splat(10.0 / (4.0 * PI)));
+    assert!((point.power(0.0) - 10.0).abs() < 1e-3, "Point light power {}", point.power(0.0));
+}